@@ -0,0 +1,63 @@
+// Compares sending and receiving a burst of fader updates one datagram per
+// system call with the batched recvmmsg/sendmmsg path.
+//
+// Run with `cargo bench --features batch --bench batch`.
+
+#[macro_use]
+extern crate criterion;
+extern crate osc;
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use criterion::{Criterion, Throughput};
+
+use osc::data::OscPacket::*;
+use osc::data::OscArg::*;
+use osc::data::OscPacket;
+use osc::batch::RecvBatch;
+use osc::receiver::OscReceiver;
+use osc::sender::OscSender;
+
+const BURST: usize = 64;
+
+fn burst() -> Vec<OscPacket> {
+	(0..BURST).map(|i| OscMessage{
+		addr: format!("/mixer/{}/fader", i % 32),
+		args: vec!(OscFloat(i as f32 / BURST as f32))
+	}).collect()
+}
+
+fn bench_burst(c: &mut Criterion) {
+	let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+	let receiver = OscReceiver::new(localhost).unwrap();
+	let sender = OscSender::new(localhost, receiver.local_addr().unwrap()).unwrap();
+	let packets = burst();
+	let timeout = Some(Duration::from_secs(1));
+
+	let mut group = c.benchmark_group("loopback_burst");
+	group.throughput(Throughput::Elements(BURST as u64));
+
+	group.bench_function("one_per_syscall", |b| b.iter(|| {
+		for p in &packets {
+			sender.send(p.clone()).unwrap();
+		}
+		for _ in 0..BURST {
+			receiver.recv(timeout).unwrap();
+		}
+	}));
+
+	let mut batch = RecvBatch::new(BURST);
+	group.bench_function("batched", |b| b.iter(|| {
+		sender.send_batch(&packets).unwrap();
+		let mut received = 0;
+		while received < BURST {
+			received += receiver.recv_batch(&mut batch, timeout).unwrap().len();
+		}
+	}));
+
+	group.finish();
+}
+
+criterion_group!(benches, bench_burst);
+criterion_main!(benches);
@@ -0,0 +1,72 @@
+// Encode and decode throughput for a range of realistic packets.
+//
+// Run with `cargo bench --bench codec`.
+
+#[macro_use]
+extern crate criterion;
+extern crate osc;
+
+use criterion::{Criterion, Throughput};
+
+use osc::data::OscPacket::*;
+use osc::data::OscArg::*;
+use osc::data::OscPacket;
+use osc::receiver::datagram_to_packet;
+use osc::sender::packet_to_buffer;
+
+fn fader() -> OscPacket {
+	OscMessage{addr: "/mixer/ch/01/fader".to_string(), args: vec!(OscFloat(0.75))}
+}
+
+fn blob() -> OscPacket {
+	let data = (0..8192).map(|i| i as u8).collect();
+	OscMessage{addr: "/video/frame".to_string(), args: vec!(OscInt(1), OscBlob(data))}
+}
+
+fn nested(depth: u32) -> OscPacket {
+	let mut packet = fader();
+	for i in 0..depth {
+		packet = OscBundle{time_tag: (i, 1), conts: vec!(packet, fader())};
+	}
+	packet
+}
+
+fn wide() -> OscPacket {
+	let args = (0..128).map(|i| match i % 3 {
+		0 => OscInt(i),
+		1 => OscFloat(i as f32 * 0.5),
+		_ => OscStr(format!("arg{}", i))
+	}).collect();
+	OscMessage{addr: "/lights/dmx/universe/1".to_string(), args}
+}
+
+fn cases() -> Vec<(&'static str, OscPacket)> {
+	vec!(
+		("fader", fader()),
+		("blob_8k", blob()),
+		("nested_bundle_16", nested(16)),
+		("wide_128_args", wide())
+	)
+}
+
+fn bench_encode(c: &mut Criterion) {
+	let mut group = c.benchmark_group("encode");
+	for (name, packet) in cases() {
+		group.throughput(Throughput::Bytes(packet_to_buffer(packet.clone()).len() as u64 - 4));
+		group.bench_function(name, |b| b.iter(|| packet_to_buffer(packet.clone())));
+	}
+	group.finish();
+}
+
+fn bench_decode(c: &mut Criterion) {
+	let mut group = c.benchmark_group("decode");
+	for (name, packet) in cases() {
+		let buf = packet_to_buffer(packet);
+		group.throughput(Throughput::Bytes(buf.len() as u64 - 4));
+		group.bench_function(name, |b| b.iter(|| datagram_to_packet(&buf[4..]).unwrap()));
+	}
+	group.finish();
+}
+
+criterion_group!(benches, bench_encode, bench_decode);
+criterion_main!(benches);
@@ -0,0 +1,40 @@
+// Round-trip latency of a small message over UDP loopback, to an echo thread
+// and back.
+//
+// Run with `cargo bench --bench loopback`.
+
+#[macro_use]
+extern crate criterion;
+extern crate osc;
+
+use std::thread;
+use std::time::Duration;
+
+use criterion::Criterion;
+
+use osc::data::OscPacket::*;
+use osc::data::OscArg::*;
+use osc::socket::OscSocket;
+
+fn bench_round_trip(c: &mut Criterion) {
+	let echo = OscSocket::bind("127.0.0.1:0").unwrap();
+	let echo_addr = echo.local_addr().unwrap();
+	thread::spawn(move || {
+		// runs until the process exits
+		while let Ok((packet, source)) = echo.recv_from(None) {
+			let _ = echo.send_to(packet, source);
+		}
+	});
+
+	let client = OscSocket::new("127.0.0.1:0".parse().unwrap(), echo_addr).unwrap();
+	let timeout = Some(Duration::from_secs(1));
+	let message = OscMessage{addr: "/mixer/ch/01/fader".to_string(), args: vec!(OscFloat(0.75))};
+
+	c.bench_function("udp_loopback_round_trip", |b| b.iter(|| {
+		client.send(message.clone()).unwrap();
+		client.recv(timeout).unwrap()
+	}));
+}
+
+criterion_group!(benches, bench_round_trip);
+criterion_main!(benches);
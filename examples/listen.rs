@@ -0,0 +1,25 @@
+extern crate osc;
+
+use std::thread;
+use std::time::Duration;
+
+use osc::receiver::*;
+use osc::listener::*;
+
+fn main() {
+
+	let addr = "localhost:7009";
+
+	let receiver = OscReceiver::new(addr).unwrap();
+
+	let listener = OscListener::spawn_with_errors(
+		receiver,
+		|packet, source| println!("contents from {}: {:?}", source, packet),
+		|e| println!("Error: {:?}", e)
+	).unwrap();
+
+	thread::sleep(Duration::from_secs(60));
+
+	listener.stop();
+
+}
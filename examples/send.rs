@@ -11,7 +11,7 @@ fn main() {
 
 	let sender = OscSender::new(local_addr, dest_addr).unwrap();
 
-	let tests = vec!(
+	let tests = [
 		OscMessage{
 			addr: "/test/addr/1".to_string(),
 			args: vec!( OscInt(123), OscFloat(2.0), OscStr("I'm a string".to_string()), OscBlob(vec!(5u8, 10u8, 15u8)) )
@@ -34,7 +34,7 @@ fn main() {
 				}
 			)
 		}
-	);
+	];
 
      for i in 0..5 {
 
@@ -0,0 +1,417 @@
+//! Module for signing OSC with a shared key and checking signatures.
+//!
+//! A Signer wraps each packet in a message to a reserved address carrying the
+//! time it was signed, a session id, a counter, the encoded packet as a blob,
+//! and an HMAC-SHA256 of everything before it as a second blob:
+//!
+//! ```text
+//! /osc-auth/signed ,iiiibb seconds fraction session counter packet mac
+//! ```
+//!
+//! The time is an OSC time tag split into two ints.  Its seconds wrap every
+//! 136 years, first in February 2036, so a Verifier reads them as the time
+//! closest to its own clock.  A Verifier holding the
+//! same key checks the MAC and then rejects packets signed too long ago or
+//! too far in the future, so clocks must agree to within the maximum age, and
+//! packets whose session and counter it has already seen.  It remembers the
+//! last 64 counters of each session, so packets reordered on the way are
+//! still accepted.
+//!
+//! SignedSender and VerifiedReceiver wrap an OscSender and an OscReceiver to
+//! do this on every packet.  The packet itself is not encrypted.
+//!
+//! Only available with the `auth` feature.
+
+extern crate hmac;
+extern crate sha2;
+extern crate getrandom;
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::net::{UdpSocket, SocketAddr, ToSocketAddrs};
+use std::io::{Error, Result};
+use std::io::ErrorKind::InvalidData;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use self::hmac::{Hmac, Mac};
+use self::sha2::Sha256;
+
+use data::*;
+use data::OscPacket::*;
+use data::OscArg::*;
+use receiver::{OscReceiver, datagram_to_packet};
+use sender::{OscSender, packet_to_buffer};
+use transport::Transport;
+
+/// Address of signed packets.
+pub const SIGNED_ADDR: &str = "/osc-auth/signed";
+
+const DEFAULT_MAX_AGE_MS: u64 = 5000;
+// seconds from the OSC time tag epoch, 1900, to the unix epoch
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+// how many counters before the highest seen are remembered per session
+const REPLAY_WINDOW: u32 = 64;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Why a packet failed verification.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum AuthFailure {
+	/// The packet is not a signed packet.
+	Unsigned,
+	/// The MAC doesn't match, so the packet was not signed with the key or
+	/// was changed on the way.
+	BadSignature,
+	/// The packet was signed longer ago, or further in the future, than
+	/// the maximum age.
+	Stale,
+	/// The packet has already been accepted once.
+	Replayed
+}
+
+impl fmt::Display for AuthFailure {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(match *self {
+			AuthFailure::Unsigned => "Packet is not signed.",
+			AuthFailure::BadSignature => "Packet signature does not match.",
+			AuthFailure::Stale => "Packet was signed outside the allowed time.",
+			AuthFailure::Replayed => "Packet has already been received."
+		})
+	}
+}
+
+impl error::Error for AuthFailure {}
+
+/// Signs packets with a shared key.
+pub struct Signer {
+	key: Vec<u8>,
+	session: i32,
+	counter: u32
+}
+
+impl Signer {
+
+	/// Constructs a signer with a new, random session id.  Panics if the
+	/// operating system can't supply random numbers.
+	pub fn new(key: &[u8]) -> Signer {
+		Signer{key: key.to_vec(), session: new_session(), counter: 0}
+	}
+
+	/// Wrap a packet in a signed message.
+	pub fn sign(&mut self, packet: OscPacket) -> OscPacket {
+		self.sign_at(packet, SystemTime::now())
+	}
+
+	fn sign_at(&mut self, packet: OscPacket, now: SystemTime) -> OscPacket {
+		if self.counter == u32::MAX {
+			self.session = new_session();
+			self.counter = 0;
+		}
+		let (sec, frac) = to_time_tag(now);
+		let mut args = vec!(
+			OscInt(sec as i32),
+			OscInt(frac as i32),
+			OscInt(self.session),
+			OscInt(self.counter as i32),
+			OscBlob(packet_to_buffer(packet)[4..].to_vec())
+		);
+		self.counter += 1;
+		let mac = mac_for(&self.key, args.clone()).finalize().into_bytes();
+		args.push(OscBlob(mac.to_vec()));
+		OscMessage{addr: SIGNED_ADDR.to_string(), args}
+	}
+}
+
+// the highest counter accepted from a session, and which of the counters
+// before it have been accepted, one bit each
+struct Window {
+	highest: u32,
+	seen: u64,
+	last_seen: Instant
+}
+
+impl Window {
+	fn accept(&mut self, counter: u32) -> bool {
+		if counter > self.highest {
+			let shift = counter - self.highest;
+			self.seen = if shift >= REPLAY_WINDOW { 0 } else { self.seen << shift };
+			self.seen |= 1;
+			self.highest = counter;
+			return true;
+		}
+		let back = self.highest - counter;
+		if back >= REPLAY_WINDOW || self.seen & (1 << back) != 0 {
+			return false;
+		}
+		self.seen |= 1 << back;
+		true
+	}
+}
+
+/// Checks signed packets against a shared key, rejecting stale and replayed
+/// packets.
+pub struct Verifier {
+	key: Vec<u8>,
+	max_age: Duration,
+	sessions: HashMap<i32, Window>
+}
+
+impl Verifier {
+
+	/// Constructs a verifier accepting packets signed within the last 5s.
+	pub fn new(key: &[u8]) -> Verifier {
+		Verifier{key: key.to_vec(), max_age: Duration::from_millis(DEFAULT_MAX_AGE_MS), sessions: HashMap::new()}
+	}
+
+	/// Set how far from the current time a packet may have been signed.
+	pub fn set_max_age(&mut self, max_age: Duration) {
+		self.max_age = max_age;
+	}
+
+	/// Check a signed packet and unwrap it.
+	pub fn verify(&mut self, packet: &OscPacket) -> std::result::Result<OscPacket, AuthFailure> {
+		self.verify_at(packet, SystemTime::now())
+	}
+
+	fn verify_at(&mut self, packet: &OscPacket, now: SystemTime) -> std::result::Result<OscPacket, AuthFailure> {
+		let args = match *packet {
+			OscMessage{ref addr, ref args} if addr == SIGNED_ADDR => args,
+			_ => return Err(AuthFailure::Unsigned)
+		};
+		let (sec, frac, session, counter, inner, mac) = match args[..] {
+			[OscInt(sec), OscInt(frac), OscInt(session), OscInt(counter), OscBlob(ref inner), OscBlob(ref mac)] =>
+				(sec as u32, frac as u32, session, counter as u32, inner, mac),
+			_ => return Err(AuthFailure::Unsigned)
+		};
+		mac_for(&self.key, args[..5].to_vec()).verify_slice(mac).map_err(|_| AuthFailure::BadSignature)?;
+
+		let signed_at = from_time_tag((sec, frac), now).ok_or(AuthFailure::Stale)?;
+		let skew = now.duration_since(signed_at).or_else(|_| signed_at.duration_since(now)).unwrap_or_default();
+		if skew > self.max_age {
+			return Err(AuthFailure::Stale);
+		}
+		let inner = datagram_to_packet(inner).map_err(|_| AuthFailure::BadSignature)?;
+
+		if !self.sessions.contains_key(&session) {
+			// a replay from a forgotten session would be stale
+			let forget_after = self.max_age * 2;
+			self.sessions.retain(|_, w| w.last_seen.elapsed() < forget_after);
+		}
+		let window = self.sessions.entry(session)
+			.or_insert(Window{highest: counter, seen: 0, last_seen: Instant::now()});
+		if !window.accept(counter) {
+			return Err(AuthFailure::Replayed);
+		}
+		window.last_seen = Instant::now();
+		Ok(inner)
+	}
+}
+
+/// Signs every packet sent through an OscSender.
+pub struct SignedSender<T: ToSocketAddrs, S: Transport = UdpSocket> {
+	sender: OscSender<T, S>,
+	signer: Signer
+}
+
+impl<T: ToSocketAddrs, S: Transport> SignedSender<T, S> {
+
+	/// Constructs a SignedSender signing with a shared key.
+	pub fn new(sender: OscSender<T, S>, key: &[u8]) -> Self {
+		SignedSender{sender, signer: Signer::new(key)}
+	}
+
+	/// Sign a packet and send it.
+	pub fn send(&mut self, packet: OscPacket) -> Result<usize> {
+		let signed = self.signer.sign(packet);
+		self.sender.send(signed)
+	}
+
+	/// Give back the sender.
+	pub fn into_inner(self) -> OscSender<T, S> {
+		self.sender
+	}
+}
+
+/// Verifies every packet received by an OscReceiver.
+pub struct VerifiedReceiver<S: Transport = UdpSocket> {
+	receiver: OscReceiver<S>,
+	verifier: Verifier
+}
+
+impl<S: Transport> VerifiedReceiver<S> {
+
+	/// Constructs a VerifiedReceiver checking against a shared key.
+	pub fn new(receiver: OscReceiver<S>, key: &[u8]) -> Self {
+		VerifiedReceiver{receiver, verifier: Verifier::new(key)}
+	}
+
+	/// Set how far from the current time a packet may have been signed.
+	pub fn set_max_age(&mut self, max_age: Duration) {
+		self.verifier.set_max_age(max_age);
+	}
+
+	/// Receive a signed packet and unwrap it.  Blocks and times out in the
+	/// same way as OscReceiver::recv.  A packet that fails verification is
+	/// an InvalidData error holding an AuthFailure.
+	pub fn recv(&mut self, timeout: Option<Duration>) -> Result<OscPacket> {
+		self.recv_from(timeout).map(|(packet, _)| packet)
+	}
+
+	/// Receive a signed packet and unwrap it, along with the address it was
+	/// sent from.
+	pub fn recv_from(&mut self, timeout: Option<Duration>) -> Result<(OscPacket, SocketAddr)> {
+		let (packet, source) = self.receiver.recv_from(timeout)?;
+		match self.verifier.verify(&packet) {
+			Ok(inner) => Ok((inner, source)),
+			Err(failure) => Err(Error::new(InvalidData, failure))
+		}
+	}
+
+	/// Give back the receiver.
+	pub fn into_inner(self) -> OscReceiver<S> {
+		self.receiver
+	}
+}
+
+fn mac_for(key: &[u8], args: Vec<OscArg>) -> HmacSha256 {
+	let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+	let unsigned = OscMessage{addr: SIGNED_ADDR.to_string(), args};
+	mac.update(&packet_to_buffer(unsigned)[4..]);
+	mac
+}
+
+fn new_session() -> i32 {
+	getrandom::u32().expect("The OS can't supply random numbers for a session id.") as i32
+}
+
+// the seconds of a time tag wrap every 2^32 seconds; only the low bits are kept
+fn to_time_tag(t: SystemTime) -> OscTimeTag {
+	let since_epoch = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+	let sec = since_epoch.as_secs() + NTP_UNIX_OFFSET;
+	let frac = (since_epoch.subsec_nanos() as u64 * (1u64 << 32)) / 1_000_000_000;
+	(sec as u32, frac as u32)
+}
+
+// read a time tag as the time closest to now, within 68 years either way
+fn from_time_tag((sec, frac): OscTimeTag, now: SystemTime) -> Option<SystemTime> {
+	let now_secs = now.duration_since(UNIX_EPOCH).ok()?.as_secs() as i64 + NTP_UNIX_OFFSET as i64;
+	let offset = sec.wrapping_sub(now_secs as u32) as i32 as i64;
+	let unix_secs = now_secs + offset - NTP_UNIX_OFFSET as i64;
+	if unix_secs < 0 {
+		return None;
+	}
+	let nanos = (frac as u64 * 1_000_000_000) >> 32;
+	UNIX_EPOCH.checked_add(Duration::new(unix_secs as u64, nanos as u32))
+}
+
+#[cfg(test)]
+fn cue(i: i32) -> OscPacket {
+	OscMessage{addr: "/cue/go".to_string(), args: vec!(OscInt(i))}
+}
+
+#[test]
+fn test_sign_and_verify() {
+	let mut signer = Signer::new(b"venue key");
+	let mut verifier = Verifier::new(b"venue key");
+
+	let signed = signer.sign(cue(1));
+	assert_eq!(verifier.verify(&signed), Ok(cue(1)));
+	assert_eq!(verifier.verify(&signed), Err(AuthFailure::Replayed));
+	assert_eq!(verifier.verify(&cue(1)), Err(AuthFailure::Unsigned));
+
+	let mut wrong_key = Verifier::new(b"other key");
+	assert_eq!(wrong_key.verify(&signer.sign(cue(2))), Err(AuthFailure::BadSignature));
+
+	// change the wrapped packet without re-signing
+	let tampered = match signer.sign(cue(3)) {
+		OscMessage{addr, mut args} => {
+			args[4] = OscBlob(packet_to_buffer(cue(4))[4..].to_vec());
+			OscMessage{addr, args}
+		},
+		bundle => bundle
+	};
+	assert_eq!(verifier.verify(&tampered), Err(AuthFailure::BadSignature));
+}
+
+#[test]
+fn test_verify_stale() {
+	let mut signer = Signer::new(b"key");
+	let mut verifier = Verifier::new(b"key");
+	verifier.set_max_age(Duration::from_secs(2));
+	let now = SystemTime::now();
+
+	assert_eq!(verifier.verify_at(&signer.sign_at(cue(1), now - Duration::from_secs(3)), now), Err(AuthFailure::Stale));
+	assert_eq!(verifier.verify_at(&signer.sign_at(cue(2), now + Duration::from_secs(3)), now), Err(AuthFailure::Stale));
+	assert_eq!(verifier.verify_at(&signer.sign_at(cue(3), now - Duration::from_secs(1)), now), Ok(cue(3)));
+}
+
+#[test]
+fn test_replay_window() {
+	let mut signer = Signer::new(b"key");
+	let mut verifier = Verifier::new(b"key");
+	let signed: Vec<OscPacket> = (0..100).map(|i| signer.sign(cue(i))).collect();
+
+	// reordered packets are accepted once each
+	assert!(verifier.verify(&signed[5]).is_ok());
+	assert!(verifier.verify(&signed[3]).is_ok());
+	assert!(verifier.verify(&signed[4]).is_ok());
+	assert_eq!(verifier.verify(&signed[3]), Err(AuthFailure::Replayed));
+
+	// counters too far behind the highest can't be told apart from replays
+	assert!(verifier.verify(&signed[99]).is_ok());
+	assert_eq!(verifier.verify(&signed[10]), Err(AuthFailure::Replayed));
+	assert!(verifier.verify(&signed[50]).is_ok());
+
+	// another sender has its own session
+	let mut other = Signer::new(b"key");
+	assert!(verifier.verify(&other.sign(cue(0))).is_ok());
+}
+
+#[test]
+fn test_time_tag_era_rollover() {
+	// the seconds of a time tag wrap at 2036-02-07 06:28:16 UTC
+	let rollover = UNIX_EPOCH + Duration::from_secs((1u64 << 32) - NTP_UNIX_OFFSET);
+	let before = rollover - Duration::from_millis(1500);
+	let after = rollover + Duration::from_millis(1500);
+	assert_eq!(to_time_tag(after).0, 1);
+	assert_eq!(to_time_tag(before).0, u32::MAX - 1);
+
+	for &now in &[before, rollover, after] {
+		assert_eq!(from_time_tag(to_time_tag(before), now), Some(before));
+		assert_eq!(from_time_tag(to_time_tag(after), now), Some(after));
+	}
+	let now = SystemTime::now();
+	// a time before the unix epoch can't be a SystemTime here
+	assert!(from_time_tag(((NTP_UNIX_OFFSET - 10) as u32, 0), UNIX_EPOCH).is_none());
+	assert!(now.duration_since(from_time_tag(to_time_tag(now), now).unwrap()).unwrap() < Duration::from_micros(1));
+
+	let mut signer = Signer::new(b"key");
+	let mut verifier = Verifier::new(b"key");
+	assert_eq!(verifier.verify_at(&signer.sign_at(cue(1), before), after), Ok(cue(1)));
+	assert_eq!(verifier.verify_at(&signer.sign_at(cue(2), after), after), Ok(cue(2)));
+}
+
+#[test]
+fn test_signed_sender_and_receiver() {
+	use std::io::ErrorKind::TimedOut;
+	use transport::LoopbackNetwork;
+
+	let network = LoopbackNetwork::new();
+	let receiver = OscReceiver::from_socket(network.bind("10.0.0.1:9000").unwrap());
+	let dest = receiver.local_addr().unwrap();
+	let mut controller = SignedSender::new(OscSender::from_socket(network.bind("10.0.0.2:0").unwrap(), dest), b"key");
+	let intruder = OscSender::from_socket(network.bind("10.0.0.3:0").unwrap(), dest);
+	let mut receiver = VerifiedReceiver::new(receiver, b"key");
+	let timeout = Some(Duration::from_secs(1));
+
+	controller.send(cue(1)).unwrap();
+	assert_eq!(receiver.recv(timeout).unwrap(), cue(1));
+
+	intruder.send(cue(2)).unwrap();
+	let err = receiver.recv(timeout).unwrap_err();
+	assert_eq!(err.kind(), InvalidData);
+	assert_eq!(err.get_ref().and_then(|e| e.downcast_ref::<AuthFailure>()), Some(&AuthFailure::Unsigned));
+
+	assert_eq!(receiver.recv(Some(Duration::from_millis(10))).unwrap_err().kind(), TimedOut);
+}
@@ -0,0 +1,209 @@
+//! Module for receiving and sending many datagrams per system call on Linux.
+//!
+//! At high message rates a system call per datagram dominates the cost of
+//! OscReceiver::recv and OscSender::send.  A RecvBatch holds a pool of
+//! receive buffers allocated once and fills as many of them as are waiting
+//! with a single recvmmsg call; OscReceiver::recv_batch decodes them.
+//! send_batch encodes many packets and hands them to sendmmsg together, which
+//! OscSender::send_batch uses.
+//!
+//! Only available on Linux with the `batch` feature.
+
+extern crate libc;
+
+use std::mem;
+use std::ptr;
+use std::net::{UdpSocket, SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
+use std::io::{Error, Result};
+use std::os::unix::io::AsRawFd;
+
+use data::*;
+use receiver::{UDP_BUFFER_SIZE, datagram_to_packet};
+use sender::packet_to_buffer;
+
+/// A pool of receive buffers filled by a single system call.
+pub struct RecvBatch {
+	bufs: Vec<Vec<u8>>,
+	addrs: Vec<libc::sockaddr_storage>,
+	lens: Vec<usize>,
+	filled: usize
+}
+
+impl RecvBatch {
+
+	/// Allocate buffers for receiving up to capacity datagrams at once.
+	pub fn new(capacity: usize) -> RecvBatch {
+		RecvBatch{
+			// a byte bigger than the receive buffer, to tell when a datagram
+			// doesn't fit
+			bufs: vec!(vec!(0u8; UDP_BUFFER_SIZE + 1); capacity.max(1)),
+			addrs: vec!(unsafe { mem::zeroed() }; capacity.max(1)),
+			lens: vec!(0; capacity.max(1)),
+			filled: 0
+		}
+	}
+
+	/// The most datagrams received at once.
+	pub fn capacity(&self) -> usize {
+		self.bufs.len()
+	}
+
+	/// Receive as many datagrams as are waiting, up to the capacity, blocking
+	/// for the first one in the same way as the socket's recv_from.  Returns
+	/// the number received.
+	pub fn recv(&mut self, socket: &UdpSocket) -> Result<usize> {
+		self.filled = 0;
+		let mut iovecs: Vec<libc::iovec> = self.bufs.iter_mut()
+			.map(|b| libc::iovec{iov_base: b.as_mut_ptr() as *mut libc::c_void, iov_len: b.len()})
+			.collect();
+		let mut headers: Vec<libc::mmsghdr> = iovecs.iter_mut().zip(self.addrs.iter_mut())
+			.map(|(iov, addr)| {
+				let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+				header.msg_hdr.msg_name = addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+				header.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+				header.msg_hdr.msg_iov = iov;
+				header.msg_hdr.msg_iovlen = 1;
+				header
+			})
+			.collect();
+
+		let n = unsafe {
+			libc::recvmmsg(socket.as_raw_fd(), headers.as_mut_ptr(), headers.len() as libc::c_uint,
+				libc::MSG_WAITFORONE, ptr::null_mut())
+		};
+		if n < 0 {
+			return Err(Error::last_os_error());
+		}
+		for (len, header) in self.lens.iter_mut().zip(&headers).take(n as usize) {
+			*len = header.msg_len as usize;
+		}
+		self.filled = n as usize;
+		Ok(self.filled)
+	}
+
+	/// The contents and source of each datagram from the last recv.
+	pub fn datagrams(&self) -> Vec<(&[u8], Option<SocketAddr>)> {
+		(0..self.filled)
+			.map(|i| (&self.bufs[i][..self.lens[i]], to_socket_addr(&self.addrs[i])))
+			.collect()
+	}
+
+	/// Decode the datagrams from the last recv.  Datagrams that fail to
+	/// decode, are too big for the receive buffer, or whose source can't be
+	/// read, are skipped.
+	pub fn packets(&self) -> Vec<(OscPacket, SocketAddr)> {
+		self.datagrams().into_iter()
+			.filter(|&(buf, _)| buf.len() <= UDP_BUFFER_SIZE)
+			.filter_map(|(buf, source)| Some((datagram_to_packet(buf).ok()?, source?)))
+			.collect()
+	}
+}
+
+/// Encode packets and send them all to one destination, using as few system
+/// calls as the kernel allows.  Returns the number of packets sent, which is
+/// fewer than given if sending fails part way; an error is only returned
+/// when nothing was sent.
+pub fn send_batch(socket: &UdpSocket, dest: SocketAddr, packets: &[OscPacket]) -> Result<usize> {
+	let mut bufs: Vec<Vec<u8>> = packets.iter().map(|p| packet_to_buffer(p.clone())).collect();
+	let (mut addr, addr_len) = from_socket_addr(&dest);
+	let mut iovecs: Vec<libc::iovec> = bufs.iter_mut()
+		.map(|b| libc::iovec{iov_base: b[4..].as_mut_ptr() as *mut libc::c_void, iov_len: b.len() - 4})
+		.collect();
+	let mut headers: Vec<libc::mmsghdr> = iovecs.iter_mut()
+		.map(|iov| {
+			let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+			header.msg_hdr.msg_name = &mut addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+			header.msg_hdr.msg_namelen = addr_len;
+			header.msg_hdr.msg_iov = iov;
+			header.msg_hdr.msg_iovlen = 1;
+			header
+		})
+		.collect();
+
+	let mut sent = 0;
+	while sent < headers.len() {
+		let n = unsafe {
+			libc::sendmmsg(socket.as_raw_fd(), headers[sent..].as_mut_ptr(), (headers.len() - sent) as libc::c_uint, 0)
+		};
+		if n < 0 {
+			let err = Error::last_os_error();
+			return if sent > 0 { Ok(sent) } else { Err(err) };
+		}
+		sent += n as usize;
+	}
+	Ok(sent)
+}
+
+fn to_socket_addr(addr: &libc::sockaddr_storage) -> Option<SocketAddr> {
+	match addr.ss_family as libc::c_int {
+		libc::AF_INET => {
+			let addr = unsafe { &*(addr as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+			let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+			Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+		},
+		libc::AF_INET6 => {
+			let addr = unsafe { &*(addr as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+			let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+			Some(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(addr.sin6_port),
+				addr.sin6_flowinfo, addr.sin6_scope_id)))
+		},
+		_ => None
+	}
+}
+
+fn from_socket_addr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+	let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+	let len = match *addr {
+		SocketAddr::V4(ref a) => {
+			let sin = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+			sin.sin_family = libc::AF_INET as libc::sa_family_t;
+			sin.sin_port = a.port().to_be();
+			sin.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+			mem::size_of::<libc::sockaddr_in>()
+		},
+		SocketAddr::V6(ref a) => {
+			let sin6 = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+			sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+			sin6.sin6_port = a.port().to_be();
+			sin6.sin6_flowinfo = a.flowinfo();
+			sin6.sin6_addr.s6_addr = a.ip().octets();
+			sin6.sin6_scope_id = a.scope_id();
+			mem::size_of::<libc::sockaddr_in6>()
+		}
+	};
+	(storage, len as libc::socklen_t)
+}
+
+#[test]
+fn test_socket_addr_conversion() {
+	for s in &["127.0.0.1:9000", "[::1]:53000", "[fe80::1%2]:1"] {
+		let addr: SocketAddr = s.parse().unwrap();
+		let (storage, _) = from_socket_addr(&addr);
+		assert_eq!(to_socket_addr(&storage), Some(addr));
+	}
+}
+
+#[test]
+fn test_batch_round_trip() {
+	use std::time::Duration;
+	use data::OscPacket::*;
+	use data::OscArg::*;
+	use receiver::OscReceiver;
+	use sender::OscSender;
+
+	let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+	let receiver = OscReceiver::new(localhost).unwrap();
+	let sender = OscSender::new(localhost, receiver.local_addr().unwrap()).unwrap();
+
+	let packets: Vec<OscPacket> = (0..20).map(|i| OscMessage{addr: "/fader".to_string(), args: vec!(OscInt(i))}).collect();
+	assert_eq!(sender.send_batch(&packets).unwrap(), 20);
+
+	let mut batch = RecvBatch::new(8);
+	let mut received = Vec::new();
+	while received.len() < 20 {
+		let got = receiver.recv_batch(&mut batch, Some(Duration::from_secs(5))).unwrap();
+		assert!(!got.is_empty() && got.len() <= 8);
+		received.extend(got.into_iter().map(|(p, _)| p));
+	}
+	assert_eq!(received, packets);
+}
@@ -0,0 +1,265 @@
+//! Command-line tools for inspecting and generating OSC traffic.  Built with the
+//! `cli` feature.  Packets are written and read in the syntax of the text
+//! module, and recordings use the format of the record module.
+
+extern crate osc;
+
+use std::env;
+use std::process;
+use std::collections::VecDeque;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::io::{Error, Result};
+use std::io::ErrorKind::InvalidInput;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use osc::data::{OscPacket, OscArg};
+use osc::receiver::OscReceiver;
+use osc::sender::OscSender;
+use osc::record::{OscRecorder, OscPlayer};
+use osc::text::parse_packet;
+use osc::forward::{OscForwarder, RuleSet};
+
+const USAGE: &str = "\
+usage: osc-cli <command> [arguments]
+
+commands:
+  dump <listen-addr>
+      print every packet received, with the time and source address
+  send <dest-addr> <packet>...
+      send one packet written in text syntax, e.g. /mixer/1/fader ,f 0.5,
+      either as one argument or split into several; split, a string
+      argument needs no double quotes
+  record <listen-addr> <file>
+      write every packet received to a recording file
+  replay <file> <dest-addr> [--speed <factor>] [--loop [<count>]] [--filter <pattern>]...
+      send a recording with its original timing
+  forward <listen-addr> <dest-addr>... [--rules <file>]
+      re-send every packet received to one or more destinations, optionally
+      rewriting it with a rule file (see the forward module)";
+
+fn main() {
+	let args: Vec<String> = env::args().skip(1).collect();
+
+	let result = match args.first().map(|s| s.as_str()) {
+		Some("dump") => dump(&args[1..]),
+		Some("send") => send(&args[1..]),
+		Some("record") => record(&args[1..]),
+		Some("replay") => replay(&args[1..]),
+		Some("forward") => forward(&args[1..]),
+		Some("help") | Some("--help") | Some("-h") => {
+			println!("{}", USAGE);
+			Ok(())
+		},
+		_ => Err(usage_err())
+	};
+
+	if let Err(e) = result {
+		eprintln!("osc-cli: {}", e);
+		process::exit(1);
+	}
+}
+
+fn usage_err() -> Error {
+	Error::new(InvalidInput, format!("invalid arguments\n\n{}", USAGE))
+}
+
+fn resolve(addr: &str) -> Result<SocketAddr> {
+	addr.to_socket_addrs()?.next()
+		.ok_or_else(|| Error::new(InvalidInput, format!("could not resolve {}", addr)))
+}
+
+// bind a sender on an ephemeral port of the same address family as the destination
+fn sender_to(dest: &str) -> Result<OscSender<SocketAddr>> {
+	let dest = resolve(dest)?;
+	let local = if dest.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+	OscSender::new(local.parse().unwrap(), dest)
+}
+
+// wall-clock time of day in UTC, to the microsecond
+fn timestamp() -> String {
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+	let secs = now.as_secs() % 86400;
+	format!("{:02}:{:02}:{:02}.{:06}", secs / 3600, secs / 60 % 60, secs % 60, now.subsec_micros())
+}
+
+fn dump(args: &[String]) -> Result<()> {
+	if args.len() != 1 { return Err(usage_err()); }
+	let receiver = OscReceiver::new(resolve(&args[0])?)?;
+	eprintln!("listening on {}", receiver.local_addr()?);
+
+	loop {
+		match receiver.recv_from(None) {
+			Ok((packet, source)) => println!("{} {} {}", timestamp(), source, packet),
+			Err(e) => eprintln!("{} error: {}", timestamp(), e)
+		}
+	}
+}
+
+fn send(args: &[String]) -> Result<()> {
+	if args.len() < 2 { return Err(usage_err()); }
+	let packet = packet_from_args(&args[1..])?;
+	sender_to(&args[0])?.send(packet).map(|_| ())
+}
+
+// a packet given as one argument is parsed as it is; split over several, each
+// argument is one token, so the quotes the shell removed from string
+// arguments are put back, going by their place in the type tags
+fn packet_from_args(args: &[String]) -> Result<OscPacket> {
+	if args.len() == 1 {
+		return parse_packet(&args[0]);
+	}
+	let mut tokens = Vec::with_capacity(args.len());
+	let mut tags = VecDeque::new();
+	for a in args {
+		match tags.pop_front() {
+			Some('s') if !(a.len() >= 2 && a.starts_with('"') && a.ends_with('"')) => {
+				tokens.push(OscArg::OscStr(a.clone()).to_string());
+			},
+			Some(_) => tokens.push(a.clone()),
+			None => {
+				if let Some(t) = a.strip_prefix(',') {
+					tags = t.chars().collect();
+				}
+				tokens.push(a.clone());
+			}
+		}
+	}
+	parse_packet(&tokens.join(" "))
+}
+
+fn record(args: &[String]) -> Result<()> {
+	if args.len() != 2 { return Err(usage_err()); }
+	let receiver = OscReceiver::new(resolve(&args[0])?)?;
+	eprintln!("recording from {} to {}", receiver.local_addr()?, args[1]);
+
+	let mut recorder = OscRecorder::create(receiver, &args[1])?;
+	loop {
+		match recorder.record(None) {
+			Ok(recorded) => println!("{}", recorded.to_line()),
+			Err(e) => eprintln!("{} error: {}", timestamp(), e)
+		}
+	}
+}
+
+fn replay(args: &[String]) -> Result<()> {
+	if args.len() < 2 { return Err(usage_err()); }
+	let mut player = OscPlayer::load(&args[0])?;
+	let sender = sender_to(&args[1])?;
+
+	let mut rest = args[2..].iter().peekable();
+	while let Some(flag) = rest.next() {
+		match flag.as_str() {
+			"--speed" => {
+				let speed = rest.next().and_then(|s| s.parse::<f64>().ok())
+					.filter(|s| *s > 0.0 && s.is_finite())
+					.ok_or_else(usage_err)?;
+				player = player.speed(speed);
+			},
+			"--loop" => {
+				// an optional count; without one, loop forever
+				let count = match rest.peek().and_then(|s| s.parse::<u32>().ok()) {
+					Some(n) => {
+						rest.next();
+						Some(n)
+					},
+					None => None
+				};
+				player = player.loops(count);
+			},
+			"--filter" => {
+				let pattern = rest.next().ok_or_else(usage_err)?;
+				player = player.filter(pattern);
+			},
+			_ => return Err(usage_err())
+		}
+	}
+
+	let sent = player.play(&sender)?;
+	eprintln!("sent {} packets", sent);
+	Ok(())
+}
+
+// addresses are every argument up to an optional --rules <file>, the first
+// being the one to listen on
+fn split_forward_args(args: &[String]) -> Result<(&[String], Option<&String>)> {
+	let (addrs, rules) = match args.iter().position(|a| a == "--rules") {
+		Some(i) if i + 2 == args.len() => (&args[..i], Some(&args[i + 1])),
+		Some(_) => return Err(usage_err()),
+		None => (args, None)
+	};
+	if addrs.len() < 2 { return Err(usage_err()); }
+	Ok((addrs, rules))
+}
+
+fn forward(args: &[String]) -> Result<()> {
+	let (addrs, rules) = match split_forward_args(args)? {
+		(addrs, Some(path)) => (addrs, RuleSet::load(path)?),
+		(addrs, None) => (addrs, RuleSet::new())
+	};
+
+	let receiver = OscReceiver::new(resolve(&addrs[0])?)?;
+	eprintln!("forwarding from {} to {}", receiver.local_addr()?, addrs[1..].join(", "));
+
+	let mut forwarder = OscForwarder::new(receiver, rules);
+	for dest in &addrs[1..] {
+		forwarder.add_destination(sender_to(dest)?);
+	}
+
+	loop {
+		match forwarder.forward_from(None) {
+			Ok((Some(packet), source)) => println!("{} {} {}", timestamp(), source, packet),
+			Ok((None, _)) => (),
+			Err(e) => eprintln!("{} error: {}", timestamp(), e)
+		}
+	}
+}
+
+#[cfg(test)]
+fn strings(args: &[&str]) -> Vec<String> {
+	args.iter().map(|a| a.to_string()).collect()
+}
+
+#[test]
+fn test_packet_from_args() {
+	let expected = OscPacket::OscMessage{
+		addr: "/a".to_string(),
+		args: vec!(OscArg::OscStr("x y".to_string()), OscArg::OscFloat(0.5), OscArg::OscStr("".to_string()))
+	};
+
+	// as one argument, quoted for the shell as a whole
+	assert_eq!(packet_from_args(&strings(&["/a ,sfs \"x y\" 0.5 \"\""])).unwrap(), expected);
+
+	// split by the shell, which has removed the quotes around strings
+	assert_eq!(packet_from_args(&strings(&["/a", ",sfs", "x y", "0.5", ""])).unwrap(), expected);
+	assert_eq!(packet_from_args(&strings(&["/a", ",sfs", "\"x y\"", "0.5", "\"\""])).unwrap(), expected);
+
+	// single words are strings by their type tag too
+	let hello = OscPacket::OscMessage{addr: "/a".to_string(), args: vec!(OscArg::OscStr("hello".to_string()))};
+	assert_eq!(packet_from_args(&strings(&["/a", ",s", "hello"])).unwrap(), hello);
+	assert_eq!(packet_from_args(&strings(&["/a", ",s", "\"hello\""])).unwrap(), hello);
+	assert_eq!(packet_from_args(&strings(&["/a", ",s", "x y"])).unwrap(),
+		OscPacket::OscMessage{addr: "/a".to_string(), args: vec!(OscArg::OscStr("x y".to_string()))});
+	assert_eq!(packet_from_args(&strings(&["#bundle", "0:1", "[", "/b", ",is", "1", "go", "]"])).unwrap(),
+		OscPacket::OscBundle{time_tag: (0, 1), conts: vec!(OscPacket::OscMessage{
+			addr: "/b".to_string(), args: vec!(OscArg::OscInt(1), OscArg::OscStr("go".to_string()))
+		})});
+
+	assert_eq!(packet_from_args(&strings(&["#bundle", "0:1", "[", "/b", "]"])).unwrap(),
+		OscPacket::OscBundle{time_tag: (0, 1), conts: vec!(OscPacket::OscMessage{addr: "/b".to_string(), args: vec!()})});
+
+	assert!(packet_from_args(&strings(&["/a", ",i", "x y"])).is_err());
+	assert!(packet_from_args(&strings(&["/a ,i"])).is_err());
+}
+
+#[test]
+fn test_split_forward_args() {
+	let args = strings(&["0.0.0.0:9000", "10.0.0.1:9000", "10.0.0.2:9000"]);
+	assert_eq!(split_forward_args(&args).unwrap(), (&args[..], None));
+
+	let args = strings(&["0.0.0.0:9000", "10.0.0.1:9000", "--rules", "rules.txt"]);
+	assert_eq!(split_forward_args(&args).unwrap(), (&args[..2], Some(&args[3])));
+
+	assert!(split_forward_args(&strings(&["0.0.0.0:9000"])).is_err());
+	assert!(split_forward_args(&strings(&["0.0.0.0:9000", "--rules", "rules.txt"])).is_err());
+	assert!(split_forward_args(&strings(&["0.0.0.0:9000", "10.0.0.1:9000", "--rules"])).is_err());
+}
@@ -0,0 +1,151 @@
+//! Module for fitting OSC into datagrams of a limited size.
+//!
+//! A datagram bigger than the path MTU is fragmented or dropped on the way, so
+//! packets meant for the network should be kept under a target size.  pack
+//! gathers many messages into as few bundles as fit, and split breaks an
+//! oversized bundle into several with the same time tag.  A message that won't
+//! fit in a datagram on its own is reported as an InvalidInput error.
+
+use std::io::{Error, Result};
+use std::io::ErrorKind::InvalidInput;
+
+use data::*;
+use data::OscPacket::*;
+use data::OscArg::*;
+use util::four_byte_pad;
+
+/// The largest UDP payload that fits in a single Ethernet frame.
+pub const DEFAULT_MAX_DATAGRAM: usize = 1472;
+
+/// The time tag meaning "immediately".
+pub const IMMEDIATE: OscTimeTag = (0, 1);
+
+/// The size of a bundle's header: `#bundle\0` and the time tag.
+pub const BUNDLE_HEADER_SIZE: usize = 16;
+/// The size of the length that precedes each bundle element.
+pub const ELEMENT_SIZE_SIZE: usize = 4;
+
+/// The size in bytes of a packet once encoded, without the size prefix added
+/// by sender::packet_to_buffer.
+pub fn encoded_len(packet: &OscPacket) -> usize {
+	match *packet {
+		OscMessage{ref addr, ref args} => {
+			osc_string_len(addr.len())
+				+ osc_string_len(args.len() + 1)
+				+ args.iter().map(arg_len).sum::<usize>()
+		},
+		OscBundle{ref conts, ..} => {
+			BUNDLE_HEADER_SIZE + conts.iter().map(|p| ELEMENT_SIZE_SIZE + encoded_len(p)).sum::<usize>()
+		}
+	}
+}
+
+/// Gather packets into bundles with the given time tag, each no bigger than
+/// max_size bytes once encoded.  Packets keep their order.  A nested bundle
+/// that doesn't fit is split; a message that doesn't fit is an error.
+pub fn pack(packets: Vec<OscPacket>, time_tag: OscTimeTag, max_size: usize) -> Result<Vec<OscPacket>> {
+	let mut bundles = Vec::new();
+	let mut conts = Vec::new();
+	let mut size = BUNDLE_HEADER_SIZE;
+
+	for packet in packets {
+		for piece in split(packet, max_size.saturating_sub(BUNDLE_HEADER_SIZE + ELEMENT_SIZE_SIZE))? {
+			let piece_size = ELEMENT_SIZE_SIZE + encoded_len(&piece);
+			if size + piece_size > max_size && !conts.is_empty() {
+				bundles.push(OscBundle{time_tag, conts});
+				conts = Vec::new();
+				size = BUNDLE_HEADER_SIZE;
+			}
+			conts.push(piece);
+			size += piece_size;
+		}
+	}
+	if !conts.is_empty() {
+		bundles.push(OscBundle{time_tag, conts});
+	}
+	Ok(bundles)
+}
+
+/// Break a packet into pieces no bigger than max_size bytes once encoded.  A
+/// packet that fits is returned as it is; a bundle that doesn't is divided
+/// into several bundles with the same time tag.  A message that doesn't fit is
+/// an error.
+pub fn split(packet: OscPacket, max_size: usize) -> Result<Vec<OscPacket>> {
+	let size = encoded_len(&packet);
+	if size <= max_size {
+		return Ok(vec!(packet));
+	}
+	match packet {
+		OscMessage{addr, ..} => Err(Error::new(InvalidInput,
+			format!("Message to {} is {} bytes, too large for a {} byte datagram.", addr, size, max_size))),
+		OscBundle{time_tag, conts} => {
+			if max_size < BUNDLE_HEADER_SIZE + ELEMENT_SIZE_SIZE {
+				return Err(Error::new(InvalidInput, format!("A {} byte datagram can't hold a bundle.", max_size)));
+			}
+			pack(conts, time_tag, max_size)
+		}
+	}
+}
+
+// size of a null-terminated, null-padded string of len bytes
+fn osc_string_len(len: usize) -> usize {
+	len + 1 + four_byte_pad(len + 1)
+}
+
+fn arg_len(arg: &OscArg) -> usize {
+	match *arg {
+		OscInt(_) | OscFloat(_) => 4,
+		OscStr(ref s) => osc_string_len(s.len()),
+		OscBlob(ref b) => 4 + b.len() + four_byte_pad(b.len())
+	}
+}
+
+#[test]
+fn test_encoded_len() {
+	use sender::packet_to_buffer;
+
+	let packets = vec!(
+		OscMessage{addr: "/".to_string(), args: vec!()},
+		OscMessage{addr: "/abc".to_string(), args: vec!(OscInt(1), OscFloat(2.0), OscStr("xyz".to_string()))},
+		OscMessage{addr: "/blob".to_string(), args: vec!(OscBlob(vec!(1, 2, 3, 4, 5)), OscStr(String::new()))},
+		OscBundle{time_tag: IMMEDIATE, conts: vec!(
+			OscMessage{addr: "/a".to_string(), args: vec!(OscInt(1))},
+			OscBundle{time_tag: (1, 2), conts: vec!()}
+		)}
+	);
+	for packet in packets {
+		assert_eq!(encoded_len(&packet), packet_to_buffer(packet.clone()).len() - 4, "{}", packet);
+	}
+}
+
+#[test]
+fn test_pack_and_split() {
+	// each of these is 12 bytes, 16 inside a bundle
+	let messages: Vec<OscPacket> = (0..10)
+		.map(|i| OscMessage{addr: format!("/{}", i), args: vec!(OscInt(i))})
+		.collect();
+
+	let bundles = pack(messages.clone(), (5, 6), 16 + 4 * 16).unwrap();
+	assert_eq!(bundles.len(), 3);
+	assert!(bundles.iter().all(|b| encoded_len(b) <= 80));
+
+	let mut unpacked = Vec::new();
+	for b in bundles {
+		match b {
+			OscBundle{time_tag, conts} => {
+				assert_eq!(time_tag, (5, 6));
+				unpacked.extend(conts);
+			},
+			_ => panic!("expected a bundle")
+		}
+	}
+	assert_eq!(unpacked, messages);
+
+	let bundle = OscBundle{time_tag: (5, 6), conts: messages.clone()};
+	assert_eq!(split(bundle.clone(), 1000).unwrap(), vec!(bundle.clone()));
+	assert_eq!(split(bundle, 80).unwrap().len(), 3);
+
+	let big = OscMessage{addr: "/big".to_string(), args: vec!(OscBlob(vec!(0; 100)))};
+	assert!(split(big.clone(), 100).is_err());
+	assert!(pack(vec!(messages[0].clone(), big), IMMEDIATE, 100).is_err());
+}
@@ -41,6 +41,7 @@ struct MidiMessage {
 */
 
 /// Helper macro to check if an OscArg is a given type, produces a bool
+#[allow(unused_macros)]
 macro_rules! arg_is_type{
 	($arg:ident, $targ_var:ident) => (
 		match $arg {
@@ -51,6 +52,7 @@ macro_rules! arg_is_type{
 }
 
 /// Helper macro to unwrap an OscArg as a given type, produces None if the types don't match
+#[allow(unused_macros)]
 macro_rules! unwrap_if{
 	($arg:ident is $kind:ident) => (
 		match $arg {
@@ -111,10 +113,9 @@ pub fn get_args_with_addr(packet: OscPacket, addr_match: &str) -> Option<Vec<Osc
 		},
 		OscBundle{ time_tag: _, conts} => {
 			let mut arg_vec = Vec::new();
-			for subpacket in conts.into_iter() {
-				match get_args_with_addr(subpacket, addr_match) {
-					Some(a) => arg_vec.extend(a.into_iter()),
-					None => ()
+			for subpacket in conts {
+				if let Some(a) = get_args_with_addr(subpacket, addr_match) {
+					arg_vec.extend(a);
 				}
 			}
 			if arg_vec.is_empty() {
@@ -125,6 +126,31 @@ pub fn get_args_with_addr(packet: OscPacket, addr_match: &str) -> Option<Vec<Osc
 	}
 }
 
+/// Keep only the messages whose address satisfies a predicate.  Bundles keep
+/// their time tag and lose the messages that were filtered out; a bundle left
+/// with no contents is removed entirely.  Returns None if nothing is kept.
+pub fn filter_packet<F: Fn(&str) -> bool>(packet: OscPacket, keep: &F) -> Option<OscPacket> {
+	match packet {
+		OscMessage{addr, args} => {
+			if keep(&addr) {
+				Some(OscMessage{addr, args})
+			}
+			else {
+				None
+			}
+		},
+		OscBundle{time_tag, conts} => {
+			let conts: Vec<OscPacket> = conts.into_iter()
+				.filter_map(|p| filter_packet(p, keep))
+				.collect();
+			if conts.is_empty() {
+				return None;
+			}
+			Some(OscBundle{time_tag, conts})
+		}
+	}
+}
+
 #[test]
 fn test_packet_has_addr(){
 	let p1 = OscMessage{addr: "hello/test/address".to_string(), args: vec!(OscInt(0))};
@@ -173,3 +199,26 @@ fn test_get_args_with_addr(){
 
 	assert_eq!(get_args_with_addr(p3, "double/addr/test"), Some(vec!(OscFloat(3.0), OscFloat(1.5), OscStr("payload".to_string()))));
 }
+
+#[test]
+fn test_filter_packet(){
+	let packet = OscBundle{
+		time_tag: (0,1),
+		conts: vec!(
+			OscMessage{addr: "/keep".to_string(), args: vec!(OscInt(1))},
+			OscMessage{addr: "/drop".to_string(), args: vec!(OscInt(2))},
+			OscBundle{
+				time_tag: (1,2),
+				conts: vec!(OscMessage{addr: "/drop".to_string(), args: vec!()})
+			}
+		)
+	};
+
+	let keep_only = |a: &str| a == "/keep";
+	assert_eq!(filter_packet(packet.clone(), &keep_only), Some(OscBundle{
+		time_tag: (0,1),
+		conts: vec!(OscMessage{addr: "/keep".to_string(), args: vec!(OscInt(1))})
+	}));
+	assert_eq!(filter_packet(packet.clone(), &|_: &str| true), Some(packet.clone()));
+	assert_eq!(filter_packet(packet, &|_: &str| false), None);
+}
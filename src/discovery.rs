@@ -0,0 +1,764 @@
+//! Module for advertising and discovering OSC services with multicast DNS
+//! service discovery (mDNS/DNS-SD).  Requires the `discovery` feature.
+//!
+//! A ServiceAdvertiser answers mDNS queries for the services it was given, so
+//! that tablets and consoles browsing for `_osc._udp` or `_oscjson._tcp` can
+//! find them.  browse() sends a query and collects the answers, giving the
+//! address and port to hand to an OscSender.  Both speak the protocol directly
+//! over multicast UDP and don't need a system mDNS daemon.
+//!
+//! Queries are sent from an ephemeral port, so responders answer them directly
+//! by unicast (a "legacy unicast" query in RFC 6762 terms) and browsing can run
+//! alongside a system daemon that owns port 5353.  The multicast group, port
+//! and interface are configurable so tests can run on loopback.
+
+extern crate socket2;
+
+use std::collections::HashSet;
+use std::net::{UdpSocket, Ipv4Addr, IpAddr, SocketAddr, SocketAddrV4};
+use std::io::{Error, Result};
+use std::io::ErrorKind::{InvalidData, WouldBlock, TimedOut};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use self::socket2::{Socket, Domain, Type, Protocol};
+
+/// Service type for OSC over UDP.
+pub const OSC_UDP: &str = "_osc._udp.local";
+/// Service type for OSCQuery servers.
+pub const OSCJSON_TCP: &str = "_oscjson._tcp.local";
+
+const SERVICES_META_QUERY: &str = "_services._dns-sd._udp.local";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+// set on records only this host answers for, telling caches to replace old data
+const CACHE_FLUSH: u16 = 0x8000;
+// in a question, asks for a unicast reply; in either, the class is in the low bits
+const CLASS_MASK: u16 = 0x7fff;
+
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+// RFC 6762 section 6.7
+const LEGACY_UNICAST_TTL: u32 = 10;
+
+const MDNS_BUFFER_SIZE: usize = 9000;
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// Where to send and listen for mDNS traffic.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct MdnsConfig {
+	/// Multicast group, normally 224.0.0.251.
+	pub group: Ipv4Addr,
+	/// Port, normally 5353.
+	pub port: u16,
+	/// Local interface to send and listen on; unspecified lets the OS choose.
+	pub interface: Ipv4Addr
+}
+
+impl Default for MdnsConfig {
+	fn default() -> MdnsConfig {
+		MdnsConfig{group: Ipv4Addr::new(224, 0, 0, 251), port: 5353, interface: Ipv4Addr::UNSPECIFIED}
+	}
+}
+
+/// A service instance, either one to advertise or one found by browsing.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Service {
+	/// Human-readable instance name, e.g. "FOH Console".
+	pub instance: String,
+	/// Service type, e.g. OSC_UDP.
+	pub service_type: String,
+	/// Host name the service runs on, e.g. "foh-console.local".
+	pub host: String,
+	pub port: u16,
+	/// Addresses of the host.  When advertising, leave this empty to publish
+	/// the address of the interface the advertiser sends from.
+	pub addrs: Vec<Ipv4Addr>,
+	/// TXT record strings, conventionally "key=value".
+	pub txt: Vec<String>
+}
+
+impl Service {
+
+	/// Constructs a service with a host name derived from the instance name and
+	/// no addresses or TXT strings.
+	pub fn new(instance: &str, service_type: &str, port: u16) -> Service {
+		let host: String = instance.chars()
+			.map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '-' })
+			.collect();
+		Service{
+			instance: instance.to_string(),
+			service_type: service_type.to_string(),
+			host: format!("{}.local", host),
+			port,
+			addrs: Vec::new(),
+			txt: Vec::new()
+		}
+	}
+
+	/// The socket addresses the service can be reached at, for use as the
+	/// destination of an OscSender.
+	pub fn socket_addrs(&self) -> Vec<SocketAddr> {
+		self.addrs.iter().map(|a| SocketAddr::V4(SocketAddrV4::new(*a, self.port))).collect()
+	}
+
+	fn instance_name(&self) -> Name {
+		let mut name = vec!(self.instance.clone());
+		name.extend(split_name(&self.service_type));
+		name
+	}
+}
+
+// a domain name as a list of labels, so instance names may contain dots
+type Name = Vec<String>;
+
+fn split_name(s: &str) -> Name {
+	s.split('.').filter(|l| !l.is_empty()).map(String::from).collect()
+}
+
+fn names_equal(a: &[String], b: &[String]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.eq_ignore_ascii_case(y))
+}
+
+#[derive(Debug,Clone,PartialEq)]
+enum RData {
+	A(Ipv4Addr),
+	Ptr(Name),
+	Srv{port: u16, target: Name},
+	Txt(Vec<String>),
+	Other
+}
+
+#[derive(Debug,Clone,PartialEq)]
+struct Record {
+	name: Name,
+	class: u16,
+	ttl: u32,
+	data: RData
+}
+
+#[derive(Debug,Clone,PartialEq)]
+struct Question {
+	name: Name,
+	qtype: u16
+}
+
+#[derive(Debug,Clone,PartialEq,Default)]
+struct DnsMessage {
+	id: u16,
+	response: bool,
+	questions: Vec<Question>,
+	answers: Vec<Record>,
+	additionals: Vec<Record>
+}
+
+fn dns_err(msg: &str) -> Error {
+	Error::new(InvalidData, msg)
+}
+
+fn write_u16(buf: &mut Vec<u8>, v: u16) {
+	buf.extend_from_slice(&v.to_be_bytes());
+}
+
+fn write_name(buf: &mut Vec<u8>, name: &[String]) {
+	for label in name {
+		let bytes = &label.as_bytes()[..label.len().min(63)];
+		buf.push(bytes.len() as u8);
+		buf.extend_from_slice(bytes);
+	}
+	buf.push(0);
+}
+
+fn write_record(buf: &mut Vec<u8>, record: &Record) {
+	let rtype = match record.data {
+		RData::A(_) => TYPE_A,
+		RData::Ptr(_) => TYPE_PTR,
+		RData::Srv{..} => TYPE_SRV,
+		RData::Txt(_) => TYPE_TXT,
+		RData::Other => return
+	};
+	write_name(buf, &record.name);
+	write_u16(buf, rtype);
+	write_u16(buf, record.class);
+	buf.extend_from_slice(&record.ttl.to_be_bytes());
+
+	let mut data = Vec::new();
+	match record.data {
+		RData::A(addr) => data.extend_from_slice(&addr.octets()),
+		RData::Ptr(ref name) => write_name(&mut data, name),
+		RData::Srv{port, ref target} => {
+			write_u16(&mut data, 0); // priority
+			write_u16(&mut data, 0); // weight
+			write_u16(&mut data, port);
+			write_name(&mut data, target);
+		},
+		RData::Txt(ref strings) => {
+			// an empty TXT record still holds one empty string
+			if strings.is_empty() { data.push(0); }
+			for s in strings {
+				let bytes = &s.as_bytes()[..s.len().min(255)];
+				data.push(bytes.len() as u8);
+				data.extend_from_slice(bytes);
+			}
+		},
+		RData::Other => ()
+	}
+	write_u16(buf, data.len() as u16);
+	buf.extend_from_slice(&data);
+}
+
+impl DnsMessage {
+
+	fn to_bytes(&self) -> Vec<u8> {
+		let mut buf = Vec::new();
+		write_u16(&mut buf, self.id);
+		// a response is authoritative
+		write_u16(&mut buf, if self.response { 0x8400 } else { 0 });
+		write_u16(&mut buf, self.questions.len() as u16);
+		write_u16(&mut buf, self.answers.len() as u16);
+		write_u16(&mut buf, 0);
+		write_u16(&mut buf, self.additionals.len() as u16);
+
+		for q in &self.questions {
+			write_name(&mut buf, &q.name);
+			write_u16(&mut buf, q.qtype);
+			write_u16(&mut buf, CLASS_IN);
+		}
+		for r in self.answers.iter().chain(self.additionals.iter()) {
+			write_record(&mut buf, r);
+		}
+		buf
+	}
+
+	fn from_bytes(buf: &[u8]) -> Result<DnsMessage> {
+		let mut reader = DnsReader{buf, pos: 0};
+		let id = reader.u16()?;
+		let flags = reader.u16()?;
+		let qd = reader.u16()?;
+		let an = reader.u16()?;
+		let ns = reader.u16()?;
+		let ar = reader.u16()?;
+
+		let mut msg = DnsMessage{id, response: flags & 0x8000 != 0, ..Default::default()};
+		for _ in 0..qd {
+			let name = reader.name()?;
+			let qtype = reader.u16()?;
+			reader.u16()?;
+			msg.questions.push(Question{name, qtype});
+		}
+		for i in 0..(an as usize + ns as usize + ar as usize) {
+			let record = reader.record()?;
+			if i < an as usize { msg.answers.push(record); } else { msg.additionals.push(record); }
+		}
+		Ok(msg)
+	}
+}
+
+struct DnsReader<'a> {
+	buf: &'a [u8],
+	pos: usize
+}
+
+impl<'a> DnsReader<'a> {
+
+	fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+		if self.pos + n > self.buf.len() {
+			return Err(dns_err("Truncated DNS message."));
+		}
+		let bytes = &self.buf[self.pos..self.pos + n];
+		self.pos += n;
+		Ok(bytes)
+	}
+
+	fn u16(&mut self) -> Result<u16> {
+		let b = self.bytes(2)?;
+		Ok(u16::from_be_bytes([b[0], b[1]]))
+	}
+
+	fn u32(&mut self) -> Result<u32> {
+		let b = self.bytes(4)?;
+		Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+	}
+
+	// read a possibly compressed name
+	fn name(&mut self) -> Result<Name> {
+		let mut name = Vec::new();
+		let mut pos = self.pos;
+		let mut jumped = false;
+		// each jump must go backwards, so this bounds the loop
+		let mut jumps = 0;
+		loop {
+			let len = *self.buf.get(pos).ok_or_else(|| dns_err("Truncated DNS name."))? as usize;
+			if len == 0 {
+				pos += 1;
+				break;
+			}
+			else if len & 0xc0 == 0xc0 {
+				let low = *self.buf.get(pos + 1).ok_or_else(|| dns_err("Truncated DNS name."))? as usize;
+				let target = ((len & 0x3f) << 8) | low;
+				if !jumped { self.pos = pos + 2; }
+				jumped = true;
+				jumps += 1;
+				if target >= pos || jumps > 128 { return Err(dns_err("Bad DNS name pointer.")); }
+				pos = target;
+			}
+			else {
+				let label = self.buf.get(pos + 1..pos + 1 + len).ok_or_else(|| dns_err("Truncated DNS name."))?;
+				name.push(String::from_utf8_lossy(label).into_owned());
+				pos += 1 + len;
+			}
+		}
+		if !jumped { self.pos = pos; }
+		Ok(name)
+	}
+
+	fn record(&mut self) -> Result<Record> {
+		let name = self.name()?;
+		let rtype = self.u16()?;
+		let class = self.u16()?;
+		let ttl = self.u32()?;
+		let len = self.u16()? as usize;
+		let end = self.pos + len;
+		if end > self.buf.len() {
+			return Err(dns_err("Truncated DNS record."));
+		}
+
+		let data = match rtype {
+			TYPE_A if len == 4 => {
+				let b = self.bytes(4)?;
+				RData::A(Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+			},
+			TYPE_PTR => RData::Ptr(self.name()?),
+			TYPE_SRV => {
+				self.u16()?;
+				self.u16()?;
+				let port = self.u16()?;
+				RData::Srv{port, target: self.name()?}
+			},
+			TYPE_TXT => {
+				let mut strings = Vec::new();
+				while self.pos < end {
+					let n = self.bytes(1)?[0] as usize;
+					let s = self.bytes(n)?;
+					if n > 0 { strings.push(String::from_utf8_lossy(s).into_owned()); }
+				}
+				RData::Txt(strings)
+			},
+			_ => RData::Other
+		};
+		self.pos = end;
+		Ok(Record{name, class, ttl, data})
+	}
+}
+
+// build the answers and additional records this host gives for one question
+fn answer_question(question: &Question, services: &[Service], ttl: &dyn Fn(u32) -> u32) -> (Vec<Record>, Vec<Record>) {
+	let mut answers = Vec::new();
+	let mut additionals = Vec::new();
+	let wants = |t: u16| question.qtype == t || question.qtype == TYPE_ANY;
+
+	let host_records = |s: &Service| -> Vec<Record> {
+		s.addrs.iter().map(|a| Record{
+			name: split_name(&s.host), class: CLASS_IN | CACHE_FLUSH, ttl: ttl(HOST_TTL), data: RData::A(*a)
+		}).collect()
+	};
+	let srv_txt = |s: &Service| -> Vec<Record> {
+		vec!(
+			Record{name: s.instance_name(), class: CLASS_IN | CACHE_FLUSH, ttl: ttl(HOST_TTL),
+				data: RData::Srv{port: s.port, target: split_name(&s.host)}},
+			Record{name: s.instance_name(), class: CLASS_IN | CACHE_FLUSH, ttl: ttl(SERVICE_TTL),
+				data: RData::Txt(s.txt.clone())}
+		)
+	};
+
+	if names_equal(&question.name, &split_name(SERVICES_META_QUERY)) && wants(TYPE_PTR) {
+		let mut types: Vec<&str> = services.iter().map(|s| s.service_type.as_str()).collect();
+		types.sort();
+		types.dedup();
+		for t in types {
+			answers.push(Record{name: question.name.clone(), class: CLASS_IN, ttl: ttl(SERVICE_TTL),
+				data: RData::Ptr(split_name(t))});
+		}
+	}
+
+	for s in services {
+		if names_equal(&question.name, &split_name(&s.service_type)) && wants(TYPE_PTR) {
+			answers.push(Record{name: question.name.clone(), class: CLASS_IN, ttl: ttl(SERVICE_TTL),
+				data: RData::Ptr(s.instance_name())});
+			additionals.extend(srv_txt(s));
+			additionals.extend(host_records(s));
+		}
+		if names_equal(&question.name, &s.instance_name()) && (wants(TYPE_SRV) || wants(TYPE_TXT)) {
+			answers.extend(srv_txt(s).into_iter().filter(|r| match r.data {
+				RData::Srv{..} => wants(TYPE_SRV),
+				_ => wants(TYPE_TXT)
+			}));
+			additionals.extend(host_records(s));
+		}
+		if names_equal(&question.name, &split_name(&s.host)) && wants(TYPE_A) {
+			answers.extend(host_records(s));
+		}
+	}
+
+	// services sharing a host give the same A records
+	let mut unique: Vec<Record> = Vec::new();
+	answers.retain(|r| if unique.contains(r) { false } else { unique.push(r.clone()); true });
+	additionals.retain(|r| if unique.contains(r) { false } else { unique.push(r.clone()); true });
+	(answers, additionals)
+}
+
+// the address of the interface mDNS traffic goes out on; connecting a UDP
+// socket picks the route without sending anything
+fn interface_addr(config: &MdnsConfig) -> Result<Ipv4Addr> {
+	if !config.interface.is_unspecified() {
+		return Ok(config.interface);
+	}
+	let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+	socket.connect(SocketAddrV4::new(config.group, config.port))?;
+	match socket.local_addr()?.ip() {
+		IpAddr::V4(a) if !a.is_unspecified() => Ok(a),
+		_ => Err(Error::new(InvalidData, "No interface address for mDNS."))
+	}
+}
+
+fn multicast_socket(config: &MdnsConfig, bind_port: u16) -> Result<UdpSocket> {
+	let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+	socket.set_reuse_address(true)?;
+	#[cfg(unix)]
+	socket.set_reuse_port(true)?;
+	socket.bind(&SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, bind_port)).into())?;
+	socket.set_multicast_if_v4(&config.interface)?;
+	socket.set_multicast_loop_v4(true)?;
+	socket.set_multicast_ttl_v4(255)?;
+	Ok(socket.into())
+}
+
+/// Answers mDNS queries for a set of services from a background thread.  The
+/// services are announced when advertising starts and withdrawn when the
+/// advertiser is dropped.
+pub struct ServiceAdvertiser {
+	socket: UdpSocket,
+	config: MdnsConfig,
+	services: Arc<Vec<Service>>,
+	stop: Arc<AtomicBool>,
+	thread: Option<JoinHandle<()>>
+}
+
+impl ServiceAdvertiser {
+
+	/// Join the multicast group and start answering queries.  If config.port
+	/// is 0 an ephemeral port is used; see local_port.  Services without
+	/// addresses are given the address of the interface the advertiser sends
+	/// from.
+	pub fn start(config: MdnsConfig, mut services: Vec<Service>) -> Result<ServiceAdvertiser> {
+		let socket = multicast_socket(&config, config.port)?;
+		socket.join_multicast_v4(&config.group, &config.interface)?;
+		socket.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL_MS)))?;
+
+		let config = MdnsConfig{port: socket.local_addr()?.port(), ..config};
+		if services.iter().any(|s| s.addrs.is_empty()) {
+			let addr = interface_addr(&config)?;
+			for s in services.iter_mut().filter(|s| s.addrs.is_empty()) {
+				s.addrs.push(addr);
+			}
+		}
+		let services = Arc::new(services);
+		let stop = Arc::new(AtomicBool::new(false));
+
+		let mut advertiser = ServiceAdvertiser{
+			socket: socket.try_clone()?,
+			config,
+			services: services.clone(),
+			stop: stop.clone(),
+			thread: None
+		};
+		let _ = advertiser.announce(1);
+
+		advertiser.thread = Some(thread::spawn(move || {
+			let mut buf = vec![0u8; MDNS_BUFFER_SIZE];
+			while !stop.load(Ordering::SeqCst) {
+				match socket.recv_from(&mut buf) {
+					Ok((n, source)) => {
+						if let Ok(query) = DnsMessage::from_bytes(&buf[..n]) {
+							let _ = respond(&socket, &config, &services, &query, source);
+						}
+					},
+					Err(ref e) if e.kind() == WouldBlock || e.kind() == TimedOut => (),
+					Err(_) => break
+				}
+			}
+		}));
+
+		Ok(advertiser)
+	}
+
+	/// The port the advertiser is listening on.
+	pub fn local_port(&self) -> u16 {
+		self.config.port
+	}
+
+	// multicast every record for every service with the given TTL multiplier;
+	// 0 withdraws them
+	fn announce(&self, ttl_scale: u32) -> Result<()> {
+		let msg = announcement(&self.services, ttl_scale);
+		if msg.answers.is_empty() {
+			return Ok(());
+		}
+		self.socket.send_to(&msg.to_bytes(), SocketAddrV4::new(self.config.group, self.config.port)).map(|_| ())
+	}
+}
+
+// every record for every service, asking once for each service type so
+// services sharing a type aren't announced twice
+fn announcement(services: &[Service], ttl_scale: u32) -> DnsMessage {
+	let mut types: Vec<&str> = services.iter().map(|s| s.service_type.as_str()).collect();
+	types.sort();
+	types.dedup();
+
+	let mut msg = DnsMessage{response: true, ..Default::default()};
+	for t in types {
+		let question = Question{name: split_name(t), qtype: TYPE_ANY};
+		let (answers, additionals) = answer_question(&question, services, &|t| t * ttl_scale);
+		for r in answers.into_iter().chain(additionals) {
+			if !msg.answers.contains(&r) { msg.answers.push(r); }
+		}
+	}
+	msg
+}
+
+impl Drop for ServiceAdvertiser {
+	fn drop(&mut self) {
+		self.stop.store(true, Ordering::SeqCst);
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+		let _ = self.announce(0);
+	}
+}
+
+fn respond(socket: &UdpSocket, config: &MdnsConfig, services: &[Service], query: &DnsMessage, source: SocketAddr) -> Result<()> {
+	if query.response {
+		return Ok(());
+	}
+
+	// a query from another port can't hear multicast replies; answer it directly
+	let legacy = source.port() != config.port;
+	let ttl = |t: u32| if legacy { t.min(LEGACY_UNICAST_TTL) } else { t };
+
+	let mut reply = DnsMessage{response: true, ..Default::default()};
+	for q in &query.questions {
+		let (answers, additionals) = answer_question(q, services, &ttl);
+		if legacy && !answers.is_empty() {
+			reply.questions.push(q.clone());
+		}
+		reply.answers.extend(answers);
+		reply.additionals.extend(additionals);
+	}
+	if reply.answers.is_empty() {
+		return Ok(());
+	}
+
+	if legacy {
+		reply.id = query.id;
+		// legacy resolvers don't understand the cache flush bit
+		for r in reply.answers.iter_mut().chain(reply.additionals.iter_mut()) {
+			r.class &= CLASS_MASK;
+		}
+		socket.send_to(&reply.to_bytes(), source).map(|_| ())
+	}
+	else {
+		socket.send_to(&reply.to_bytes(), SocketAddrV4::new(config.group, config.port)).map(|_| ())
+	}
+}
+
+/// Send a query for a service type and collect the services that answer
+/// within the timeout.  A service that doesn't advertise any addresses is given
+/// the address its answer came from.
+pub fn browse(config: &MdnsConfig, service_type: &str, timeout: Duration) -> Result<Vec<Service>> {
+	let socket = multicast_socket(config, 0)?;
+	let query = DnsMessage{
+		id: (SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos() & 0xffff) as u16,
+		questions: vec!(Question{name: split_name(service_type), qtype: TYPE_PTR}),
+		..Default::default()
+	};
+	socket.send_to(&query.to_bytes(), SocketAddrV4::new(config.group, config.port))?;
+
+	let mut records = Vec::new();
+	let deadline = Instant::now() + timeout;
+	let mut buf = vec![0u8; MDNS_BUFFER_SIZE];
+	loop {
+		let now = Instant::now();
+		if now >= deadline { break; }
+		socket.set_read_timeout(Some(deadline - now))?;
+		match socket.recv_from(&mut buf) {
+			Ok((n, source)) => {
+				if let Ok(msg) = DnsMessage::from_bytes(&buf[..n]) {
+					if msg.response {
+						for r in msg.answers.into_iter().chain(msg.additionals) {
+							records.push((r, source.ip()));
+						}
+					}
+				}
+			},
+			Err(ref e) if e.kind() == WouldBlock || e.kind() == TimedOut => break,
+			Err(e) => return Err(e)
+		}
+	}
+
+	Ok(collect_services(service_type, &records))
+}
+
+// piece services together from PTR, SRV, TXT and A records
+fn collect_services(service_type: &str, records: &[(Record, IpAddr)]) -> Vec<Service> {
+	let type_name = split_name(service_type);
+	let mut services: Vec<Service> = Vec::new();
+	let mut seen: HashSet<String> = HashSet::new();
+
+	for (ptr, _) in records {
+		let instance_name = match ptr.data {
+			RData::Ptr(ref target) if names_equal(&ptr.name, &type_name) && ptr.ttl > 0 => target,
+			_ => continue
+		};
+		let key = instance_name.join(".").to_lowercase();
+		if seen.contains(&key) || instance_name.len() <= type_name.len() { continue; }
+
+		let srv = records.iter().filter(|(r, _)| names_equal(&r.name, instance_name)).find_map(|(r, ip)| match r.data {
+			RData::Srv{port, ref target} => Some((port, target.clone(), *ip)),
+			_ => None
+		});
+		let (port, host, source) = match srv {
+			Some(s) => s,
+			None => continue
+		};
+		let txt = records.iter().filter(|(r, _)| names_equal(&r.name, instance_name)).find_map(|(r, _)| match r.data {
+			RData::Txt(ref strings) => Some(strings.clone()),
+			_ => None
+		}).unwrap_or_default();
+
+		let mut addrs: Vec<Ipv4Addr> = Vec::new();
+		for (r, _) in records {
+			if let RData::A(a) = r.data {
+				if names_equal(&r.name, &host) && !addrs.contains(&a) { addrs.push(a); }
+			}
+		}
+		if addrs.is_empty() {
+			if let IpAddr::V4(a) = source { addrs.push(a); }
+		}
+
+		seen.insert(key);
+		services.push(Service{
+			instance: instance_name[0].clone(),
+			service_type: service_type.to_string(),
+			host: host.join("."),
+			port,
+			addrs,
+			txt
+		});
+	}
+	services
+}
+
+#[test]
+fn test_dns_message_round_trip() {
+	let msg = DnsMessage{
+		id: 7,
+		response: true,
+		questions: vec!(Question{name: split_name(OSC_UDP), qtype: TYPE_PTR}),
+		answers: vec!(Record{name: split_name(OSC_UDP), class: CLASS_IN, ttl: 10,
+			data: RData::Ptr(vec!("FOH Console.v2".to_string(), "_osc".to_string(), "_udp".to_string(), "local".to_string()))}),
+		additionals: vec!(
+			Record{name: split_name("a.local"), class: CLASS_IN | CACHE_FLUSH, ttl: 120, data: RData::A(Ipv4Addr::new(10, 0, 0, 2))},
+			Record{name: split_name("x.local"), class: CLASS_IN, ttl: 120, data: RData::Srv{port: 9000, target: split_name("a.local")}},
+			Record{name: split_name("x.local"), class: CLASS_IN, ttl: 120, data: RData::Txt(vec!("k=v".to_string()))})
+	};
+	assert_eq!(DnsMessage::from_bytes(&msg.to_bytes()).unwrap(), msg);
+	assert!(DnsMessage::from_bytes(&msg.to_bytes()[..20]).is_err());
+}
+
+#[test]
+fn test_dns_name_compression() {
+	// "local" at offset 12, then "_osc" pointing back at it
+	let mut buf = vec!(0u8, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0);
+	buf.extend_from_slice(&[5, b'l', b'o', b'c', b'a', b'l', 0, 0, 1, 0, 1]);
+	buf.extend_from_slice(&[4, b'_', b'o', b's', b'c', 0xc0, 12, 0, 12, 0, 1]);
+	let msg = DnsMessage::from_bytes(&buf).unwrap();
+	assert_eq!(msg.questions[1].name, split_name("_osc.local"));
+
+	// a pointer to itself must not loop forever
+	let mut bad = vec!(0u8, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0);
+	bad.extend_from_slice(&[0xc0, 12, 0, 12, 0, 1]);
+	assert!(DnsMessage::from_bytes(&bad).is_err());
+}
+
+#[test]
+fn test_advertise_and_browse() {
+	let config = MdnsConfig{port: 0, interface: Ipv4Addr::LOCALHOST, ..Default::default()};
+
+	let mut console = Service::new("FOH Console", OSC_UDP, 9000);
+	console.txt.push("txtvers=1".to_string());
+	let mut query = Service::new("FOH Console", OSCJSON_TCP, 8080);
+	query.addrs.push(Ipv4Addr::new(127, 0, 0, 2));
+
+	let advertiser = ServiceAdvertiser::start(config, vec!(console, query)).unwrap();
+	let config = MdnsConfig{port: advertiser.local_port(), ..config};
+
+	let found = browse(&config, OSC_UDP, Duration::from_millis(500)).unwrap();
+	assert_eq!(found.len(), 1);
+	assert_eq!(found[0].instance, "FOH Console");
+	assert_eq!(found[0].host, "foh-console.local");
+	assert_eq!(found[0].txt, vec!("txtvers=1".to_string()));
+	assert_eq!(found[0].socket_addrs(), vec!("127.0.0.1:9000".parse().unwrap()));
+
+	let found = browse(&config, OSCJSON_TCP, Duration::from_millis(500)).unwrap();
+	assert_eq!(found.len(), 1);
+	assert_eq!(found[0].socket_addrs(), vec!("127.0.0.2:8080".parse().unwrap()));
+
+	assert!(browse(&config, "_other._udp.local", Duration::from_millis(200)).unwrap().is_empty());
+}
+
+#[test]
+fn test_announcement() {
+	let mut a = Service::new("Desk A", OSC_UDP, 9000);
+	a.host = "desk.local".to_string();
+	a.addrs.push(Ipv4Addr::new(10, 0, 0, 2));
+	let mut b = Service::new("Desk B", OSC_UDP, 9001);
+	b.host = "desk.local".to_string();
+	b.addrs.push(Ipv4Addr::new(10, 0, 0, 2));
+
+	let msg = announcement(&[a, b], 1);
+	let count = |f: &dyn Fn(&RData) -> bool| msg.answers.iter().filter(|r| f(&r.data)).count();
+	assert_eq!(count(&|d| matches!(*d, RData::Ptr(_))), 2);
+	assert_eq!(count(&|d| matches!(*d, RData::Srv{..})), 2);
+	assert_eq!(count(&|d| matches!(*d, RData::A(_))), 1);
+	assert!(announcement(&[], 1).answers.is_empty());
+}
+
+#[test]
+fn test_advertiser_answers_host_address() {
+	let config = MdnsConfig{port: 0, interface: Ipv4Addr::LOCALHOST, ..Default::default()};
+	let advertiser = ServiceAdvertiser::start(config, vec!(Service::new("FOH Console", OSC_UDP, 9000))).unwrap();
+
+	let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+	socket.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+	let query = DnsMessage{
+		id: 9,
+		questions: vec!(Question{name: split_name("foh-console.local"), qtype: TYPE_A}),
+		..Default::default()
+	};
+	socket.send_to(&query.to_bytes(), SocketAddrV4::new(Ipv4Addr::LOCALHOST, advertiser.local_port())).unwrap();
+
+	let mut buf = vec![0u8; MDNS_BUFFER_SIZE];
+	let n = socket.recv(&mut buf).unwrap();
+	let reply = DnsMessage::from_bytes(&buf[..n]).unwrap();
+	assert_eq!(reply.id, 9);
+	assert_eq!(reply.answers.len(), 1);
+	assert_eq!(reply.answers[0].data, RData::A(Ipv4Addr::LOCALHOST));
+}
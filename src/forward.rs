@@ -0,0 +1,338 @@
+//! Module for forwarding OSC from one port to several destinations, rewriting
+//! it along the way.
+//!
+//! What happens to each message is decided by a RuleSet.  Rules are tried in
+//! order and every rule whose pattern matches the message's address is applied,
+//! so a rewrite can be followed by a rule that matches the new address.  A
+//! denied message is not looked at by any later rule.
+//!
+//! Rule sets can be written in a small config format, one rule per line:
+//!
+//! ```text
+//! # anything after a # at the start of a line is a comment
+//! deny /ping
+//! rewrite /1/fader* /mixer/ch*/level
+//! scale /mixer/*/level 0 127 0 1
+//! ```
+//!
+//! `rewrite` replaces the address.  Each `*` in the new address is replaced by
+//! the text matched by the next wildcard, set or alternative in the pattern, so
+//! the rule above turns `/1/fader3` into `/mixer/ch3/level`; a rule with more
+//! `*` in the new address than the pattern has captures is rejected.  `scale` maps
+//! every int or float argument linearly from the first range to the second,
+//! producing floats; other arguments are left alone.  `deny` discards the
+//! message.
+
+use std::fs::File;
+use std::path::Path;
+use std::net::{UdpSocket, SocketAddr, ToSocketAddrs};
+use std::io::{Error, Result, Read};
+use std::io::ErrorKind::InvalidInput;
+use std::time::Duration;
+
+use data::*;
+use data::OscPacket::*;
+use data::OscArg::*;
+use pattern::{addr_matches, addr_captures, capture_count};
+use receiver::OscReceiver;
+use sender::OscSender;
+use transport::Transport;
+
+/// What to do with a message whose address matches a rule.
+#[derive(Debug,Clone,PartialEq)]
+pub enum Action {
+	/// Discard the message.
+	Deny,
+	/// Replace the address, filling each `*` from the pattern's captures.
+	Rewrite(String),
+	/// Map numeric arguments linearly from one range onto another.
+	Scale{from: (f32, f32), to: (f32, f32)}
+}
+
+/// A single forwarding rule: an OSC address pattern and the action to apply
+/// to messages matching it.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Rule {
+	pub pattern: String,
+	pub action: Action
+}
+
+/// An ordered list of rules.
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct RuleSet {
+	pub rules: Vec<Rule>
+}
+
+impl RuleSet {
+
+	/// Constructs an empty RuleSet, which forwards everything unchanged.
+	pub fn new() -> RuleSet {
+		RuleSet{rules: Vec::new()}
+	}
+
+	/// Add a rule denying messages that match the pattern.
+	pub fn deny(mut self, pattern: &str) -> RuleSet {
+		self.rules.push(Rule{pattern: pattern.to_string(), action: Action::Deny});
+		self
+	}
+
+	/// Add a rule rewriting the address of messages that match the pattern.
+	/// Returns an InvalidInput error if the target has more `*` than the
+	/// pattern has captures.
+	pub fn rewrite(mut self, pattern: &str, target: &str) -> Result<RuleSet> {
+		if !fills_template(pattern, target) {
+			return Err(Error::new(InvalidInput, format!("{} has more * than {} has captures.", target, pattern)));
+		}
+		self.rules.push(Rule{pattern: pattern.to_string(), action: Action::Rewrite(target.to_string())});
+		Ok(self)
+	}
+
+	/// Add a rule rescaling the arguments of messages that match the pattern.
+	/// Returns an InvalidInput error if either range has a bound that isn't
+	/// finite, or the first range is empty.
+	pub fn scale(mut self, pattern: &str, from: (f32, f32), to: (f32, f32)) -> Result<RuleSet> {
+		if from.0 == from.1 || ![from.0, from.1, to.0, to.1].iter().all(|v| v.is_finite()) {
+			return Err(Error::new(InvalidInput, format!("Can't scale from {:?} to {:?}.", from, to)));
+		}
+		self.rules.push(Rule{pattern: pattern.to_string(), action: Action::Scale{from, to}});
+		Ok(self)
+	}
+
+	/// Parse a rule set from the config format described in the module
+	/// documentation.
+	pub fn parse(s: &str) -> Result<RuleSet> {
+		let mut rules = RuleSet::new();
+		for (n, line) in s.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') { continue; }
+
+			let err = || Error::new(InvalidInput, format!("Invalid rule on line {}: {}", n + 1, line));
+			let words: Vec<&str> = line.split_whitespace().collect();
+			rules = match words[..] {
+				["deny", pattern] => rules.deny(pattern),
+				["rewrite", pattern, target] => rules.rewrite(pattern, target).map_err(|_| err())?,
+				["scale", pattern, a, b, c, d] => {
+					let nums = [a, b, c, d].iter()
+						.map(|w| w.parse::<f32>().ok())
+						.collect::<Option<Vec<f32>>>()
+						.ok_or_else(err)?;
+					rules.scale(pattern, (nums[0], nums[1]), (nums[2], nums[3])).map_err(|_| err())?
+				},
+				_ => return Err(err())
+			};
+		}
+		Ok(rules)
+	}
+
+	/// Load a rule set from a config file.
+	pub fn load<P: AsRef<Path>>(path: P) -> Result<RuleSet> {
+		let mut text = String::new();
+		File::open(path)?.read_to_string(&mut text)?;
+		RuleSet::parse(&text)
+	}
+
+	/// Apply the rules to every message in a packet.  Denied messages are
+	/// removed from their bundles and empty bundles are removed; returns None
+	/// if nothing is left.
+	pub fn apply(&self, packet: OscPacket) -> Option<OscPacket> {
+		match packet {
+			OscMessage{addr, args} => self.apply_message(addr, args),
+			OscBundle{time_tag, conts} => {
+				let conts: Vec<OscPacket> = conts.into_iter().filter_map(|p| self.apply(p)).collect();
+				if conts.is_empty() {
+					return None;
+				}
+				Some(OscBundle{time_tag, conts})
+			}
+		}
+	}
+
+	fn apply_message(&self, mut addr: String, mut args: Vec<OscArg>) -> Option<OscPacket> {
+		for rule in &self.rules {
+			match rule.action {
+				Action::Deny => {
+					if addr_matches(&rule.pattern, &addr) { return None; }
+				},
+				Action::Rewrite(ref target) => {
+					if let Some(captures) = addr_captures(&rule.pattern, &addr) {
+						addr = fill_template(target, &captures);
+					}
+				},
+				Action::Scale{from, to} => {
+					if addr_matches(&rule.pattern, &addr) {
+						args = args.into_iter().map(|a| scale_arg(a, from, to)).collect();
+					}
+				}
+			}
+		}
+		Some(OscMessage{addr, args})
+	}
+}
+
+// check the pattern captures enough for every * in the template
+fn fills_template(pattern: &str, template: &str) -> bool {
+	template.matches('*').count() <= capture_count(pattern)
+}
+
+// replace each * in the template with the next capture
+fn fill_template(template: &str, captures: &[String]) -> String {
+	let mut captures = captures.iter();
+	let mut result = String::new();
+	for c in template.chars() {
+		if c == '*' {
+			result.push_str(captures.next().map_or("", |cap| cap.as_str()));
+		}
+		else {
+			result.push(c);
+		}
+	}
+	result
+}
+
+fn scale_arg(arg: OscArg, from: (f32, f32), to: (f32, f32)) -> OscArg {
+	let v = match arg {
+		OscInt(v) => v as f32,
+		OscFloat(v) => v,
+		other => return other
+	};
+	OscFloat(to.0 + (v - from.0) * (to.1 - to.0) / (from.1 - from.0))
+}
+
+/// Receives OSC on one socket, applies a RuleSet and sends the result to every
+/// destination.  Uses UDP unless the receiver and senders use another
+/// Transport.
+pub struct OscForwarder<T: ToSocketAddrs, S: Transport = UdpSocket> {
+	receiver: OscReceiver<S>,
+	destinations: Vec<OscSender<T, S>>,
+	rules: RuleSet
+}
+
+impl<T: ToSocketAddrs, S: Transport> OscForwarder<T, S> {
+
+	/// Constructs a new OscForwarder with no destinations.
+	pub fn new(receiver: OscReceiver<S>, rules: RuleSet) -> Self {
+		OscForwarder{receiver, destinations: Vec::new(), rules}
+	}
+
+	/// Add a destination for forwarded packets.
+	pub fn add_destination(&mut self, sender: OscSender<T, S>) {
+		self.destinations.push(sender);
+	}
+
+	/// Apply the rules to a packet and send what is left to every destination.
+	/// Returns the packet that was sent, or None if the rules denied it.  Every
+	/// destination is tried even if sending to one fails; the first error is
+	/// returned.
+	pub fn dispatch(&self, packet: OscPacket) -> Result<Option<OscPacket>> {
+		let packet = match self.rules.apply(packet) {
+			Some(p) => p,
+			None => return Ok(None)
+		};
+
+		let mut result = Ok(());
+		for dest in &self.destinations {
+			if let Err(e) = dest.send(packet.clone()) {
+				if result.is_ok() { result = Err(e); }
+			}
+		}
+		result.map(|_| Some(packet))
+	}
+
+	/// Receive a single packet and dispatch it.  Blocks and times out in the
+	/// same way as OscReceiver::recv.
+	pub fn forward(&self, timeout: Option<Duration>) -> Result<Option<OscPacket>> {
+		self.forward_from(timeout).map(|(packet, _)| packet)
+	}
+
+	/// Like forward, but also returns the address the packet came from.
+	pub fn forward_from(&self, timeout: Option<Duration>) -> Result<(Option<OscPacket>, SocketAddr)> {
+		let (packet, source) = self.receiver.recv_from(timeout)?;
+		Ok((self.dispatch(packet)?, source))
+	}
+
+	/// Forward packets until the receiving socket fails.  Packets that fail to
+	/// decode and errors sending to a destination are skipped.
+	pub fn run(&self) -> Result<()> {
+		loop {
+			match self.receiver.recv(None) {
+				Ok(packet) => { let _ = self.dispatch(packet); },
+				Err(ref e) if e.kind() == InvalidInput => (),
+				Err(e) => return Err(e)
+			}
+		}
+	}
+}
+
+#[test]
+fn test_rule_set_apply() {
+	let rules = RuleSet::new()
+		.deny("/ping")
+		.rewrite("/1/fader*", "/mixer/ch*/level").unwrap()
+		.scale("/mixer/*/level", (0.0, 127.0), (0.0, 1.0)).unwrap();
+
+	let fader = OscMessage{addr: "/1/fader3".to_string(), args: vec!(OscInt(127), OscStr("x".to_string()))};
+	assert_eq!(rules.apply(fader), Some(OscMessage{
+		addr: "/mixer/ch3/level".to_string(),
+		args: vec!(OscFloat(1.0), OscStr("x".to_string()))
+	}));
+
+	let other = OscMessage{addr: "/other".to_string(), args: vec!(OscInt(127))};
+	assert_eq!(rules.apply(other.clone()), Some(other.clone()));
+
+	let bundle = OscBundle{
+		time_tag: (0,1),
+		conts: vec!(OscMessage{addr: "/ping".to_string(), args: vec!()}, other.clone())
+	};
+	assert_eq!(rules.apply(bundle), Some(OscBundle{time_tag: (0,1), conts: vec!(other)}));
+	assert_eq!(rules.apply(OscMessage{addr: "/ping".to_string(), args: vec!()}), None);
+}
+
+#[test]
+fn test_rule_set_parse() {
+	let text = "# legacy controller\n\ndeny /ping\n  rewrite /1/fader* /mixer/ch*/level\nscale /mixer/*/level 0 127 0 1\n";
+	assert_eq!(RuleSet::parse(text).unwrap(), RuleSet::new()
+		.deny("/ping")
+		.rewrite("/1/fader*", "/mixer/ch*/level").unwrap()
+		.scale("/mixer/*/level", (0.0, 127.0), (0.0, 1.0)).unwrap());
+
+	assert!(RuleSet::parse("deny").is_err());
+	assert!(RuleSet::parse("drop /a").is_err());
+	assert!(RuleSet::parse("rewrite /a").is_err());
+	assert!(RuleSet::parse("rewrite /a /b*").is_err());
+	assert!(RuleSet::parse("rewrite /a/* /b/*/*").is_err());
+	assert!(RuleSet::parse("scale /a 0 1 0").is_err());
+	assert!(RuleSet::parse("scale /a 1 1 0 1").is_err());
+	assert!(RuleSet::parse("scale /a 0 x 0 1").is_err());
+	assert!(RuleSet::parse("scale /a 0 inf 0 1").is_err());
+	assert!(RuleSet::parse("explode /a").is_err());
+
+	assert_eq!(RuleSet::new().rewrite("/a", "/b*").unwrap_err().kind(), InvalidInput);
+	assert_eq!(RuleSet::new().scale("/a", (1.0, 1.0), (0.0, 1.0)).unwrap_err().kind(), InvalidInput);
+}
+
+#[test]
+fn test_forwarder() {
+	use std::net::SocketAddr;
+
+	let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+	let dest1 = OscReceiver::new(localhost).unwrap();
+	let dest2 = OscReceiver::new(localhost).unwrap();
+	let input = OscReceiver::new(localhost).unwrap();
+	let input_addr = input.local_addr().unwrap();
+
+	let mut forwarder = OscForwarder::new(input, RuleSet::new().deny("/deny").rewrite("/a", "/b").unwrap());
+	forwarder.add_destination(OscSender::new(localhost, dest1.local_addr().unwrap()).unwrap());
+	forwarder.add_destination(OscSender::new(localhost, dest2.local_addr().unwrap()).unwrap());
+
+	let source = OscSender::new(localhost, input_addr).unwrap();
+	let timeout = Some(Duration::from_secs(5));
+
+	source.send(OscMessage{addr: "/deny".to_string(), args: vec!()}).unwrap();
+	assert_eq!(forwarder.forward(timeout).unwrap(), None);
+
+	source.send(OscMessage{addr: "/a".to_string(), args: vec!(OscInt(1))}).unwrap();
+	let expected = OscMessage{addr: "/b".to_string(), args: vec!(OscInt(1))};
+	assert_eq!(forwarder.forward(timeout).unwrap(), Some(expected.clone()));
+	assert_eq!(dest1.recv(timeout).unwrap(), expected);
+	assert_eq!(dest2.recv(timeout).unwrap(), expected);
+}
@@ -0,0 +1,247 @@
+//! Module for converting OSC packets to and from JSON.  Requires the `json`
+//! feature.
+//!
+//! The mapping is lossless.  A message is an object tagged `"message"`:
+//!
+//! ```text
+//! {"type": "message", "address": "/mixer/1/fader",
+//!  "args": [{"type": "f", "value": 0.5}, {"type": "i", "value": 3}]}
+//! ```
+//!
+//! and a bundle is an object tagged `"bundle"` whose contents are themselves
+//! packets:
+//!
+//! ```text
+//! {"type": "bundle", "time_tag": {"seconds": 123, "fraction": 456},
+//!  "contents": [...]}
+//! ```
+//!
+//! Every argument carries its OSC type tag, so `1` as an int and `1.0` as a float
+//! stay distinct.  Ints and floats are JSON numbers, except that infinite and NaN
+//! floats are the strings `"inf"`, `"-inf"` and `"NaN"`.  Strings are JSON
+//! strings and blobs are standard base64 strings with padding.
+
+extern crate serde_json;
+extern crate base64;
+
+use std::io::{Error, Result};
+use std::io::ErrorKind::InvalidInput;
+
+use self::serde_json::{Value, Map, Number};
+use self::base64::Engine;
+use self::base64::engine::general_purpose::STANDARD as BASE64;
+
+use data::*;
+use data::OscPacket::*;
+use data::OscArg::*;
+
+use sender::arg_to_type_tag;
+
+/// Convert a packet to its JSON value.
+pub fn packet_to_json(packet: &OscPacket) -> Value {
+	let mut obj = Map::new();
+	match *packet {
+		OscMessage{ref addr, ref args} => {
+			obj.insert("type".to_string(), Value::from("message"));
+			obj.insert("address".to_string(), Value::from(addr.as_str()));
+			obj.insert("args".to_string(), Value::Array(args.iter().map(arg_to_json).collect()));
+		},
+		OscBundle{time_tag: (sec, frac_sec), ref conts} => {
+			let mut tt = Map::new();
+			tt.insert("seconds".to_string(), Value::from(sec));
+			tt.insert("fraction".to_string(), Value::from(frac_sec));
+
+			obj.insert("type".to_string(), Value::from("bundle"));
+			obj.insert("time_tag".to_string(), Value::Object(tt));
+			obj.insert("contents".to_string(), Value::Array(conts.iter().map(packet_to_json).collect()));
+		}
+	}
+	Value::Object(obj)
+}
+
+/// Convert a JSON value back into a packet.  Returns Err if the value does not
+/// follow the mapping described in the module documentation.
+pub fn packet_from_json(value: &Value) -> Result<OscPacket> {
+	match get_str(value, "type")? {
+		"message" => {
+			let addr = get_str(value, "address")?.to_string();
+			let args = get_array(value, "args")?.iter()
+				.map(arg_from_json)
+				.collect::<Result<Vec<OscArg>>>()?;
+			Ok(OscMessage{addr, args})
+		},
+		"bundle" => {
+			let time_tag = value.get("time_tag").ok_or_else(|| missing("time_tag"))?;
+			let sec = get_u32(time_tag, "seconds")?;
+			let frac_sec = get_u32(time_tag, "fraction")?;
+			let conts = get_array(value, "contents")?.iter()
+				.map(packet_from_json)
+				.collect::<Result<Vec<OscPacket>>>()?;
+			Ok(OscBundle{time_tag: (sec, frac_sec), conts})
+		},
+		other => Err(json_err(format!("Unknown packet type {}.", other)))
+	}
+}
+
+/// Convert a packet to a JSON string.
+pub fn packet_to_json_string(packet: &OscPacket) -> String {
+	packet_to_json(packet).to_string()
+}
+
+/// Parse a JSON string as a packet.
+pub fn packet_from_json_str(s: &str) -> Result<OscPacket> {
+	let value: Value = serde_json::from_str(s).map_err(|e| Error::new(InvalidInput, e))?;
+	packet_from_json(&value)
+}
+
+/// Convert a single argument to its JSON value.
+pub fn arg_to_json(arg: &OscArg) -> Value {
+	let value = match *arg {
+		OscInt(v) => Value::from(v),
+		OscFloat(v) => float_to_json(v),
+		OscStr(ref v) => Value::from(v.as_str()),
+		OscBlob(ref v) => Value::from(BASE64.encode(v))
+	};
+
+	let mut obj = Map::new();
+	obj.insert("type".to_string(), Value::from(arg_to_type_tag(arg).to_string()));
+	obj.insert("value".to_string(), value);
+	Value::Object(obj)
+}
+
+/// Convert a JSON value back into a single argument.
+pub fn arg_from_json(value: &Value) -> Result<OscArg> {
+	let type_tag = get_str(value, "type")?;
+	let v = value.get("value").ok_or_else(|| missing("value"))?;
+	let invalid = || json_err(format!("Invalid value {} for type tag {}.", v, type_tag));
+
+	match type_tag {
+		"i" => v.as_i64()
+			.and_then(|n| if n >= i32::MIN as i64 && n <= i32::MAX as i64 { Some(n as i32) } else { None })
+			.map(OscInt)
+			.ok_or_else(invalid),
+		"f" => float_from_json(v).map(OscFloat).ok_or_else(invalid),
+		"s" => v.as_str().map(|s| OscStr(s.to_string())).ok_or_else(invalid),
+		"b" => v.as_str()
+			.and_then(|s| BASE64.decode(s).ok())
+			.map(OscBlob)
+			.ok_or_else(invalid),
+		_ => Err(json_err(format!("Invalid type tag {}", type_tag)))
+	}
+}
+
+// go through the shortest decimal form so 0.1f32 is written as 0.1, not as
+// the f64 closest to it
+fn float_to_json(v: f32) -> Value {
+	if v.is_nan() {
+		Value::from("NaN")
+	}
+	else if v.is_infinite() {
+		Value::from(if v > 0.0 { "inf" } else { "-inf" })
+	}
+	else {
+		let wide: f64 = format!("{:?}", v).parse().unwrap();
+		Value::Number(Number::from_f64(wide).unwrap())
+	}
+}
+
+fn float_from_json(v: &Value) -> Option<f32> {
+	match *v {
+		Value::Number(ref n) => n.as_f64().and_then(|f| format!("{}", f).parse().ok()),
+		Value::String(ref s) => match s.as_str() {
+			"NaN" => Some(f32::NAN),
+			"inf" => Some(f32::INFINITY),
+			"-inf" => Some(f32::NEG_INFINITY),
+			_ => None
+		},
+		_ => None
+	}
+}
+
+fn json_err<S: Into<String>>(msg: S) -> Error {
+	Error::new(InvalidInput, msg.into())
+}
+
+fn missing(field: &str) -> Error {
+	json_err(format!("Missing or invalid field {}.", field))
+}
+
+fn get_str<'a>(value: &'a Value, field: &str) -> Result<&'a str> {
+	value.get(field).and_then(Value::as_str).ok_or_else(|| missing(field))
+}
+
+fn get_u32(value: &Value, field: &str) -> Result<u32> {
+	value.get(field)
+		.and_then(Value::as_u64)
+		.and_then(|n| if n <= u32::MAX as u64 { Some(n as u32) } else { None })
+		.ok_or_else(|| missing(field))
+}
+
+fn get_array<'a>(value: &'a Value, field: &str) -> Result<&'a Vec<Value>> {
+	value.get(field).and_then(Value::as_array).ok_or_else(|| missing(field))
+}
+
+#[test]
+fn test_packet_to_json() {
+	let mess = OscMessage{
+		addr: "/a".to_string(),
+		args: vec!(OscInt(1), OscFloat(1.0), OscFloat(0.1), OscStr("x".to_string()), OscBlob(vec!(1u8, 2u8, 3u8)))
+	};
+	assert_eq!(packet_to_json_string(&mess),
+		"{\"address\":\"/a\",\"args\":[{\"type\":\"i\",\"value\":1},{\"type\":\"f\",\"value\":1.0},\
+		{\"type\":\"f\",\"value\":0.1},{\"type\":\"s\",\"value\":\"x\"},{\"type\":\"b\",\"value\":\"AQID\"}],\
+		\"type\":\"message\"}");
+
+	let bundle = OscBundle{time_tag: (123,456), conts: vec!()};
+	assert_eq!(packet_to_json_string(&bundle),
+		"{\"contents\":[],\"time_tag\":{\"fraction\":456,\"seconds\":123},\"type\":\"bundle\"}");
+}
+
+#[test]
+fn test_json_round_trip() {
+	let packet = OscBundle{
+		time_tag: (4294967295,1),
+		conts: vec!(
+			OscMessage{
+				addr: "/t".to_string(),
+				args: vec!(
+					OscInt(i32::MIN),
+					OscInt(i32::MAX),
+					OscFloat(0.1),
+					OscFloat(-3.4028235e38),
+					OscFloat(1.0e-45),
+					OscFloat(f32::INFINITY),
+					OscFloat(f32::NEG_INFINITY),
+					OscStr("unicode \u{e9} \"quoted\"".to_string()),
+					OscBlob(vec!()),
+					OscBlob(vec!(0u8, 255u8, 128u8, 7u8)))
+			},
+			OscBundle{time_tag: (0,1), conts: vec!(OscMessage{addr: "/empty".to_string(), args: vec!()})}
+		)
+	};
+
+	let json = packet_to_json_string(&packet);
+	assert_eq!(packet_from_json_str(&json).unwrap(), packet);
+
+	// NaN never compares equal, so check it separately
+	let nan = OscMessage{addr: "/n".to_string(), args: vec!(OscFloat(f32::NAN))};
+	match packet_from_json_str(&packet_to_json_string(&nan)).unwrap() {
+		OscMessage{args, ..} => match args[0] {
+			OscFloat(v) => assert!(v.is_nan()),
+			_ => panic!("expected a float")
+		},
+		_ => panic!("expected a message")
+	}
+}
+
+#[test]
+fn test_packet_from_json_errors() {
+	assert!(packet_from_json_str("not json").is_err());
+	assert!(packet_from_json_str("{\"type\":\"other\"}").is_err());
+	assert!(packet_from_json_str("{\"type\":\"message\",\"address\":\"/a\"}").is_err());
+	assert!(packet_from_json_str("{\"type\":\"message\",\"address\":\"/a\",\"args\":[{\"type\":\"i\",\"value\":1.5}]}").is_err());
+	assert!(packet_from_json_str("{\"type\":\"message\",\"address\":\"/a\",\"args\":[{\"type\":\"i\",\"value\":2147483648}]}").is_err());
+	assert!(packet_from_json_str("{\"type\":\"message\",\"address\":\"/a\",\"args\":[{\"type\":\"b\",\"value\":\"!!\"}]}").is_err());
+	assert!(packet_from_json_str("{\"type\":\"message\",\"address\":\"/a\",\"args\":[{\"type\":\"x\",\"value\":1}]}").is_err());
+	assert!(packet_from_json_str("{\"type\":\"bundle\",\"time_tag\":{\"seconds\":-1,\"fraction\":0},\"contents\":[]}").is_err());
+}
@@ -8,5 +8,36 @@
 mod util;  // must declare mods with macro exports in them before users!
 pub mod receiver;
 pub mod sender;
+pub mod transport;
+pub mod policy;
+pub mod stats;
+pub mod socket;
+pub mod listener;
+pub mod server;
+#[cfg(test)]
+mod conformance;
 #[macro_use]
 pub mod data;
+pub mod text;
+pub mod pattern;
+pub mod record;
+pub mod forward;
+pub mod state;
+pub mod sync;
+pub mod queue;
+pub mod bundle;
+pub mod reliable;
+pub mod query;
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "oscquery")]
+pub mod oscquery;
+#[cfg(feature = "discovery")]
+pub mod discovery;
+#[cfg(all(feature = "batch", target_os = "linux"))]
+pub mod batch;
+// also built for tests, so the properties run without the feature
+#[cfg(any(feature = "arbitrary", test))]
+pub mod arbitrary;
+#[cfg(feature = "auth")]
+pub mod auth;
@@ -0,0 +1,256 @@
+//! Module for receiving OSC on a background thread.
+//!
+//! An OscListener owns an OscReceiver and a thread that receives from it,
+//! handing every packet to a handler: any closure taking the packet and the
+//! address it came from, or an OscDispatcher calling a different closure for
+//! each address pattern.  Receive and decode errors go to an error callback
+//! and the thread carries on.
+//!
+//! The thread checks for shutdown between packets, at least every 100ms.  It
+//! is stopped by a ShutdownHandle, by OscListener::stop, which also gives the
+//! receiver back, or by dropping the listener; the last two wait for the
+//! thread to finish.
+
+use std::net::{UdpSocket, SocketAddr};
+use std::io::{Error, Result};
+use std::io::ErrorKind::{TimedOut, WouldBlock};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use data::*;
+use data::OscPacket::*;
+use pattern::addr_matches;
+use receiver::OscReceiver;
+use transport::Transport;
+
+// how often the thread checks whether it should stop
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// Something that handles received packets.
+pub trait OscHandler {
+	fn handle(&mut self, packet: OscPacket, source: SocketAddr);
+}
+
+impl<F: FnMut(OscPacket, SocketAddr)> OscHandler for F {
+	fn handle(&mut self, packet: OscPacket, source: SocketAddr) {
+		self(packet, source)
+	}
+}
+
+type MessageHandler = Box<dyn FnMut(&str, &[OscArg]) + Send>;
+
+/// Calls a closure for every message whose address matches its pattern,
+/// unpacking bundles.  A message matching several patterns is handed to each
+/// in the order they were added.
+#[derive(Default)]
+pub struct OscDispatcher {
+	routes: Vec<(String, MessageHandler)>
+}
+
+impl OscDispatcher {
+
+	/// Constructs a dispatcher with no routes.
+	pub fn new() -> OscDispatcher {
+		OscDispatcher{routes: Vec::new()}
+	}
+
+	/// Call a closure with the address and arguments of every message matching
+	/// an OSC address pattern.
+	pub fn add<F: FnMut(&str, &[OscArg]) + Send + 'static>(&mut self, pattern: &str, handler: F) {
+		self.routes.push((pattern.to_string(), Box::new(handler)));
+	}
+
+	/// Hand every message in a packet to the matching routes.  Returns the
+	/// number of calls made.
+	pub fn dispatch(&mut self, packet: &OscPacket) -> usize {
+		match *packet {
+			OscMessage{ref addr, ref args} => {
+				let mut calls = 0;
+				for &mut (ref pattern, ref mut handler) in &mut self.routes {
+					if addr_matches(pattern, addr) {
+						handler(addr, args);
+						calls += 1;
+					}
+				}
+				calls
+			},
+			OscBundle{ref conts, ..} => conts.iter().map(|p| self.dispatch(p)).sum()
+		}
+	}
+}
+
+impl OscHandler for OscDispatcher {
+	fn handle(&mut self, packet: OscPacket, _: SocketAddr) {
+		self.dispatch(&packet);
+	}
+}
+
+/// Stops a listener's thread from anywhere.
+#[derive(Clone,Default)]
+pub struct ShutdownHandle {
+	stop: Arc<AtomicBool>
+}
+
+impl ShutdownHandle {
+
+	/// Constructs a handle that hasn't been shut down, for threads of your own.
+	pub fn new() -> ShutdownHandle {
+		ShutdownHandle{stop: Arc::new(AtomicBool::new(false))}
+	}
+
+	/// Ask the thread to stop.  It finishes handling the current packet first.
+	pub fn shutdown(&self) {
+		self.stop.store(true, Ordering::SeqCst);
+	}
+
+	/// Whether shutdown has been asked for.
+	pub fn is_shutdown(&self) -> bool {
+		self.stop.load(Ordering::SeqCst)
+	}
+}
+
+/// A receiver running on a background thread.  Receives over UDP unless the
+/// receiver uses another Transport.
+pub struct OscListener<S: Transport = UdpSocket> {
+	local_addr: SocketAddr,
+	handle: ShutdownHandle,
+	thread: Option<JoinHandle<OscReceiver<S>>>
+}
+
+impl<S: Transport + Send + 'static> OscListener<S> {
+
+	/// Start a thread receiving from a receiver and passing every packet to a
+	/// handler.  Errors are ignored.
+	pub fn spawn<H: OscHandler + Send + 'static>(receiver: OscReceiver<S>, handler: H) -> Result<OscListener<S>> {
+		OscListener::spawn_with_errors(receiver, handler, |_| ())
+	}
+
+	/// Start a thread receiving from a receiver, passing every packet to a
+	/// handler and every error other than a read timing out to on_error.
+	pub fn spawn_with_errors<H, E>(receiver: OscReceiver<S>, handler: H, on_error: E) -> Result<OscListener<S>>
+		where H: OscHandler + Send + 'static, E: FnMut(Error) + Send + 'static {
+
+		OscListener::start(receiver, handler, on_error, ShutdownHandle::new())
+	}
+
+	/// Start a thread like spawn, stopped by a handle that can be shared with
+	/// other threads.  Stopping or dropping the listener shuts the handle
+	/// down for all of them.
+	pub fn spawn_with_handle<H: OscHandler + Send + 'static>(receiver: OscReceiver<S>, handler: H, handle: ShutdownHandle)
+		-> Result<OscListener<S>> {
+
+		OscListener::start(receiver, handler, |_| (), handle)
+	}
+
+	fn start<H, E>(receiver: OscReceiver<S>, mut handler: H, mut on_error: E, handle: ShutdownHandle) -> Result<OscListener<S>>
+		where H: OscHandler + Send + 'static, E: FnMut(Error) + Send + 'static {
+
+		let local_addr = receiver.local_addr()?;
+		let thread_handle = handle.clone();
+		let thread = thread::spawn(move || {
+			let timeout = Some(Duration::from_millis(POLL_INTERVAL_MS));
+			while !thread_handle.is_shutdown() {
+				match receiver.recv_from(timeout) {
+					Ok((packet, source)) => handler.handle(packet, source),
+					Err(ref e) if e.kind() == WouldBlock || e.kind() == TimedOut => (),
+					Err(e) => on_error(e)
+				}
+			}
+			receiver
+		});
+
+		Ok(OscListener{local_addr, handle, thread: Some(thread)})
+	}
+
+	/// The local address the receiver is bound to.
+	pub fn local_addr(&self) -> SocketAddr {
+		self.local_addr
+	}
+
+	/// A handle for stopping the thread.
+	pub fn shutdown_handle(&self) -> ShutdownHandle {
+		self.handle.clone()
+	}
+
+	/// Whether the thread is still running.
+	pub fn is_running(&self) -> bool {
+		self.thread.as_ref().is_some_and(|t| !t.is_finished())
+	}
+
+	/// Stop the thread, wait for it to finish and give back the receiver.
+	/// Returns None if the handler panicked.
+	pub fn stop(mut self) -> Option<OscReceiver<S>> {
+		self.handle.shutdown();
+		self.thread.take().and_then(|t| t.join().ok())
+	}
+}
+
+impl<S: Transport> Drop for OscListener<S> {
+	fn drop(&mut self) {
+		self.handle.shutdown();
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+	}
+}
+
+#[test]
+fn test_listener_with_closure() {
+	use std::sync::mpsc::channel;
+	use std::net::UdpSocket;
+	use sender::OscSender;
+
+	let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+	let (packets, received) = channel();
+	let (errors, received_errors) = channel();
+	let listener = OscListener::spawn_with_errors(
+		OscReceiver::new(localhost).unwrap(),
+		move |packet, _| { packets.send(packet).unwrap(); },
+		move |e: Error| { errors.send(e.kind()).unwrap(); }
+	).unwrap();
+	assert!(listener.is_running());
+
+	let sender = OscSender::new(localhost, listener.local_addr()).unwrap();
+	let msg = OscMessage{addr: "/a".to_string(), args: vec!()};
+	sender.send(msg.clone()).unwrap();
+	assert_eq!(received.recv_timeout(Duration::from_secs(5)).unwrap(), msg);
+
+	// garbage is reported, and the thread carries on
+	UdpSocket::bind(localhost).unwrap().send_to(&[1, 2], listener.local_addr()).unwrap();
+	assert_eq!(received_errors.recv_timeout(Duration::from_secs(5)).unwrap(), ::std::io::ErrorKind::InvalidInput);
+
+	let handle = listener.shutdown_handle();
+	handle.shutdown();
+	let receiver = listener.stop().unwrap();
+	assert_eq!(receiver.try_recv().unwrap(), None);
+}
+
+#[test]
+fn test_listener_with_dispatcher() {
+	use std::sync::mpsc::channel;
+	use data::OscArg::*;
+	use sender::OscSender;
+
+	let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+	let (faders, received) = channel();
+	let mut dispatcher = OscDispatcher::new();
+	dispatcher.add("/mixer/*/fader", move |addr, args| {
+		faders.send((addr.to_string(), args.to_vec())).unwrap();
+	});
+	dispatcher.add("/mixer/*/mute", |_, _| ());
+	assert_eq!(dispatcher.dispatch(&OscMessage{addr: "/mixer/1/mute".to_string(), args: vec!()}), 1);
+
+	let listener = OscListener::spawn(OscReceiver::new(localhost).unwrap(), dispatcher).unwrap();
+	let sender = OscSender::new(localhost, listener.local_addr()).unwrap();
+	sender.send(OscBundle{time_tag: (0, 1), conts: vec!(
+		OscMessage{addr: "/mixer/1/mute".to_string(), args: vec!(OscInt(1))},
+		OscMessage{addr: "/mixer/2/fader".to_string(), args: vec!(OscFloat(0.5))}
+	)}).unwrap();
+
+	assert_eq!(received.recv_timeout(Duration::from_secs(5)).unwrap(),
+		("/mixer/2/fader".to_string(), vec!(OscFloat(0.5))));
+	drop(listener);
+	assert!(received.recv().is_err());
+}
@@ -0,0 +1,722 @@
+//! Module for publishing and browsing OSC address spaces with OSCQuery.
+//! Requires the `oscquery` feature.
+//!
+//! OSCQuery describes an address space as a tree of JSON nodes served over
+//! HTTP.  Each node has a `FULL_PATH`, and may have `CONTENTS` (its children),
+//! and, for a method that accepts messages, its `TYPE` tags, `ACCESS`, current
+//! `VALUE`, `RANGE` and `DESCRIPTION`.  `GET /path` returns the node at that
+//! path, `GET /path?VALUE` returns just one attribute, and `GET /?HOST_INFO`
+//! describes the server and the OSC port it belongs to.
+//!
+//! The server here answers plain HTTP only, with a thread for each connection.
+//! The optional WebSocket `LISTEN` extension, for streaming value changes to
+//! clients, is not implemented: HOST_INFO reports `LISTEN` as false, so
+//! clients poll values with `GET` instead.  Blob values are written as base64
+//! strings, as in the json module.  A remote method whose type tags include
+//! types other than `i`, `f`, `s` and `b` is read without its value.
+
+extern crate serde_json;
+extern crate base64;
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::net::{TcpListener, TcpStream, SocketAddr, ToSocketAddrs, Shutdown, Ipv4Addr, Ipv6Addr};
+use std::io::{Error, Result, BufRead, BufReader, Read, Write};
+use std::io::ErrorKind::{InvalidData, InvalidInput, NotFound};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use self::serde_json::{Value, Map, Number};
+use self::base64::Engine;
+use self::base64::engine::general_purpose::STANDARD as BASE64;
+
+use data::*;
+use data::OscPacket::*;
+use data::OscArg::*;
+
+use sender::arg_to_type_tag;
+
+const HTTP_TIMEOUT_SECS: u64 = 5;
+// connections served at once; more are turned away until one finishes
+const MAX_CONNECTIONS: usize = 64;
+// longest request line or header, and most headers, a request may have
+const MAX_LINE_LEN: usize = 8192;
+const MAX_HEADERS: usize = 100;
+
+/// Whether a method's value may be read, written, or both.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Access {
+	NoValue,
+	ReadOnly,
+	WriteOnly,
+	ReadWrite
+}
+
+impl Access {
+	/// The numeric form used by OSCQuery.
+	pub fn to_number(self) -> u64 {
+		match self {
+			Access::NoValue => 0,
+			Access::ReadOnly => 1,
+			Access::WriteOnly => 2,
+			Access::ReadWrite => 3
+		}
+	}
+
+	/// Parse the numeric form used by OSCQuery.
+	pub fn from_number(n: u64) -> Option<Access> {
+		match n {
+			0 => Some(Access::NoValue),
+			1 => Some(Access::ReadOnly),
+			2 => Some(Access::WriteOnly),
+			3 => Some(Access::ReadWrite),
+			_ => None
+		}
+	}
+}
+
+/// The range of one argument of a method.  Either bound may be left open.
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct Range {
+	pub min: Option<f64>,
+	pub max: Option<f64>
+}
+
+/// A node of an OSCQuery address space.  A node with type tags is a method
+/// that accepts messages; any node may also contain child nodes.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Node {
+	pub full_path: String,
+	pub type_tags: Option<String>,
+	pub access: Option<Access>,
+	pub value: Option<Vec<OscArg>>,
+	pub range: Option<Vec<Range>>,
+	pub description: Option<String>,
+	pub contents: BTreeMap<String, Node>
+}
+
+impl Node {
+
+	/// Constructs an empty container node.
+	pub fn new(full_path: &str) -> Node {
+		Node{
+			full_path: full_path.to_string(),
+			type_tags: None,
+			access: None,
+			value: None,
+			range: None,
+			description: None,
+			contents: BTreeMap::new()
+		}
+	}
+
+	/// Convert the node and its children to OSCQuery JSON.
+	pub fn to_json(&self) -> Value {
+		let mut obj = Map::new();
+		obj.insert("FULL_PATH".to_string(), Value::from(self.full_path.as_str()));
+		for attr in &["TYPE", "ACCESS", "VALUE", "RANGE", "DESCRIPTION"] {
+			if let Some(v) = self.attribute(attr) {
+				obj.insert(attr.to_string(), v);
+			}
+		}
+		if !self.contents.is_empty() {
+			let contents = self.contents.iter().map(|(k, n)| (k.clone(), n.to_json())).collect();
+			obj.insert("CONTENTS".to_string(), Value::Object(contents));
+		}
+		Value::Object(obj)
+	}
+
+	/// Get a single attribute by its OSCQuery name, if the node has it.
+	pub fn attribute(&self, name: &str) -> Option<Value> {
+		match name {
+			"FULL_PATH" => Some(Value::from(self.full_path.as_str())),
+			"TYPE" => self.type_tags.as_ref().map(|t| Value::from(t.as_str())),
+			"ACCESS" => self.access.map(|a| Value::from(a.to_number())),
+			"VALUE" => self.value.as_ref().map(|v| Value::Array(v.iter().map(value_to_json).collect())),
+			"RANGE" => self.range.as_ref().map(|r| Value::Array(r.iter().map(range_to_json).collect())),
+			"DESCRIPTION" => self.description.as_ref().map(|d| Value::from(d.as_str())),
+			"CONTENTS" => self.to_json().get("CONTENTS").cloned(),
+			_ => None
+		}
+	}
+
+	/// Parse a node and its children from OSCQuery JSON.
+	pub fn from_json(json: &Value) -> Result<Node> {
+		let full_path = json.get("FULL_PATH").and_then(Value::as_str)
+			.ok_or_else(|| invalid("Node without FULL_PATH."))?;
+		let mut node = Node::new(full_path);
+
+		node.type_tags = json.get("TYPE").and_then(Value::as_str).map(String::from);
+		node.description = json.get("DESCRIPTION").and_then(Value::as_str).map(String::from);
+		node.access = match json.get("ACCESS") {
+			Some(a) => Some(a.as_u64().and_then(Access::from_number).ok_or_else(|| invalid("Invalid ACCESS."))?),
+			None => None
+		};
+		node.range = json.get("RANGE").and_then(Value::as_array).map(|ranges| {
+			ranges.iter().map(|r| Range{
+				min: r.get("MIN").and_then(Value::as_f64),
+				max: r.get("MAX").and_then(Value::as_f64)
+			}).collect()
+		});
+		node.value = match (json.get("VALUE").and_then(Value::as_array), node.type_tags.as_ref()) {
+			(Some(values), Some(tags)) => values_from_json(values, tags)?,
+			_ => None
+		};
+		if let Some(contents) = json.get("CONTENTS").and_then(Value::as_object) {
+			for (name, child) in contents {
+				node.contents.insert(name.clone(), Node::from_json(child)?);
+			}
+		}
+		Ok(node)
+	}
+}
+
+fn invalid<S: Into<String>>(msg: S) -> Error {
+	Error::new(InvalidData, msg.into())
+}
+
+fn value_to_json(arg: &OscArg) -> Value {
+	match *arg {
+		OscInt(v) => Value::from(v),
+		OscFloat(v) => Number::from_f64(v as f64).map(Value::Number).unwrap_or(Value::Null),
+		OscStr(ref v) => Value::from(v.as_str()),
+		OscBlob(ref v) => Value::from(BASE64.encode(v))
+	}
+}
+
+fn range_to_json(range: &Range) -> Value {
+	let mut obj = Map::new();
+	if let Some(min) = range.min { obj.insert("MIN".to_string(), Value::from(min)); }
+	if let Some(max) = range.max { obj.insert("MAX".to_string(), Value::from(max)); }
+	Value::Object(obj)
+}
+
+// values of types OscArg can't hold (T, F, N, d, h, arrays...) leave the
+// node without a value rather than failing the whole tree
+fn values_from_json(values: &[Value], type_tags: &str) -> Result<Option<Vec<OscArg>>> {
+	if !type_tags.chars().all(|tt| "ifsb".contains(tt)) {
+		return Ok(None);
+	}
+	if values.len() != type_tags.chars().count() {
+		return Err(invalid("VALUE does not match TYPE."));
+	}
+	type_tags.chars().zip(values).map(|(tt, v)| {
+		let arg = match tt {
+			'i' => v.as_i64().and_then(|n| i32::try_from(n).ok()).map(OscInt),
+			'f' => v.as_f64().map(|n| OscFloat(n as f32)),
+			's' => v.as_str().map(|s| OscStr(s.to_string())),
+			'b' => v.as_str().and_then(|s| BASE64.decode(s).ok()).map(OscBlob),
+			_ => None
+		};
+		arg.ok_or_else(|| invalid(format!("Invalid value {} for type tag {}.", v, tt)))
+	}).collect::<Result<Vec<OscArg>>>().map(Some)
+}
+
+// split "/a/b" into ["a", "b"]; the root has no parts
+fn path_parts(path: &str) -> Vec<&str> {
+	path.split('/').filter(|p| !p.is_empty()).collect()
+}
+
+/// A registry of the OSC methods an application accepts, published by an
+/// OscQueryServer.
+#[derive(Debug,Clone,PartialEq)]
+pub struct OscNamespace {
+	root: Node
+}
+
+impl Default for OscNamespace {
+	fn default() -> Self {
+		OscNamespace::new()
+	}
+}
+
+impl OscNamespace {
+
+	/// Constructs an empty namespace.
+	pub fn new() -> OscNamespace {
+		OscNamespace{root: Node::new("/")}
+	}
+
+	/// Register a method, creating any containers above it.  Returns the new
+	/// node so its range, description and initial value can be filled in.
+	pub fn add_method(&mut self, path: &str, type_tags: &str, access: Access) -> &mut Node {
+		let node = self.node_mut(path);
+		node.type_tags = Some(type_tags.to_string());
+		node.access = Some(access);
+		node
+	}
+
+	/// Look up the node at a path.
+	pub fn get(&self, path: &str) -> Option<&Node> {
+		let mut node = &self.root;
+		for part in path_parts(path) {
+			node = node.contents.get(part)?;
+		}
+		Some(node)
+	}
+
+	/// The root of the namespace.
+	pub fn root(&self) -> &Node {
+		&self.root
+	}
+
+	/// Set the current value of a registered method.  Returns false if no
+	/// method is registered at that path or the arguments don't match its type
+	/// tags.
+	pub fn set_value(&mut self, path: &str, args: Vec<OscArg>) -> bool {
+		let mut node = &mut self.root;
+		for part in path_parts(path) {
+			node = match node.contents.get_mut(part) {
+				Some(n) => n,
+				None => return false
+			};
+		}
+		let type_tags: String = args.iter().map(arg_to_type_tag).collect();
+		if node.type_tags.as_ref() != Some(&type_tags) {
+			return false;
+		}
+		node.value = Some(args);
+		true
+	}
+
+	/// Update the values of registered methods from every message in a
+	/// packet.  Messages for unregistered addresses are ignored.
+	pub fn update(&mut self, packet: &OscPacket) {
+		match *packet {
+			OscMessage{ref addr, ref args} => { self.set_value(addr, args.clone()); },
+			OscBundle{ref conts, ..} => {
+				for p in conts { self.update(p); }
+			}
+		}
+	}
+
+	fn node_mut(&mut self, path: &str) -> &mut Node {
+		let mut node = &mut self.root;
+		let mut full_path = String::new();
+		for part in path_parts(path) {
+			full_path.push('/');
+			full_path.push_str(part);
+			node = node.contents.entry(part.to_string()).or_insert_with(|| Node::new(&full_path));
+		}
+		node
+	}
+}
+
+/// Describes the server and the OSC port its namespace belongs to.
+#[derive(Debug,Clone,PartialEq)]
+pub struct HostInfo {
+	pub name: String,
+	pub osc_ip: Option<String>,
+	pub osc_port: u16,
+	pub osc_transport: String
+}
+
+impl HostInfo {
+
+	/// Constructs host info for an application receiving OSC over UDP.
+	pub fn new(name: &str, osc_port: u16) -> HostInfo {
+		HostInfo{name: name.to_string(), osc_ip: None, osc_port, osc_transport: "UDP".to_string()}
+	}
+
+	/// Convert to OSCQuery HOST_INFO JSON.
+	pub fn to_json(&self) -> Value {
+		let mut extensions = Map::new();
+		for ext in &["ACCESS", "VALUE", "RANGE", "DESCRIPTION"] {
+			extensions.insert(ext.to_string(), Value::Bool(true));
+		}
+		extensions.insert("LISTEN".to_string(), Value::Bool(false));
+
+		let mut obj = Map::new();
+		obj.insert("NAME".to_string(), Value::from(self.name.as_str()));
+		if let Some(ref ip) = self.osc_ip {
+			obj.insert("OSC_IP".to_string(), Value::from(ip.as_str()));
+		}
+		obj.insert("OSC_PORT".to_string(), Value::from(self.osc_port));
+		obj.insert("OSC_TRANSPORT".to_string(), Value::from(self.osc_transport.as_str()));
+		obj.insert("EXTENSIONS".to_string(), Value::Object(extensions));
+		Value::Object(obj)
+	}
+
+	/// Parse OSCQuery HOST_INFO JSON.
+	pub fn from_json(json: &Value) -> Result<HostInfo> {
+		Ok(HostInfo{
+			name: json.get("NAME").and_then(Value::as_str).unwrap_or("").to_string(),
+			osc_ip: json.get("OSC_IP").and_then(Value::as_str).map(String::from),
+			osc_port: json.get("OSC_PORT").and_then(Value::as_u64)
+				.filter(|p| *p <= u16::MAX as u64)
+				.ok_or_else(|| invalid("HOST_INFO without OSC_PORT."))? as u16,
+			osc_transport: json.get("OSC_TRANSPORT").and_then(Value::as_str).unwrap_or("UDP").to_string()
+		})
+	}
+}
+
+/// Serves a namespace over HTTP from a background thread, handling each
+/// connection on a thread of its own.  The namespace is shared, so values
+/// updated by the application are visible to clients straight away.  The
+/// server stops accepting connections when it is dropped.
+pub struct OscQueryServer {
+	local_addr: SocketAddr,
+	stop: Arc<AtomicBool>,
+	thread: Option<JoinHandle<()>>
+}
+
+impl OscQueryServer {
+
+	/// Bind the HTTP port and start serving.  Returns Err if the port could not
+	/// be bound.
+	pub fn bind<A: ToSocketAddrs>(addr: A, namespace: Arc<Mutex<OscNamespace>>, host_info: HostInfo) -> Result<OscQueryServer> {
+		let listener = TcpListener::bind(addr)?;
+		let local_addr = listener.local_addr()?;
+		let stop = Arc::new(AtomicBool::new(false));
+
+		let thread_stop = stop.clone();
+		let host_info = Arc::new(host_info);
+		let connections = Arc::new(AtomicUsize::new(0));
+		let thread = thread::spawn(move || {
+			for stream in listener.incoming() {
+				if thread_stop.load(Ordering::SeqCst) { break; }
+				let stream = match stream {
+					Ok(s) => s,
+					Err(_) => continue
+				};
+				if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+					connections.fetch_sub(1, Ordering::SeqCst);
+					let _ = write_response(stream, 503, "Service Unavailable", None);
+					continue;
+				}
+
+				// a slow or misbehaving client only holds up its own connection
+				let namespace = namespace.clone();
+				let host_info = host_info.clone();
+				let connections = connections.clone();
+				thread::spawn(move || {
+					let _ = handle_connection(stream, &namespace, &host_info);
+					connections.fetch_sub(1, Ordering::SeqCst);
+				});
+			}
+		});
+
+		Ok(OscQueryServer{local_addr, stop, thread: Some(thread)})
+	}
+
+	/// The address the server is listening on.
+	pub fn local_addr(&self) -> SocketAddr {
+		self.local_addr
+	}
+}
+
+impl Drop for OscQueryServer {
+	fn drop(&mut self) {
+		self.stop.store(true, Ordering::SeqCst);
+		// wake the accept loop so it sees the flag; a server bound to every
+		// interface is reached through loopback
+		let mut wake_addr = self.local_addr;
+		if wake_addr.ip().is_unspecified() {
+			wake_addr.set_ip(if wake_addr.is_ipv4() { Ipv4Addr::LOCALHOST.into() } else { Ipv6Addr::LOCALHOST.into() });
+		}
+		let _ = TcpStream::connect(wake_addr);
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+	}
+}
+
+fn handle_connection(stream: TcpStream, namespace: &Mutex<OscNamespace>, host_info: &HostInfo) -> Result<()> {
+	stream.set_read_timeout(Some(Duration::from_secs(HTTP_TIMEOUT_SECS)))?;
+	let mut reader = BufReader::new(stream.try_clone()?);
+	let request_line = match read_request(&mut reader) {
+		Ok(line) => line,
+		Err(ref e) if e.kind() == InvalidData => return write_response(stream, 400, "Bad Request", None),
+		Err(e) => return Err(e)
+	};
+
+	let mut words = request_line.split_whitespace();
+	let (method, target) = match (words.next(), words.next()) {
+		(Some(m), Some(t)) => (m, t),
+		_ => return write_response(stream, 400, "Bad Request", None)
+	};
+	if method != "GET" {
+		return write_response(stream, 405, "Method Not Allowed", None);
+	}
+
+	let (path, query) = match target.find('?') {
+		Some(i) => (&target[..i], Some(&target[i + 1..])),
+		None => (target, None)
+	};
+
+	let body = if query == Some("HOST_INFO") {
+		Some(host_info.to_json())
+	}
+	else {
+		let path = percent_decode(path);
+		let namespace = namespace.lock().unwrap();
+		let node = match namespace.get(&path) {
+			Some(n) => n,
+			None => return write_response(stream, 404, "Not Found", None)
+		};
+		match query {
+			None => Some(node.to_json()),
+			Some(attr) => node.attribute(attr).map(|v| {
+				let mut obj = Map::new();
+				obj.insert(attr.to_string(), v);
+				Value::Object(obj)
+			})
+		}
+	};
+
+	match body {
+		Some(b) => write_response(stream, 200, "OK", Some(b)),
+		// the node exists but lacks the attribute
+		None => write_response(stream, 204, "No Content", None)
+	}
+}
+
+// read the request line and skip the headers, we don't need any of them
+fn read_request<R: BufRead>(reader: &mut R) -> Result<String> {
+	let request_line = read_http_line(reader)?;
+	let mut headers = 0;
+	loop {
+		let header = read_http_line(reader)?;
+		if header.trim().is_empty() { break; }
+		headers += 1;
+		if headers > MAX_HEADERS {
+			return Err(invalid("Too many headers."));
+		}
+	}
+	Ok(request_line)
+}
+
+fn read_http_line<R: BufRead>(reader: &mut R) -> Result<String> {
+	let mut line = String::new();
+	let n = reader.take(MAX_LINE_LEN as u64).read_line(&mut line)?;
+	if n == MAX_LINE_LEN && !line.ends_with('\n') {
+		return Err(invalid("Line too long."));
+	}
+	Ok(line)
+}
+
+fn write_response(mut stream: TcpStream, status: u16, reason: &str, body: Option<Value>) -> Result<()> {
+	let body = body.map(|b| b.to_string()).unwrap_or_default();
+	write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+		status, reason, body.len(), body)?;
+	stream.flush()?;
+	stream.shutdown(Shutdown::Both)
+}
+
+// decode %XX escapes in a request path
+fn percent_decode(s: &str) -> String {
+	let bytes = s.as_bytes();
+	let mut out = Vec::with_capacity(bytes.len());
+	let mut i = 0;
+	while i < bytes.len() {
+		if bytes[i] == b'%' && i + 2 < bytes.len() {
+			// the two digits are checked as bytes, since they may be part of a
+			// multi-byte character
+			let digits = std::str::from_utf8(&bytes[i + 1..i + 3]).ok()
+				.and_then(|d| u8::from_str_radix(d, 16).ok());
+			if let Some(b) = digits {
+				out.push(b);
+				i += 3;
+				continue;
+			}
+		}
+		out.push(bytes[i]);
+		i += 1;
+	}
+	String::from_utf8_lossy(&out).into_owned()
+}
+
+/// Browses the namespace of a remote OSCQuery server.
+pub struct OscQueryClient {
+	addr: SocketAddr
+}
+
+impl OscQueryClient {
+
+	/// Constructs a client for the server at the given HTTP address.
+	pub fn new<A: ToSocketAddrs>(addr: A) -> Result<OscQueryClient> {
+		let addr = addr.to_socket_addrs()?.next()
+			.ok_or_else(|| Error::new(InvalidInput, "No address to connect to."))?;
+		Ok(OscQueryClient{addr})
+	}
+
+	/// Fetch the server's host info.
+	pub fn host_info(&self) -> Result<HostInfo> {
+		let json = self.get("/?HOST_INFO")?.ok_or_else(|| invalid("Server sent no HOST_INFO."))?;
+		HostInfo::from_json(&json)
+	}
+
+	/// Fetch the node at a path along with everything below it.
+	pub fn node(&self, path: &str) -> Result<Node> {
+		let json = self.get(path)?.ok_or_else(|| invalid("Server sent an empty node."))?;
+		Node::from_json(&json)
+	}
+
+	/// Fetch the current value of the method at a path.  Returns None if the
+	/// method has no value.
+	pub fn value(&self, path: &str) -> Result<Option<Vec<OscArg>>> {
+		let node = self.node(path)?;
+		Ok(node.value)
+	}
+
+	// perform a GET, returning None for 204 No Content
+	fn get(&self, target: &str) -> Result<Option<Value>> {
+		let mut stream = TcpStream::connect(self.addr)?;
+		stream.set_read_timeout(Some(Duration::from_secs(HTTP_TIMEOUT_SECS)))?;
+		write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", target, self.addr)?;
+		stream.flush()?;
+
+		let mut response = Vec::new();
+		stream.read_to_end(&mut response)?;
+		let response = String::from_utf8(response).map_err(|e| Error::new(InvalidData, e))?;
+
+		let (head, body) = match response.find("\r\n\r\n") {
+			Some(i) => (&response[..i], &response[i + 4..]),
+			None => return Err(invalid("Malformed HTTP response."))
+		};
+		let status = head.split_whitespace().nth(1).and_then(|s| s.parse::<u16>().ok())
+			.ok_or_else(|| invalid("Malformed HTTP status line."))?;
+
+		match status {
+			200 => serde_json::from_str(body).map(Some).map_err(|e| Error::new(InvalidData, e)),
+			204 => Ok(None),
+			404 => Err(Error::new(NotFound, format!("No node at {}.", target))),
+			_ => Err(invalid(format!("HTTP error {}.", status)))
+		}
+	}
+}
+
+#[cfg(test)]
+fn test_namespace() -> OscNamespace {
+	let mut namespace = OscNamespace::new();
+	{
+		let fader = namespace.add_method("/mixer/1/fader", "f", Access::ReadWrite);
+		fader.value = Some(vec!(OscFloat(0.5)));
+		fader.range = Some(vec!(Range{min: Some(0.0), max: Some(1.0)}));
+		fader.description = Some("Channel 1 fader".to_string());
+	}
+	namespace.add_method("/mixer/1/name", "s", Access::ReadOnly);
+	namespace
+}
+
+#[test]
+fn test_namespace_json() {
+	let mut namespace = test_namespace();
+	assert!(namespace.set_value("/mixer/1/name", vec!(OscStr("Kick".to_string()))));
+	assert!(!namespace.set_value("/mixer/1/name", vec!(OscInt(1))));
+	assert!(!namespace.set_value("/mixer/1", vec!(OscInt(1))));
+	assert!(!namespace.set_value("/nowhere", vec!(OscInt(1))));
+
+	let json = namespace.get("/mixer/1/fader").unwrap().to_json();
+	assert_eq!(json.to_string(),
+		"{\"ACCESS\":3,\"DESCRIPTION\":\"Channel 1 fader\",\"FULL_PATH\":\"/mixer/1/fader\",\
+		\"RANGE\":[{\"MAX\":1.0,\"MIN\":0.0}],\"TYPE\":\"f\",\"VALUE\":[0.5]}");
+
+	let root = namespace.root();
+	assert_eq!(&Node::from_json(&root.to_json()).unwrap(), root);
+}
+
+#[test]
+fn test_namespace_update() {
+	let mut namespace = test_namespace();
+	namespace.update(&OscBundle{
+		time_tag: (0,1),
+		conts: vec!(
+			OscMessage{addr: "/mixer/1/fader".to_string(), args: vec!(OscFloat(0.25))},
+			OscMessage{addr: "/unknown".to_string(), args: vec!(OscFloat(1.0))}
+		)
+	});
+	assert_eq!(namespace.get("/mixer/1/fader").unwrap().value, Some(vec!(OscFloat(0.25))));
+	assert!(namespace.get("/unknown").is_none());
+}
+
+#[test]
+fn test_server_and_client() {
+	let namespace = Arc::new(Mutex::new(test_namespace()));
+	let server = OscQueryServer::bind("127.0.0.1:0", namespace.clone(), HostInfo::new("test", 9000)).unwrap();
+	let client = OscQueryClient::new(server.local_addr()).unwrap();
+
+	let info = client.host_info().unwrap();
+	assert_eq!(info, HostInfo::new("test", 9000));
+
+	let root = client.node("/").unwrap();
+	assert_eq!(&root, namespace.lock().unwrap().root());
+
+	namespace.lock().unwrap().set_value("/mixer/1/fader", vec!(OscFloat(0.75)));
+	assert_eq!(client.value("/mixer/1/fader").unwrap(), Some(vec!(OscFloat(0.75))));
+	assert_eq!(client.value("/mixer/1/name").unwrap(), None);
+	assert_eq!(client.get("/mixer/1/name?VALUE").unwrap(), None);
+	assert_eq!(client.get("/mixer/1/fader?ACCESS").unwrap().unwrap().to_string(), "{\"ACCESS\":3}");
+	assert_eq!(client.node("/nowhere").unwrap_err().kind(), NotFound);
+}
+
+#[test]
+fn test_percent_decode() {
+	assert_eq!(percent_decode("/mixer/1%2Ffader"), "/mixer/1/fader");
+	assert_eq!(percent_decode("/a%20b%"), "/a b%");
+	// a % followed by part of a multi-byte character is left alone
+	assert_eq!(percent_decode("/%a\u{e9}"), "/%a\u{e9}");
+	assert_eq!(percent_decode("/%\u{e9}"), "/%\u{e9}");
+}
+
+#[test]
+fn test_server_survives_bad_requests() {
+	let namespace = Arc::new(Mutex::new(test_namespace()));
+	let server = OscQueryServer::bind("127.0.0.1:0", namespace.clone(), HostInfo::new("test", 9000)).unwrap();
+
+	// a client that never sends anything doesn't hold up the others
+	let _idle = TcpStream::connect(server.local_addr()).unwrap();
+
+	let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+	write!(stream, "GET /%a\u{e9} HTTP/1.1\r\n\r\n").unwrap();
+	let mut response = String::new();
+	stream.read_to_string(&mut response).unwrap();
+	assert!(response.starts_with("HTTP/1.1 404"));
+
+	let client = OscQueryClient::new(server.local_addr()).unwrap();
+	assert_eq!(client.value("/mixer/1/fader").unwrap(), Some(vec!(OscFloat(0.5))));
+	assert!(namespace.lock().is_ok());
+}
+
+#[test]
+fn test_read_request() {
+	let request = "GET /a HTTP/1.1\r\nHost: x\r\n\r\n";
+	assert_eq!(read_request(&mut request.as_bytes()).unwrap(), "GET /a HTTP/1.1\r\n");
+
+	let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE_LEN));
+	assert_eq!(read_request(&mut long.as_bytes()).unwrap_err().kind(), InvalidData);
+
+	let long = format!("GET /a HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_LINE_LEN));
+	assert_eq!(read_request(&mut long.as_bytes()).unwrap_err().kind(), InvalidData);
+
+	let many = format!("GET /a HTTP/1.1\r\n{}\r\n", "X: y\r\n".repeat(MAX_HEADERS + 1));
+	assert_eq!(read_request(&mut many.as_bytes()).unwrap_err().kind(), InvalidData);
+}
+
+#[test]
+fn test_values_from_json() {
+	let values = [Value::from(1), Value::from("a")];
+	assert_eq!(values_from_json(&values, "is").unwrap(), Some(vec!(OscInt(1), OscStr("a".to_string()))));
+	assert!(values_from_json(&[Value::from(1u64 << 40)], "i").is_err());
+	assert!(values_from_json(&[Value::from(-(1i64 << 40))], "i").is_err());
+	assert!(values_from_json(&values, "i").is_err());
+
+	// types OscArg can't hold leave the value unset
+	assert_eq!(values_from_json(&[Value::from(true), Value::from(1)], "Ti").unwrap(), None);
+	assert_eq!(values_from_json(&[Value::from(0.5)], "d").unwrap(), None);
+	assert_eq!(values_from_json(&[Value::from(1)], "h").unwrap(), None);
+
+	let json: Value = serde_json::from_str("{\"FULL_PATH\":\"/a\",\"TYPE\":\"N\",\"VALUE\":[null]}").unwrap();
+	let node = Node::from_json(&json).unwrap();
+	assert_eq!(node.type_tags, Some("N".to_string()));
+	assert_eq!(node.value, None);
+}
+
+#[test]
+fn test_server_drop_when_bound_to_any() {
+	let namespace = Arc::new(Mutex::new(test_namespace()));
+	let server = OscQueryServer::bind("0.0.0.0:0", namespace, HostInfo::new("test", 9000)).unwrap();
+	drop(server);
+}
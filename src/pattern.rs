@@ -0,0 +1,238 @@
+//! Module for matching OSC address patterns against addresses.
+//!
+//! Patterns follow the OSC 1.0 rules: `?` matches any single character, `*`
+//! matches any run of characters, `[abc]` and `[a-z]` match one character from
+//! a set (`[!a-z]` matches one character not in it) and `{foo,bar}` matches any
+//! of the listed strings.  None of the wildcards match across a `/`.
+
+/// Check whether an address matches an OSC address pattern.  A pattern with no
+/// special characters only matches the identical address.
+pub fn addr_matches(pattern: &str, addr: &str) -> bool {
+	addr_captures(pattern, addr).is_some()
+}
+
+/// Match an address against an OSC address pattern, returning the text matched
+/// by each wildcard, set or alternative in the order they appear in the
+/// pattern.  Returns None if the address does not match.
+pub fn addr_captures(pattern: &str, addr: &str) -> Option<Vec<String>> {
+	let pattern: Vec<char> = pattern.chars().collect();
+	let addr: Vec<char> = addr.chars().collect();
+	let mut captures = Vec::new();
+	if match_from(&pattern, &addr, &mut captures) {
+		Some(captures)
+	}
+	else {
+		None
+	}
+}
+
+/// Check whether a string contains any of the OSC pattern special characters.
+pub fn is_pattern(s: &str) -> bool {
+	s.chars().any(|c| matches!(c, '*' | '?' | '[' | ']' | '{' | '}'))
+}
+
+/// Count the captures a pattern produces when it matches: one for each
+/// wildcard, set and alternative.
+pub fn capture_count(pattern: &str) -> usize {
+	let pattern: Vec<char> = pattern.chars().collect();
+	let mut count = 0;
+	let mut i = 0;
+	while i < pattern.len() {
+		let close = match pattern[i] {
+			'[' => pattern[i..].iter().position(|&c| c == ']'),
+			'{' => pattern[i..].iter().position(|&c| c == '}'),
+			_ => None
+		};
+		if let Some(close) = close {
+			count += 1;
+			i += close;
+		}
+		else if pattern[i] == '*' || pattern[i] == '?' {
+			count += 1;
+		}
+		i += 1;
+	}
+	count
+}
+
+// Stars are matched greedily, remembering only the most recent one: on a
+// mismatch its run is extended by a character and matching resumes after it.
+// An earlier star never needs to grow instead, since the later one can take
+// whatever it would have, so the time taken grows with the length of the
+// pattern times the address rather than exponentially with the number of
+// stars.  On failure, captures is left as it was on entry.
+fn match_from(pattern: &[char], addr: &[char], captures: &mut Vec<String>) -> bool {
+	let entry_len = captures.len();
+	let mut p = 0;
+	let mut a = 0;
+	// the pattern index after the last star, the address index its run would
+	// grow to take next, and the number of captures up to and including it
+	let mut star: Option<(usize, usize, usize)> = None;
+
+	loop {
+		let matched = match pattern.get(p) {
+			None => a == addr.len(),
+			Some(&'*') => {
+				captures.push(String::new());
+				p += 1;
+				star = Some((p, a, captures.len()));
+				continue;
+			},
+			Some(&'?') => match addr.get(a) {
+				Some(&c) if c != '/' => {
+					captures.push(c.to_string());
+					true
+				},
+				_ => false
+			},
+			Some(&'[') => {
+				match pattern[p..].iter().position(|&c| c == ']') {
+					Some(close) => match addr.get(a) {
+						Some(&c) if c != '/' && set_contains(&pattern[p + 1..p + close], c) => {
+							captures.push(c.to_string());
+							p += close;
+							true
+						},
+						_ => false
+					},
+					None => {
+						captures.truncate(entry_len);
+						return false;
+					}
+				}
+			},
+			Some(&'{') => {
+				let close = match pattern[p..].iter().position(|&c| c == '}') {
+					Some(i) => p + i,
+					None => {
+						captures.truncate(entry_len);
+						return false;
+					}
+				};
+				// alternatives can differ in length, so each is tried against
+				// the rest of the pattern
+				let rest = &pattern[close + 1..];
+				let found = pattern[p + 1..close].split(|&c| c == ',').any(|alt| {
+					if !addr[a..].starts_with(alt) { return false; }
+					captures.push(alt.iter().collect());
+					if match_from(rest, &addr[a + alt.len()..], captures) { return true; }
+					captures.pop();
+					false
+				});
+				if found { return true; }
+				false
+			},
+			Some(&c) => addr.get(a) == Some(&c)
+		};
+
+		if matched {
+			if p == pattern.len() { return true; }
+			p += 1;
+			a += 1;
+			continue;
+		}
+
+		// grow the last star's run by one character, which can't be a slash
+		match star {
+			Some((star_p, star_a, len)) if star_a < addr.len() && addr[star_a] != '/' => {
+				captures.truncate(len);
+				captures[len - 1].push(addr[star_a]);
+				star = Some((star_p, star_a + 1, len));
+				p = star_p;
+				a = star_a + 1;
+			},
+			_ => {
+				captures.truncate(entry_len);
+				return false;
+			}
+		}
+	}
+}
+
+// check a character against the inside of a [...] set
+fn set_contains(set: &[char], c: char) -> bool {
+	let (negate, set) = match set.first() {
+		Some(&'!') => (true, &set[1..]),
+		_ => (false, set)
+	};
+
+	let mut found = false;
+	let mut i = 0;
+	while i < set.len() {
+		// a dash between two characters is a range, anywhere else it is literal
+		if i + 2 < set.len() && set[i + 1] == '-' {
+			if set[i] <= c && c <= set[i + 2] { found = true; }
+			i += 3;
+		}
+		else {
+			if set[i] == c { found = true; }
+			i += 1;
+		}
+	}
+	found != negate
+}
+
+#[test]
+fn test_addr_matches_literal() {
+	assert!(addr_matches("/a/b", "/a/b"));
+	assert!(!addr_matches("/a/b", "/a/bc"));
+	assert!(!addr_matches("/a/b", "/a"));
+	assert!(addr_matches("", ""));
+}
+
+#[test]
+fn test_addr_matches_wildcards() {
+	assert!(addr_matches("/1/fader*", "/1/fader"));
+	assert!(addr_matches("/1/fader*", "/1/fader12"));
+	assert!(addr_matches("/*/fader", "/12/fader"));
+	assert!(!addr_matches("/*", "/a/b"));
+	assert!(addr_matches("/*/*", "/a/b"));
+	assert!(addr_matches("/a?c", "/abc"));
+	assert!(!addr_matches("/a?c", "/ac"));
+	assert!(!addr_matches("/a?c", "/a/c"));
+}
+
+#[test]
+fn test_addr_matches_sets() {
+	assert!(addr_matches("/ch/[1-4]", "/ch/3"));
+	assert!(!addr_matches("/ch/[1-4]", "/ch/5"));
+	assert!(addr_matches("/ch/[!1-4]", "/ch/5"));
+	assert!(!addr_matches("/ch/[!1-4]", "/ch/2"));
+	assert!(addr_matches("/ch/[abc]", "/ch/b"));
+	assert!(addr_matches("/ch/[a-]", "/ch/-"));
+	assert!(!addr_matches("/ch/[1-4", "/ch/1"));
+}
+
+#[test]
+fn test_addr_matches_alternatives() {
+	assert!(addr_matches("/mixer/{fader,mute}/1", "/mixer/fader/1"));
+	assert!(addr_matches("/mixer/{fader,mute}/1", "/mixer/mute/1"));
+	assert!(!addr_matches("/mixer/{fader,mute}/1", "/mixer/pan/1"));
+	assert!(addr_matches("/{a,ab}c", "/abc"));
+}
+
+#[test]
+fn test_addr_captures() {
+	assert_eq!(addr_captures("/1/fader*", "/1/fader12"), Some(vec!("12".to_string())));
+	assert_eq!(addr_captures("/*/{fader,mute}/[0-9]?", "/a/mute/42"),
+		Some(vec!("a".to_string(), "mute".to_string(), "4".to_string(), "2".to_string())));
+	assert_eq!(addr_captures("/a/b", "/a/b"), Some(vec!()));
+	assert_eq!(addr_captures("/a/*", "/b/c"), None);
+}
+
+#[test]
+fn test_addr_matches_many_stars() {
+	// backtracking into every star would take exponential time here
+	let addr = format!("/{}", "a".repeat(64));
+	assert!(!addr_matches("/*a*a*a*a*a*a*a*a*a*a*a*a*b", &addr));
+	assert!(addr_matches("/*a*a*a*a*a*a*a*a*a*a*a*a*", &addr));
+	assert_eq!(addr_captures("/*b*c", "/abbcc"), Some(vec!("a".to_string(), "bc".to_string())));
+	assert!(!addr_matches("/*b/*c", "/ab/b/c"));
+}
+
+#[test]
+fn test_capture_count() {
+	assert_eq!(capture_count("/a/b"), 0);
+	assert_eq!(capture_count("/*/{fader,mute}/[0-9]?"), 4);
+	assert_eq!(capture_count("/[1-4"), 0);
+}
@@ -0,0 +1,420 @@
+//! Module for deciding which sources a receiver accepts packets from.
+//!
+//! A SourcePolicy admits packets only from listed addresses or subnets, and
+//! caps how many packets per second each source IP may send.  Every decision
+//! is counted, in total and per source, so a device flooding the network can
+//! be found, and rejected packets can be handed to a callback for logging.
+//!
+//! Set a policy on an OscReceiver with set_policy; rejected packets are then
+//! dropped before they are decoded, and recv carries on waiting for one that
+//! is accepted.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::io::{Error, Result};
+use std::io::ErrorKind::InvalidInput;
+use std::time::{Duration, Instant};
+
+use util::Recency;
+
+// at most this many sources are tracked; beyond it, the one heard from least
+// recently is forgotten
+const MAX_TRACKED_SOURCES: usize = 1024;
+
+/// A block of IP addresses, written as `192.168.1.0/24`, `fd00::/8`, or a
+/// single address.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub struct Subnet {
+	addr: IpAddr,
+	prefix_len: u8
+}
+
+impl Subnet {
+
+	/// Constructs the subnet of addresses sharing the first prefix_len bits
+	/// of addr.  Returns an InvalidInput error if the prefix is longer than
+	/// the address.
+	pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Subnet> {
+		let max_len = if addr.is_ipv4() { 32 } else { 128 };
+		if prefix_len > max_len {
+			return Err(Error::new(InvalidInput, format!("Prefix /{} is too long for {}.", prefix_len, addr)));
+		}
+		Ok(Subnet{addr, prefix_len})
+	}
+
+	/// Whether an address is in the subnet.  IPv4 addresses mapped into IPv6
+	/// count as IPv4.
+	pub fn contains(&self, addr: &IpAddr) -> bool {
+		match (self.addr, canonical(*addr)) {
+			(IpAddr::V4(net), IpAddr::V4(a)) =>
+				prefix_matches(u32::from(net) as u128, u32::from(a) as u128, 32, self.prefix_len),
+			(IpAddr::V6(net), IpAddr::V6(a)) =>
+				prefix_matches(u128::from(net), u128::from(a), 128, self.prefix_len),
+			_ => false
+		}
+	}
+}
+
+impl From<IpAddr> for Subnet {
+	fn from(addr: IpAddr) -> Subnet {
+		Subnet{addr, prefix_len: if addr.is_ipv4() { 32 } else { 128 }}
+	}
+}
+
+impl FromStr for Subnet {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<Subnet> {
+		let bad = || Error::new(InvalidInput, format!("Invalid subnet {}.", s));
+		match s.find('/') {
+			Some(i) => {
+				let addr = s[..i].parse().map_err(|_| bad())?;
+				let prefix_len = s[i + 1..].parse().map_err(|_| bad())?;
+				Subnet::new(addr, prefix_len)
+			},
+			None => s.parse::<IpAddr>().map(Subnet::from).map_err(|_| bad())
+		}
+	}
+}
+
+impl fmt::Display for Subnet {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}/{}", self.addr, self.prefix_len)
+	}
+}
+
+fn prefix_matches(net: u128, addr: u128, bits: u8, prefix_len: u8) -> bool {
+	let shift = (bits - prefix_len) as u32;
+	net.checked_shr(shift).unwrap_or(0) == addr.checked_shr(shift).unwrap_or(0)
+}
+
+fn canonical(addr: IpAddr) -> IpAddr {
+	match addr {
+		IpAddr::V6(a) => a.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+		v4 => v4
+	}
+}
+
+/// Why a packet was rejected.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Rejection {
+	/// The source is not in any allowed subnet.
+	NotAllowed,
+	/// The source has sent more than its share of packets.
+	RateLimited
+}
+
+/// Counts of the decisions made by a policy.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub struct PolicyStats {
+	pub accepted: u64,
+	pub not_allowed: u64,
+	pub rate_limited: u64
+}
+
+impl PolicyStats {
+	/// The number of packets decided on.
+	pub fn total(&self) -> u64 {
+		self.accepted + self.not_allowed + self.rate_limited
+	}
+
+	fn count(&mut self, decision: &std::result::Result<(), Rejection>) {
+		match *decision {
+			Ok(()) => self.accepted += 1,
+			Err(Rejection::NotAllowed) => self.not_allowed += 1,
+			Err(Rejection::RateLimited) => self.rate_limited += 1
+		}
+	}
+}
+
+struct Source {
+	stats: PolicyStats,
+	// only kept while there is a rate limit
+	bucket: Option<Bucket>
+}
+
+struct Bucket {
+	tokens: f64,
+	refilled_at: Instant
+}
+
+type RejectHandler = Arc<Mutex<dyn FnMut(SocketAddr, Rejection) + Send>>;
+
+// a rejection whose callback is still to be called, so a receiver can call it
+// once its locks are released
+pub(crate) struct Rejected {
+	source: SocketAddr,
+	rejection: Rejection,
+	on_reject: Option<RejectHandler>
+}
+
+impl Rejected {
+	pub(crate) fn notify(self) {
+		if let Some(on_reject) = self.on_reject {
+			(on_reject.lock().unwrap())(self.source, self.rejection);
+		}
+	}
+}
+
+/// Which sources to accept packets from and how fast.  A new policy
+/// accepts everything and only counts.
+#[derive(Default)]
+pub struct SourcePolicy {
+	allowed: Vec<Subnet>,
+	max_rate: Option<u32>,
+	stats: PolicyStats,
+	sources: HashMap<IpAddr, Source>,
+	// when each source was last tracked, in counts of tracked packets
+	source_seen: Recency<IpAddr>,
+	tracked: u64,
+	on_reject: Option<RejectHandler>
+}
+
+impl SourcePolicy {
+
+	/// Constructs a policy accepting every packet.
+	pub fn new() -> SourcePolicy {
+		SourcePolicy::default()
+	}
+
+	/// Accept packets from a subnet.  Once any subnet is allowed, packets
+	/// from sources outside every allowed subnet are rejected.
+	pub fn allow(&mut self, subnet: Subnet) {
+		self.allowed.push(subnet);
+	}
+
+	/// Limit each source IP to max_rate packets per second, allowing a burst
+	/// of up to a second's worth.  None removes the limit.
+	pub fn set_max_rate(&mut self, max_rate: Option<u32>) {
+		self.max_rate = max_rate;
+		if max_rate.is_none() {
+			for source in self.sources.values_mut() {
+				source.bucket = None;
+			}
+		}
+	}
+
+	/// Call a closure with the source of every rejected packet and why it
+	/// was rejected.  The closure runs on the receiving thread, so should be
+	/// quick.  On a receiver it runs after the receiver's locks are released,
+	/// so it may call back into the receiver.
+	pub fn set_on_reject<F: FnMut(SocketAddr, Rejection) + Send + 'static>(&mut self, on_reject: F) {
+		self.on_reject = Some(Arc::new(Mutex::new(on_reject)));
+	}
+
+	/// Decide whether to accept a packet from a source, counting the
+	/// decision.
+	pub fn check(&mut self, source: SocketAddr) -> std::result::Result<(), Rejection> {
+		self.check_at(source, Instant::now())
+	}
+
+	// decide and count as check does, but leave calling on_reject to the
+	// caller
+	pub(crate) fn check_deferred(&mut self, source: SocketAddr) -> std::result::Result<(), Rejected> {
+		self.count_at(source, Instant::now()).map_err(|rejection| Rejected{source, rejection, on_reject: self.on_reject.clone()})
+	}
+
+	/// Counts of every decision made so far.
+	pub fn stats(&self) -> PolicyStats {
+		self.stats
+	}
+
+	/// Counts of the decisions made for each source IP, busiest first.
+	/// Sources outside the allowed subnets are only counted in stats.  Once
+	/// 1024 sources are tracked, the one heard from least recently is
+	/// forgotten to make room for a new one, along with its counts and rate
+	/// limit.
+	pub fn source_stats(&self) -> Vec<(IpAddr, PolicyStats)> {
+		let mut stats: Vec<(IpAddr, PolicyStats)> = self.sources.iter()
+			.map(|(&ip, s)| (ip, s.stats))
+			.collect();
+		stats.sort_by(|a, b| b.1.total().cmp(&a.1.total()).then(a.0.cmp(&b.0)));
+		stats
+	}
+
+	/// Reset every count to zero.
+	pub fn reset_stats(&mut self) {
+		self.stats = PolicyStats::default();
+		for source in self.sources.values_mut() {
+			source.stats = PolicyStats::default();
+		}
+	}
+
+	fn check_at(&mut self, source: SocketAddr, now: Instant) -> std::result::Result<(), Rejection> {
+		let decision = self.count_at(source, now);
+		if let (Err(rejection), Some(on_reject)) = (decision, self.on_reject.as_ref()) {
+			(on_reject.lock().unwrap())(source, rejection);
+		}
+		decision
+	}
+
+	fn count_at(&mut self, source: SocketAddr, now: Instant) -> std::result::Result<(), Rejection> {
+		let ip = canonical(source.ip());
+		let decision = self.decide(ip, now);
+		self.stats.count(&decision);
+		if let Some(s) = self.sources.get_mut(&ip) {
+			s.stats.count(&decision);
+		}
+		decision
+	}
+
+	fn decide(&mut self, ip: IpAddr, now: Instant) -> std::result::Result<(), Rejection> {
+		// sources turned away aren't tracked, so they can't push out allowed ones
+		if !self.is_allowed(&ip) {
+			return Err(Rejection::NotAllowed);
+		}
+		let max_rate = self.max_rate;
+		let source = self.track(ip);
+
+		let max_rate = match max_rate {
+			Some(r) => r as f64,
+			None => return Ok(())
+		};
+		let bucket = source.bucket.get_or_insert(Bucket{tokens: max_rate, refilled_at: now});
+		let elapsed = now.saturating_duration_since(bucket.refilled_at).as_secs_f64();
+		bucket.tokens = (bucket.tokens + elapsed * max_rate).min(max_rate);
+		bucket.refilled_at = now;
+		if bucket.tokens < 1.0 {
+			return Err(Rejection::RateLimited);
+		}
+		bucket.tokens -= 1.0;
+		Ok(())
+	}
+
+	fn is_allowed(&self, ip: &IpAddr) -> bool {
+		self.allowed.is_empty() || self.allowed.iter().any(|s| s.contains(ip))
+	}
+
+	// make sure a source is tracked, forgetting the one heard from least
+	// recently if there are too many
+	fn track(&mut self, ip: IpAddr) -> &mut Source {
+		if !self.sources.contains_key(&ip) && self.sources.len() >= MAX_TRACKED_SOURCES {
+			if let Some(oldest) = self.source_seen.pop_oldest() {
+				self.sources.remove(&oldest);
+			}
+		}
+		self.tracked += 1;
+		self.source_seen.touch(&ip, self.tracked);
+		self.sources.entry(ip).or_insert_with(|| Source{stats: PolicyStats::default(), bucket: None})
+	}
+}
+
+#[test]
+fn test_subnet() {
+	let lan: Subnet = "192.168.1.0/24".parse().unwrap();
+	assert!(lan.contains(&"192.168.1.77".parse().unwrap()));
+	assert!(!lan.contains(&"192.168.2.1".parse().unwrap()));
+	assert!(lan.contains(&"::ffff:192.168.1.5".parse().unwrap()));
+	assert_eq!(lan.to_string(), "192.168.1.0/24");
+
+	let host: Subnet = "10.0.0.5".parse().unwrap();
+	assert!(host.contains(&"10.0.0.5".parse().unwrap()));
+	assert!(!host.contains(&"10.0.0.6".parse().unwrap()));
+
+	let everything: Subnet = "0.0.0.0/0".parse().unwrap();
+	assert!(everything.contains(&"8.8.8.8".parse().unwrap()));
+	assert!(!everything.contains(&"::1".parse().unwrap()));
+
+	let ula: Subnet = "fd00::/8".parse().unwrap();
+	assert!(ula.contains(&"fd12:3456::1".parse().unwrap()));
+	assert!(!ula.contains(&"fe80::1".parse().unwrap()));
+
+	assert!("10.0.0.0/33".parse::<Subnet>().is_err());
+	assert!("10.0.0.0/x".parse::<Subnet>().is_err());
+	assert!("console".parse::<Subnet>().is_err());
+}
+
+#[test]
+fn test_policy_allow_list() {
+	use std::sync::{Arc, Mutex};
+
+	let rejected = Arc::new(Mutex::new(Vec::new()));
+	let log = rejected.clone();
+	let mut policy = SourcePolicy::new();
+	let controller: SocketAddr = "192.168.1.20:9000".parse().unwrap();
+	let stranger: SocketAddr = "10.1.1.1:9000".parse().unwrap();
+
+	assert_eq!(policy.check(stranger), Ok(()));
+
+	policy.allow("192.168.1.0/24".parse().unwrap());
+	policy.set_on_reject(move |source, why| log.lock().unwrap().push((source, why)));
+	assert_eq!(policy.check(controller), Ok(()));
+	assert_eq!(policy.check(stranger), Err(Rejection::NotAllowed));
+
+	assert_eq!(*rejected.lock().unwrap(), vec!((stranger, Rejection::NotAllowed)));
+	assert_eq!(policy.stats(), PolicyStats{accepted: 2, not_allowed: 1, rate_limited: 0});
+	assert_eq!(policy.source_stats(), vec!(
+		(stranger.ip(), PolicyStats{accepted: 1, not_allowed: 1, rate_limited: 0}),
+		(controller.ip(), PolicyStats{accepted: 1, not_allowed: 0, rate_limited: 0})
+	));
+}
+
+#[test]
+fn test_policy_rate_limit() {
+	let mut policy = SourcePolicy::new();
+	policy.set_max_rate(Some(10));
+	let flooder: SocketAddr = "192.168.1.99:5000".parse().unwrap();
+	let controller: SocketAddr = "192.168.1.20:9000".parse().unwrap();
+	let start = Instant::now();
+
+	// a second's worth in a burst, then nothing until tokens refill
+	let accepted = (0..100).filter(|_| policy.check_at(flooder, start).is_ok()).count();
+	assert_eq!(accepted, 10);
+	// other sources aren't starved
+	assert_eq!(policy.check_at(controller, start), Ok(()));
+
+	let later = start + Duration::from_millis(500);
+	let accepted = (0..100).filter(|_| policy.check_at(flooder, later).is_ok()).count();
+	assert_eq!(accepted, 5);
+
+	assert_eq!(policy.stats(), PolicyStats{accepted: 16, not_allowed: 0, rate_limited: 185});
+	assert_eq!(policy.source_stats()[0], (flooder.ip(), PolicyStats{accepted: 15, not_allowed: 0, rate_limited: 185}));
+
+	policy.reset_stats();
+	assert_eq!(policy.stats(), PolicyStats::default());
+}
+
+#[test]
+fn test_policy_forgets_least_recent_source() {
+	use std::net::Ipv4Addr;
+
+	let mut policy = SourcePolicy::new();
+	let start = Instant::now();
+	let source = |i: u32| SocketAddr::new(IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + i)), 9000);
+
+	// a full table never turns an allowed source away
+	for i in 0..MAX_TRACKED_SOURCES as u32 + 10 {
+		let now = start + Duration::from_millis(i as u64);
+		assert_eq!(policy.check_at(source(i), now), Ok(()));
+		// the first source stays recent
+		assert_eq!(policy.check_at(source(0), now), Ok(()));
+	}
+	let tracked: Vec<IpAddr> = policy.source_stats().into_iter().map(|(ip, _)| ip).collect();
+	assert_eq!(tracked.len(), MAX_TRACKED_SOURCES);
+	assert!(tracked.contains(&source(0).ip()));
+	assert!(!tracked.contains(&source(1).ip()));
+	assert!(tracked.contains(&source(MAX_TRACKED_SOURCES as u32 + 9).ip()));
+	assert!(policy.sources.values().all(|s| s.bucket.is_none()));
+}
+
+#[test]
+fn test_policy_tracks_only_allowed_sources() {
+	use std::net::Ipv4Addr;
+
+	let mut policy = SourcePolicy::new();
+	policy.allow("192.168.1.0/24".parse().unwrap());
+	let now = Instant::now();
+	let controller: SocketAddr = "192.168.1.20:9000".parse().unwrap();
+	assert_eq!(policy.check_at(controller, now), Ok(()));
+
+	// strangers from every address can't push the controller out
+	for i in 0..MAX_TRACKED_SOURCES as u32 * 2 {
+		let stranger = SocketAddr::new(IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + i)), 9000);
+		assert_eq!(policy.check_at(stranger, now), Err(Rejection::NotAllowed));
+	}
+	assert_eq!(policy.source_stats(), vec!((controller.ip(), PolicyStats{accepted: 1, not_allowed: 0, rate_limited: 0})));
+	assert_eq!(policy.stats().not_allowed, MAX_TRACKED_SOURCES as u64 * 2);
+}
@@ -0,0 +1,319 @@
+//! Module for asking devices for values and waiting for their replies.
+//!
+//! Many devices answer a message to an address with a message on the same
+//! address carrying the current value, or on a reply address such as QLab's
+//! `/reply/...`, sent back to the port the request came from.  An OscQuerier
+//! sends requests and receives replies on one socket.  A background thread
+//! receives everything arriving at the socket and hands each reply to the
+//! oldest query waiting for that address and source, so any number of queries
+//! can be in flight from different threads.  Packets that no query is waiting
+//! for can be read with recv, as can the rest of a bundle some of whose
+//! messages were replies.
+
+use std::net::{UdpSocket, SocketAddr, ToSocketAddrs};
+use std::io::{Error, Result};
+use std::io::ErrorKind::{InvalidInput, TimedOut, WouldBlock};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Sender, Receiver, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use data::*;
+use data::OscPacket::*;
+use data::OscArg::*;
+use pattern::addr_matches;
+use receiver::OscReceiver;
+use sender::packet_to_buffer;
+use transport::Transport;
+
+// how often the receive thread checks whether it should stop
+const POLL_INTERVAL_MS: u64 = 100;
+// unmatched packets kept for recv before newer ones are dropped
+const UNMATCHED_BACKLOG: usize = 1024;
+
+struct Waiter {
+	id: usize,
+	pattern: String,
+	source: SocketAddr,
+	reply: Sender<Vec<OscArg>>
+}
+
+/// Sends queries and matches replies on a single socket, UDP unless another
+/// Transport is chosen.  The receive thread stops when the querier is
+/// dropped.
+pub struct OscQuerier<S: Transport = UdpSocket> {
+	receiver: Arc<OscReceiver<S>>,
+	waiters: Arc<Mutex<Vec<Waiter>>>,
+	next_id: AtomicUsize,
+	unmatched: Mutex<Receiver<(OscPacket, SocketAddr)>>,
+	stop: Arc<AtomicBool>,
+	thread: Option<JoinHandle<()>>
+}
+
+impl OscQuerier {
+
+	/// Bind a socket for sending queries and receiving replies, and start the
+	/// receive thread.
+	pub fn bind<T: ToSocketAddrs>(addr: T) -> Result<OscQuerier> {
+		OscQuerier::from_socket(UdpSocket::bind(addr)?)
+	}
+}
+
+impl<S: Transport + Send + Sync + 'static> OscQuerier<S> {
+
+	/// Start the receive thread on a socket that is already bound.  The
+	/// socket should not be used to receive anywhere else.
+	pub fn from_socket(socket: S) -> Result<OscQuerier<S>> {
+		let receiver = Arc::new(OscReceiver::from_socket(socket));
+
+		let waiters: Arc<Mutex<Vec<Waiter>>> = Arc::new(Mutex::new(Vec::new()));
+		let stop = Arc::new(AtomicBool::new(false));
+		let (unmatched_tx, unmatched_rx) = sync_channel(UNMATCHED_BACKLOG);
+
+		let thread_receiver = receiver.clone();
+		let thread_waiters = waiters.clone();
+		let thread_stop = stop.clone();
+		let thread = thread::spawn(move || {
+			let timeout = Some(Duration::from_millis(POLL_INTERVAL_MS));
+			while !thread_stop.load(Ordering::SeqCst) {
+				match thread_receiver.recv_from(timeout) {
+					Ok((packet, source)) => {
+						if let Some(rest) = dispatch(&thread_waiters, packet, source) {
+							let _ = unmatched_tx.try_send((rest, source));
+						}
+					},
+					// datagrams that fail to decode are skipped
+					Err(ref e) if e.kind() == WouldBlock || e.kind() == TimedOut || e.kind() == InvalidInput => (),
+					Err(_) => break
+				}
+			}
+		});
+
+		Ok(OscQuerier{
+			receiver,
+			waiters,
+			next_id: AtomicUsize::new(0),
+			unmatched: Mutex::new(unmatched_rx),
+			stop,
+			thread: Some(thread)
+		})
+	}
+
+	/// The local address the querier is bound to.
+	pub fn local_addr(&self) -> Result<SocketAddr> {
+		self.receiver.local_addr()
+	}
+
+	/// Send a packet without waiting for a reply.
+	pub fn send_to<A: ToSocketAddrs>(&self, packet: OscPacket, dest: A) -> Result<usize> {
+		let dest = self.resolve(dest)?;
+		self.receiver.get_ref().send_to(&packet_to_buffer(packet)[4..], dest)
+	}
+
+	/// Send a message to a device and wait for it to reply on the same
+	/// address.  Returns the arguments of the reply, or a TimedOut error.
+	pub fn query<A: ToSocketAddrs>(&self, dest: A, addr: &str, args: Vec<OscArg>, timeout: Duration) -> Result<Vec<OscArg>> {
+		let request = OscMessage{addr: addr.to_string(), args};
+		self.query_reply(dest, request, addr, timeout)
+	}
+
+	/// Send a packet to a device and wait for a reply on an address matching
+	/// an OSC address pattern.  Returns the arguments of the reply, or a
+	/// TimedOut error.
+	pub fn query_reply<A: ToSocketAddrs>(&self, dest: A, request: OscPacket, reply_pattern: &str, timeout: Duration) -> Result<Vec<OscArg>> {
+		let dest = self.resolve(dest)?;
+		let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+		let (tx, rx) = channel();
+		self.waiters.lock().unwrap().push(Waiter{id, pattern: reply_pattern.to_string(), source: dest, reply: tx});
+
+		let result = self.receiver.get_ref().send_to(&packet_to_buffer(request)[4..], dest)
+			.and_then(|_| rx.recv_timeout(timeout).map_err(|e| match e {
+				RecvTimeoutError::Timeout => Error::new(TimedOut, format!("No reply on {} from {}.", reply_pattern, dest)),
+				RecvTimeoutError::Disconnected => Error::other("Receive thread stopped.")
+			}));
+
+		if result.is_err() {
+			// under the lock dispatch holds, so a reply either finds the waiter
+			// or goes to recv; if it found it first, the reply is waiting
+			let mut waiters = self.waiters.lock().unwrap();
+			match waiters.iter().position(|w| w.id == id) {
+				Some(i) => { waiters.remove(i); },
+				None => {
+					if let Ok(args) = rx.try_recv() {
+						return Ok(args);
+					}
+				}
+			}
+		}
+		result
+	}
+
+	/// Receive a packet that no query was waiting for, along with the address
+	/// it came from.  Blocks and times out in the same way as
+	/// OscReceiver::recv.
+	pub fn recv(&self, timeout: Option<Duration>) -> Result<(OscPacket, SocketAddr)> {
+		let unmatched = self.unmatched.lock().unwrap();
+		match timeout {
+			Some(t) => unmatched.recv_timeout(t).map_err(|e| match e {
+				RecvTimeoutError::Timeout => Error::new(TimedOut, "Timed out waiting for a packet."),
+				RecvTimeoutError::Disconnected => Error::other("Receive thread stopped.")
+			}),
+			None => unmatched.recv().map_err(|_| Error::other("Receive thread stopped."))
+		}
+	}
+
+	// pick an address of the same family as the socket
+	fn resolve<A: ToSocketAddrs>(&self, dest: A) -> Result<SocketAddr> {
+		let ipv4 = self.local_addr()?.is_ipv4();
+		dest.to_socket_addrs()?
+			.find(|a| a.is_ipv4() == ipv4)
+			.ok_or_else(|| Error::new(InvalidInput, "No destination address usable from this socket."))
+	}
+}
+
+impl<S: Transport> Drop for OscQuerier<S> {
+	fn drop(&mut self) {
+		self.stop.store(true, Ordering::SeqCst);
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+	}
+}
+
+// hand every message in a packet to the oldest matching waiter; returns what
+// no waiter took, with bundles keeping their time tags, or None if nothing is
+// left
+fn dispatch(waiters: &Mutex<Vec<Waiter>>, packet: OscPacket, source: SocketAddr) -> Option<OscPacket> {
+	match packet {
+		OscMessage{addr, args} => {
+			let mut waiters = waiters.lock().unwrap();
+			let found = waiters.iter().position(|w| w.source == source && addr_matches(&w.pattern, &addr));
+			match found {
+				Some(i) => {
+					let waiter = waiters.remove(i);
+					let _ = waiter.reply.send(args);
+					None
+				},
+				None => Some(OscMessage{addr, args})
+			}
+		},
+		OscBundle{time_tag, conts} => {
+			// every message is offered, even after one is taken
+			let rest: Vec<OscPacket> = conts.into_iter().filter_map(|p| dispatch(waiters, p, source)).collect();
+			if rest.is_empty() { None } else { Some(OscBundle{time_tag, conts: rest}) }
+		}
+	}
+}
+
+#[test]
+fn test_query() {
+	use receiver::{UDP_BUFFER_SIZE, datagram_to_packet};
+
+	// a device answering /get/<name> with /value/<name> and echoing anything
+	// else, from the port it listens on
+	let device = UdpSocket::bind("127.0.0.1:0").unwrap();
+	let device_addr = device.local_addr().unwrap();
+	let device_thread = thread::spawn(move || {
+		let mut buf = vec![0u8; UDP_BUFFER_SIZE];
+		for _ in 0..4 {
+			let (n, source) = device.recv_from(&mut buf).unwrap();
+			let reply = match datagram_to_packet(&buf[..n]).unwrap() {
+				OscMessage{ref addr, ..} if addr.starts_with("/get/") => {
+					OscMessage{addr: addr.replace("/get/", "/value/"), args: vec!(OscStr(addr[5..].to_string()))}
+				},
+				other => other
+			};
+			device.send_to(&packet_to_buffer(reply)[4..], source).unwrap();
+		}
+	});
+
+	let querier = OscQuerier::bind("127.0.0.1:0").unwrap();
+	let timeout = Duration::from_secs(5);
+	thread::scope(|s| {
+		let echo = s.spawn(|| querier.query(device_addr, "/fader", vec!(OscInt(1)), timeout).unwrap());
+		let names: Vec<_> = ["a", "b"].iter()
+			.map(|name| {
+				let querier = &querier;
+				s.spawn(move || {
+					let request = OscMessage{addr: format!("/get/{}", name), args: vec!()};
+					querier.query_reply(device_addr, request, "/value/*", timeout).unwrap()
+				})
+			})
+			.collect();
+		assert_eq!(echo.join().unwrap(), vec!(OscInt(1)));
+		let mut replies: Vec<_> = names.into_iter().map(|h| h.join().unwrap()).collect();
+		replies.sort_by_key(|r| format!("{:?}", r));
+		assert_eq!(replies, vec!(vec!(OscStr("a".to_string())), vec!(OscStr("b".to_string()))));
+	});
+
+	// unanswered packets go to recv
+	querier.send_to(OscMessage{addr: "/unsolicited".to_string(), args: vec!()}, device_addr).unwrap();
+	let (packet, source) = querier.recv(Some(timeout)).unwrap();
+	assert_eq!(packet, OscMessage{addr: "/unsolicited".to_string(), args: vec!()});
+	assert_eq!(source, device_addr);
+	device_thread.join().unwrap();
+
+	// nobody answers now
+	let err = querier.query(device_addr, "/fader", vec!(), Duration::from_millis(50)).unwrap_err();
+	assert_eq!(err.kind(), TimedOut);
+	assert!(querier.waiters.lock().unwrap().is_empty());
+}
+
+#[test]
+fn test_late_reply_goes_to_recv() {
+	let device = UdpSocket::bind("127.0.0.1:0").unwrap();
+	let querier = OscQuerier::bind("127.0.0.1:0").unwrap();
+	let err = querier.query(device.local_addr().unwrap(), "/fader", vec!(), Duration::from_millis(50)).unwrap_err();
+	assert_eq!(err.kind(), TimedOut);
+
+	let reply = OscMessage{addr: "/fader".to_string(), args: vec!(OscFloat(0.5))};
+	device.send_to(&packet_to_buffer(reply.clone())[4..], querier.local_addr().unwrap()).unwrap();
+	assert_eq!(querier.recv(Some(Duration::from_secs(5))).unwrap().0, reply);
+}
+
+#[test]
+fn test_bundle_leftovers_go_to_recv() {
+	let device = UdpSocket::bind("127.0.0.1:0").unwrap();
+	let device_addr = device.local_addr().unwrap();
+	let querier = OscQuerier::bind("127.0.0.1:0").unwrap();
+	let timeout = Duration::from_secs(5);
+
+	let status = OscMessage{addr: "/status".to_string(), args: vec!(OscStr("ok".to_string()))};
+	let reply = OscBundle{time_tag: (0, 1), conts: vec!(
+		OscMessage{addr: "/fader".to_string(), args: vec!(OscFloat(0.5))},
+		status.clone()
+	)};
+	thread::scope(|s| {
+		s.spawn(|| {
+			let mut buf = [0u8; 64];
+			let (_, source) = device.recv_from(&mut buf).unwrap();
+			device.send_to(&packet_to_buffer(reply)[4..], source).unwrap();
+		});
+		assert_eq!(querier.query(device_addr, "/fader", vec!(), timeout).unwrap(), vec!(OscFloat(0.5)));
+	});
+
+	let (packet, source) = querier.recv(Some(timeout)).unwrap();
+	assert_eq!(packet, OscBundle{time_tag: (0, 1), conts: vec!(status)});
+	assert_eq!(source, device_addr);
+}
+
+#[test]
+fn test_query_over_loopback() {
+	use socket::OscSocket;
+	use transport::LoopbackNetwork;
+
+	let network = LoopbackNetwork::new();
+	let device = OscSocket::from_socket(network.bind("127.0.0.1:0").unwrap());
+	let device_addr = device.local_addr().unwrap();
+	let querier = OscQuerier::from_socket(network.bind("127.0.0.1:0").unwrap()).unwrap();
+	let timeout = Duration::from_secs(5);
+
+	thread::scope(|s| {
+		s.spawn(|| {
+			let (packet, source) = device.recv_from(Some(timeout)).unwrap();
+			device.send_to(packet, source).unwrap();
+		});
+		assert_eq!(querier.query(device_addr, "/fader", vec!(OscInt(1)), timeout).unwrap(), vec!(OscInt(1)));
+	});
+}
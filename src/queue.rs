@@ -0,0 +1,424 @@
+//! Module for throttling outgoing OSC to a single destination.
+//!
+//! An OscSendQueue sits in front of an OscSender.  Messages to an address that
+//! was sent to less than the coalescing interval ago are held back, and a newer
+//! message to the same address replaces the one waiting, so dragging a fader
+//! sends at most one value per interval and always ends on the latest one.  An
+//! optional rate cap spaces out everything sent to the destination.
+//!
+//! Messages marked as triggers, either by pattern or by sending them with
+//! send_trigger, skip the queue and the rate cap, as do bundles.
+//!
+//! Held messages are sent by flush_due, which should be called regularly, for
+//! instance whenever next_due says something is ready.
+//!
+//! With bundling turned on, every message is held until the next flush_due,
+//! which gathers the due messages into as few bundles as fit in the target
+//! datagram size.  The rate cap then counts datagrams rather than messages.
+
+use std::net::{UdpSocket, ToSocketAddrs};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, Result};
+use std::io::ErrorKind::InvalidInput;
+use std::time::{Duration, Instant};
+
+use data::*;
+use data::OscPacket::*;
+use data::OscArg::*;
+use pattern::addr_matches;
+use sender::OscSender;
+use transport::Transport;
+use bundle::{DEFAULT_MAX_DATAGRAM, IMMEDIATE, BUNDLE_HEADER_SIZE, ELEMENT_SIZE_SIZE, encoded_len, pack};
+
+const DEFAULT_INTERVAL_MS: u64 = 20;
+
+struct Pending {
+	addr: String,
+	args: Vec<OscArg>
+}
+
+/// Coalesces messages by address and caps the rate they are sent at.  Sends
+/// over UDP unless the sender uses another Transport.
+pub struct OscSendQueue<T: ToSocketAddrs, S: Transport = UdpSocket> {
+	sender: OscSender<T, S>,
+	interval: Duration,
+	spacing: Option<Duration>,
+	triggers: Vec<String>,
+	// waiting messages, oldest first
+	pending: VecDeque<Pending>,
+	last_sent: HashMap<String, Instant>,
+	next_send: Option<Instant>,
+	max_datagram: Option<usize>
+}
+
+impl<T: ToSocketAddrs, S: Transport> OscSendQueue<T, S> {
+
+	/// Constructs a new OscSendQueue with a 20ms coalescing interval and no
+	/// rate cap.
+	pub fn new(sender: OscSender<T, S>) -> Self {
+		OscSendQueue{
+			sender,
+			interval: Duration::from_millis(DEFAULT_INTERVAL_MS),
+			spacing: None,
+			triggers: Vec::new(),
+			pending: VecDeque::new(),
+			last_sent: HashMap::new(),
+			next_send: None,
+			max_datagram: None
+		}
+	}
+
+	/// Set the minimum time between two messages to the same address.
+	pub fn set_interval(&mut self, interval: Duration) {
+		self.interval = interval;
+	}
+
+	/// Cap the number of queued messages sent per second, or remove the cap.
+	pub fn set_max_rate(&mut self, per_second: Option<u32>) {
+		self.spacing = per_second.filter(|&r| r > 0).map(|r| Duration::from_secs(1) / r);
+	}
+
+	/// Gather held messages into bundles of at most max_datagram bytes, or
+	/// send them one by one.
+	pub fn set_bundling(&mut self, max_datagram: Option<usize>) {
+		self.max_datagram = max_datagram;
+	}
+
+	/// Mark every address matching an OSC address pattern as a trigger.
+	pub fn trigger(&mut self, pattern: &str) {
+		self.triggers.push(pattern.to_string());
+	}
+
+	/// Send a packet, or hold it back if its address was sent to too recently
+	/// or the rate cap is reached.  Returns true if it was sent immediately.
+	/// When bundling, a message too large to fit in a datagram is an error.
+	pub fn send(&mut self, packet: OscPacket) -> Result<bool> {
+		self.send_at(packet, Instant::now())
+	}
+
+	/// Send a packet straight away, bypassing coalescing and the rate cap.
+	pub fn send_trigger(&mut self, packet: OscPacket) -> Result<()> {
+		self.sender.send(packet).map(|_| ())
+	}
+
+	/// Send every held message that is due.  Returns the number sent.
+	pub fn flush_due(&mut self) -> Result<usize> {
+		self.flush_due_at(Instant::now())
+	}
+
+	/// Send every held message now, ignoring the interval and the rate cap.
+	/// Returns the number sent.  Messages that fail to send stay held.
+	pub fn flush(&mut self) -> Result<usize> {
+		let now = Instant::now();
+		if self.max_datagram.is_some() {
+			let held = self.pending.drain(..).collect();
+			return self.send_bundles(held, now);
+		}
+		let mut count = 0;
+		while let Some(p) = self.pending.pop_front() {
+			if let Err(e) = self.send_now(&p, now) {
+				self.pending.push_front(p);
+				return Err(e);
+			}
+			count += 1;
+		}
+		Ok(count)
+	}
+
+	/// The earliest time a held message can be sent, or None if nothing is
+	/// held.
+	pub fn next_due(&self) -> Option<Instant> {
+		let now = Instant::now();
+		let due = self.pending.iter().map(|p| self.due(&p.addr).unwrap_or(now)).min()?;
+		Some(match self.next_send {
+			Some(t) if t > due => t,
+			_ => due
+		})
+	}
+
+	/// The number of messages being held.
+	pub fn pending(&self) -> usize {
+		self.pending.len()
+	}
+
+	fn send_at(&mut self, packet: OscPacket, now: Instant) -> Result<bool> {
+		let size = encoded_len(&packet);
+		let (addr, args) = match packet {
+			OscMessage{ref addr, ..} if self.is_trigger(addr) => {
+				self.send_trigger(packet)?;
+				return Ok(true);
+			},
+			OscMessage{addr, args} => (addr, args),
+			bundle => {
+				self.send_trigger(bundle)?;
+				return Ok(true);
+			}
+		};
+
+		// when bundling, every message has to fit in a bundle on its own
+		if let Some(max) = self.max_datagram {
+			let space = max.saturating_sub(BUNDLE_HEADER_SIZE + ELEMENT_SIZE_SIZE);
+			if size > space {
+				return Err(Error::new(InvalidInput,
+					format!("Message to {} is {} bytes, too large for a {} byte datagram.", addr, size, max)));
+			}
+		}
+
+		if let Some(p) = self.pending.iter_mut().find(|p| p.addr == addr) {
+			p.args = args;
+			return Ok(false);
+		}
+		if self.max_datagram.is_some() {
+			self.pending.push_back(Pending{addr, args});
+			return Ok(false);
+		}
+		// with a rate cap, held messages go first
+		let queue_empty = self.spacing.is_none() || self.pending.is_empty();
+		if queue_empty && self.is_due(&addr, now) && self.rate_allows(now) {
+			self.send_now(&Pending{addr, args}, now)?;
+			return Ok(true);
+		}
+		self.pending.push_back(Pending{addr, args});
+		Ok(false)
+	}
+
+	fn flush_due_at(&mut self, now: Instant) -> Result<usize> {
+		// addresses sent to longer than an interval ago are due anyway
+		let interval = self.interval;
+		self.last_sent.retain(|_, &mut t| t + interval > now);
+
+		if self.max_datagram.is_some() {
+			if !self.rate_allows(now) {
+				return Ok(0);
+			}
+			let pending = std::mem::take(&mut self.pending);
+			let (due, held): (VecDeque<Pending>, VecDeque<Pending>) =
+				pending.into_iter().partition(|p| self.is_due(&p.addr, now));
+			self.pending = held;
+			return self.send_bundles(due.into(), now);
+		}
+
+		// send what is due in order, keeping the rest in a new queue
+		let mut held = VecDeque::with_capacity(self.pending.len());
+		let mut count = 0;
+		let mut result = Ok(());
+		while self.rate_allows(now) {
+			let p = match self.pending.pop_front() {
+				Some(p) => p,
+				None => break
+			};
+			if !self.is_due(&p.addr, now) {
+				held.push_back(p);
+				continue;
+			}
+			result = self.send_now(&p, now);
+			if result.is_err() {
+				held.push_back(p);
+				break;
+			}
+			count += 1;
+		}
+		held.append(&mut self.pending);
+		self.pending = held;
+		result.map(|_| count)
+	}
+
+	fn send_now(&mut self, p: &Pending, now: Instant) -> Result<()> {
+		self.sender.send(OscMessage{addr: p.addr.clone(), args: p.args.clone()})?;
+		self.last_sent.insert(p.addr.clone(), now);
+		self.next_send = self.spacing.map(|s| now + s);
+		Ok(())
+	}
+
+	// send messages as bundles, returning the number of datagrams; the
+	// messages of a datagram that fails to send, and of every one after it,
+	// go back to the front of the queue
+	fn send_bundles(&mut self, messages: Vec<Pending>, now: Instant) -> Result<usize> {
+		if messages.is_empty() {
+			return Ok(0);
+		}
+		let max = self.max_datagram.unwrap_or(DEFAULT_MAX_DATAGRAM);
+		let packets = messages.iter().map(|p| OscMessage{addr: p.addr.clone(), args: p.args.clone()}).collect();
+
+		// every message fits in a datagram on its own, so bundles hold whole
+		// messages in order
+		let bundles = match pack(packets, IMMEDIATE, max) {
+			Ok(b) => b,
+			Err(e) => {
+				self.requeue(messages);
+				return Err(e);
+			}
+		};
+		let mut count = 0;
+		let mut sent = 0;
+		let mut result = Ok(());
+		for bundle in bundles {
+			let n = match bundle {
+				OscBundle{ref conts, ..} => conts.len(),
+				_ => 1
+			};
+			result = self.sender.send(bundle).map(|_| ());
+			if result.is_err() { break; }
+			for p in &messages[sent..sent + n] {
+				self.last_sent.insert(p.addr.clone(), now);
+			}
+			sent += n;
+			count += 1;
+		}
+		if count > 0 {
+			self.next_send = self.spacing.map(|s| now + s * count as u32);
+		}
+		self.requeue(messages.into_iter().skip(sent).collect());
+		result.map(|_| count)
+	}
+
+	// put messages back at the front of the queue, in order
+	fn requeue(&mut self, messages: Vec<Pending>) {
+		for p in messages.into_iter().rev() {
+			self.pending.push_front(p);
+		}
+	}
+
+	fn due(&self, addr: &str) -> Option<Instant> {
+		self.last_sent.get(addr).map(|&t| t + self.interval)
+	}
+
+	fn is_due(&self, addr: &str, now: Instant) -> bool {
+		self.due(addr).is_none_or(|t| t <= now)
+	}
+
+	fn rate_allows(&self, now: Instant) -> bool {
+		self.next_send.is_none_or(|t| t <= now)
+	}
+
+	fn is_trigger(&self, addr: &str) -> bool {
+		self.triggers.iter().any(|p| addr_matches(p, addr))
+	}
+}
+
+#[cfg(test)]
+fn queue_pair() -> (OscSendQueue<std::net::SocketAddr>, ::receiver::OscReceiver) {
+	use std::net::SocketAddr;
+	use receiver::OscReceiver;
+
+	let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+	let receiver = OscReceiver::new(localhost).unwrap();
+	let sender = OscSender::new(localhost, receiver.local_addr().unwrap()).unwrap();
+	(OscSendQueue::new(sender), receiver)
+}
+
+#[cfg(test)]
+fn fader(v: f32) -> OscPacket {
+	OscMessage{addr: "/fader".to_string(), args: vec!(OscFloat(v))}
+}
+
+#[test]
+fn test_coalesce_by_address() {
+	let timeout = Some(Duration::from_secs(5));
+	let (mut queue, receiver) = queue_pair();
+	queue.set_interval(Duration::from_millis(100));
+	let start = Instant::now();
+
+	assert!(queue.send_at(fader(0.1), start).unwrap());
+	assert!(!queue.send_at(fader(0.2), start).unwrap());
+	assert!(!queue.send_at(fader(0.3), start + Duration::from_millis(50)).unwrap());
+	assert_eq!(queue.pending(), 1);
+
+	// other addresses aren't held up
+	let other = OscMessage{addr: "/other".to_string(), args: vec!()};
+	assert!(queue.send_at(other.clone(), start).unwrap());
+
+	assert_eq!(queue.flush_due_at(start + Duration::from_millis(50)).unwrap(), 0);
+	assert_eq!(queue.flush_due_at(start + Duration::from_millis(100)).unwrap(), 1);
+	assert_eq!(queue.pending(), 0);
+
+	assert_eq!(receiver.recv(timeout).unwrap(), fader(0.1));
+	assert_eq!(receiver.recv(timeout).unwrap(), other);
+	assert_eq!(receiver.recv(timeout).unwrap(), fader(0.3));
+}
+
+#[test]
+fn test_rate_cap_and_triggers() {
+	let timeout = Some(Duration::from_secs(5));
+	let (mut queue, receiver) = queue_pair();
+	queue.set_interval(Duration::from_millis(0));
+	queue.set_max_rate(Some(10));
+	queue.trigger("/go");
+	let start = Instant::now();
+
+	let msg = |addr: &str| OscMessage{addr: addr.to_string(), args: vec!(OscInt(1))};
+	assert!(queue.send_at(msg("/a"), start).unwrap());
+	assert!(!queue.send_at(msg("/b"), start).unwrap());
+	assert!(!queue.send_at(msg("/c"), start).unwrap());
+	assert!(queue.send_at(msg("/go"), start).unwrap());
+	queue.send_trigger(msg("/b/now")).unwrap();
+
+	assert_eq!(queue.flush_due_at(start + Duration::from_millis(50)).unwrap(), 0);
+	assert_eq!(queue.flush_due_at(start + Duration::from_millis(100)).unwrap(), 1);
+	assert_eq!(queue.pending(), 1);
+	assert_eq!(queue.flush().unwrap(), 1);
+
+	let received: Vec<OscPacket> = (0..5).map(|_| receiver.recv(timeout).unwrap()).collect();
+	assert_eq!(received, vec!(msg("/a"), msg("/go"), msg("/b/now"), msg("/b"), msg("/c")));
+}
+
+#[test]
+fn test_bundling() {
+	let timeout = Some(Duration::from_secs(5));
+	let (mut queue, receiver) = queue_pair();
+	queue.set_bundling(Some(100));
+	let start = Instant::now();
+
+	let msg = |i: i32| OscMessage{addr: format!("/{}", i), args: vec!(OscInt(i))};
+	for i in 0..6 {
+		assert!(!queue.send_at(msg(i), start).unwrap());
+	}
+	let big = OscMessage{addr: "/big".to_string(), args: vec!(OscBlob(vec!(0; 100)))};
+	assert!(queue.send_at(big, start).is_err());
+	let big_update = OscMessage{addr: "/0".to_string(), args: vec!(OscBlob(vec!(0; 100)))};
+	assert!(queue.send_at(big_update, start).is_err());
+	assert_eq!(queue.pending(), 6);
+
+	// 16 bytes of header plus 16 per message fits 5 messages in 100 bytes
+	assert_eq!(queue.flush_due_at(start).unwrap(), 2);
+	assert_eq!(receiver.recv(timeout).unwrap(), OscBundle{time_tag: IMMEDIATE, conts: (0..5).map(msg).collect()});
+	assert_eq!(receiver.recv(timeout).unwrap(), OscBundle{time_tag: IMMEDIATE, conts: vec!(msg(5))});
+}
+
+#[test]
+fn test_failed_sends_stay_held() {
+	use std::net::SocketAddr;
+
+	// an IPv4 socket can't send to an IPv6 address
+	let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+	let sender = OscSender::new(local, "[::1]:9".parse().unwrap()).unwrap();
+	let mut queue = OscSendQueue::new(sender);
+	queue.set_bundling(Some(100));
+	let start = Instant::now();
+
+	let other = OscMessage{addr: "/other".to_string(), args: vec!()};
+	assert!(!queue.send_at(fader(0.1), start).unwrap());
+	assert!(!queue.send_at(other, start).unwrap());
+	assert!(queue.flush_due_at(start).is_err());
+	assert_eq!(queue.pending(), 2);
+	assert!(queue.last_sent.is_empty());
+
+	queue.set_bundling(None);
+	assert!(queue.flush().is_err());
+	assert!(queue.flush_due_at(start).is_err());
+	assert_eq!(queue.pending(), 2);
+	assert!(queue.last_sent.is_empty());
+}
+
+#[test]
+fn test_prune_last_sent() {
+	let (mut queue, _receiver) = queue_pair();
+	queue.set_interval(Duration::from_millis(100));
+	let start = Instant::now();
+
+	assert!(queue.send_at(fader(0.1), start).unwrap());
+	assert_eq!(queue.flush_due_at(start + Duration::from_millis(50)).unwrap(), 0);
+	assert_eq!(queue.last_sent.len(), 1);
+	assert_eq!(queue.flush_due_at(start + Duration::from_millis(100)).unwrap(), 0);
+	assert!(queue.last_sent.is_empty());
+}
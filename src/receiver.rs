@@ -5,10 +5,16 @@ extern crate byteorder;
 
 use std::net::UdpSocket;
 use std::net::SocketAddrV4;
+use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
 
 use std::io::{Error, Result, BufReader, BufWriter};
-use std::io::ErrorKind::{InvalidInput};
+use std::io::ErrorKind::{InvalidInput, TimedOut, WouldBlock};
+use std::sync::Mutex;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, RawSocket};
 use std::str::*;
 use std::io::prelude::*;
 
@@ -16,33 +22,54 @@ use std::io::prelude::*;
 use self::byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use self::byteorder::Error::{Io, UnexpectedEOF};
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::net::IpAddr;
 
 use data::*;
 use data::OscPacket::*;
 use data::OscArg::*;
 
 use sender::packet_to_buffer;
+#[cfg(all(feature = "batch", target_os = "linux"))]
+use batch::RecvBatch;
+use transport::Transport;
+use policy::{SourcePolicy, PolicyStats, Rejected};
+use stats::{ReceiverStats, DecodeError, DecodeErrorKind};
+use stats::DecodeErrorKind::*;
 
 use util::*;
 
-// Max size of UDP buffer; apparently 1536 is a common UDP MTU.
-const UDP_BUFFER_SIZE: usize = 1536;
+/// Max size of UDP buffer; apparently 1536 is a common UDP MTU.
+pub const UDP_BUFFER_SIZE: usize = 1536;
 
-// static BUNDLE_ID: &'static str = "#bundle"; // perhaps we may want to use this some other day
+const BUNDLE_ID: &[u8] = b"#bundle\0";
 const BUNDLE_FIRST_CHAR: char = '#';
 
 // smallest packet is a 0 character address (4 bytes) and a comma for type tag (4 bytes)
 const MIN_OSC_PACKET_SIZE: usize = 8;
-const PACKET_SIZE_ERR: &'static str = "Packet with less than 8 bytes.";
+const PACKET_SIZE_ERR: &str = "Packet with less than 8 bytes.";
+
+// bundles nested deeper than this are rejected, so a crafted packet can't
+// run the decoder out of stack
+const MAX_BUNDLE_DEPTH: usize = 32;
 
-// we may want to generalize this beyond UDP later
 /// Structure which contains the port used to receive Osc packets, and handles
-/// the task of interpreting those packets as valid Osc.
-pub struct OscReceiver {
+/// the task of interpreting those packets as valid Osc.  Receives over UDP
+/// unless given another Transport.
+pub struct OscReceiver<S: Transport = UdpSocket> {
+
+	socket: S,
+	// the read mode last set on the socket, so it is only changed when needed
+	mode: Mutex<Option<ReadMode>>,
+	policy: Mutex<Option<SourcePolicy>>,
+	stats: Mutex<ReceiverStats>
 
-	socket: UdpSocket
+}
 
+#[derive(Clone,Copy,PartialEq)]
+enum ReadMode {
+	Blocking(Option<Duration>),
+	NonBlocking
 }
 
 impl OscReceiver {
@@ -51,46 +78,247 @@ impl OscReceiver {
 	/// error occurred when trying to bind to the socket.
 	pub fn new<T:ToSocketAddrs>(addr: T) -> Result<OscReceiver> {
 		match UdpSocket::bind(addr) {
-		    Ok(s) => Ok(OscReceiver{socket: s}),
-		    Err(e) => return Err(e),
+		    Ok(s) => Ok(OscReceiver::from_socket(s)),
+		    Err(e) => Err(e),
 		}
 	}
 
+	/// Receive as many packets as are waiting, up to the capacity of the
+	/// batch, with a single system call.  Blocks for the first packet in the
+	/// same way as recv.  Packets that fail to decode, or that the policy
+	/// rejects, are skipped.
+	#[cfg(all(feature = "batch", target_os = "linux"))]
+	pub fn recv_batch(&self, batch: &mut RecvBatch, timeout: Option<Duration>) -> Result<Vec<(OscPacket, SocketAddr)>> {
+		self.set_mode(ReadMode::Blocking(timeout))?;
+		batch.recv(&self.socket)?;
+		Ok(batch.datagrams().into_iter()
+			.filter_map(|(buf, source)| {
+				let source = source?;
+				self.process(buf, source)?.ok().map(|packet| (packet, source))
+			})
+			.collect())
+	}
+}
+
+impl<S: Transport> OscReceiver<S> {
+
+	/// Constructs a new OscReceiver from a socket that is already bound.
+	pub fn from_socket(socket: S) -> OscReceiver<S> {
+		OscReceiver{socket, mode: Mutex::new(None), policy: Mutex::new(None), stats: Mutex::new(ReceiverStats::default())}
+	}
+
 	/// Receive a Osc packet.  Blocks until a packet is available at the port.
 	/// Can optionally specify a timeout on the blocking read.
 	pub fn recv(&self, timeout: Option<Duration>) -> Result<OscPacket> {
+		self.recv_from(timeout).map(|(packet, _)| packet)
+	}
 
-		// initialize a receive buffer
-		let buf = &mut[0; UDP_BUFFER_SIZE];
+	/// Receive a Osc packet along with the address it was sent from.  Blocks
+	/// in the same way as recv.
+	pub fn recv_from(&self, timeout: Option<Duration>) -> Result<(OscPacket, SocketAddr)> {
 
-		let packet_len;
+		// initialize a receive buffer, a byte bigger to tell when a datagram
+		// doesn't fit
+		let buf = &mut[0; UDP_BUFFER_SIZE + 1];
 
-		try!(self.socket.set_read_timeout(timeout));
+		let deadline = timeout.map(|t| Instant::now() + t);
+		let mut wait = timeout;
+		loop {
+			self.set_mode(ReadMode::Blocking(wait))?;
+
+			let (packet_len, source) = self.socket.recv_from(buf)?;
+
+			if let Some(result) = self.process(&buf[..packet_len], source) {
+				return result.map(|packet| (packet, source));
+			}
 
-		match self.socket.recv_from(buf) {
-			// ignoring source address from now, can bind it here if desired
-			// if we didn't receive enough data, throw an error
-			Ok((num, _)) if num < MIN_OSC_PACKET_SIZE => {
-				return Err(Error::new(InvalidInput, PACKET_SIZE_ERR));
+			// wait out the rest of the timeout for a packet that is accepted
+			if let Some(deadline) = deadline {
+				let left = deadline.saturating_duration_since(Instant::now());
+				if left == Duration::from_secs(0) {
+					return Err(Error::new(TimedOut, "Timed out waiting for an accepted packet."));
+				}
+				wait = Some(left);
 			}
-			// if we received at least 8 bytes, continue
-		    Ok((num, _)) => {
-		    	packet_len = num;
-		    }
-		    // return an error if we encountered one
-		    Err(e) => {
-		    	return Err(e);
-		    }
 		}
+	}
+
+	/// Receive a Osc packet if one is waiting at the port, without blocking.
+	/// Returns None if there is nothing to receive.
+	pub fn try_recv(&self) -> Result<Option<OscPacket>> {
+		self.try_recv_from().map(|r| r.map(|(packet, _)| packet))
+	}
+
+	/// Receive a Osc packet and the address it was sent from if one is
+	/// waiting at the port, without blocking.
+	pub fn try_recv_from(&self) -> Result<Option<(OscPacket, SocketAddr)>> {
+		let buf = &mut[0; UDP_BUFFER_SIZE + 1];
+
+		self.set_mode(ReadMode::NonBlocking)?;
+
+		loop {
+			match self.socket.recv_from(buf) {
+				Ok((packet_len, source)) => {
+					if let Some(result) = self.process(&buf[..packet_len], source) {
+						return result.map(|packet| Some((packet, source)));
+					}
+				},
+				Err(ref e) if e.kind() == WouldBlock => return Ok(None),
+				Err(e) => return Err(e)
+			}
+		}
+	}
+
+	/// Receive every packet already waiting at the port, without blocking.
+	/// Packets that fail to decode are skipped.
+	pub fn drain(&self) -> Result<Vec<OscPacket>> {
+		let mut packets = Vec::new();
+		loop {
+			match self.try_recv() {
+				Ok(Some(packet)) => packets.push(packet),
+				Ok(None) => return Ok(packets),
+				Err(ref e) if e.kind() == InvalidInput => (),
+				Err(e) => return Err(e)
+			}
+		}
+	}
 
-		read_packet(&buf[..packet_len])
+	/// Put the socket in non-blocking mode, for waiting on it with poll, epoll
+	/// or mio before calling try_recv.  recv puts it back in blocking mode.
+	pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+		self.set_mode(if nonblocking { ReadMode::NonBlocking } else { ReadMode::Blocking(None) })
 	}
+
+	/// The local address the receiver is bound to.  Useful when binding to
+	/// port 0 to let the OS pick a free port.
+	pub fn local_addr(&self) -> Result<SocketAddr> {
+		self.socket.local_addr()
+	}
+
+	/// The underlying socket, for sending from the port the receiver is
+	/// bound to.  Its blocking mode and read timeout are managed by the
+	/// receiver and shouldn't be changed directly.
+	pub fn get_ref(&self) -> &S {
+		&self.socket
+	}
+
+	/// The underlying socket.
+	pub fn into_inner(self) -> S {
+		self.socket
+	}
+
+	/// Check the source of every datagram against a policy, dropping those it
+	/// rejects before they are decoded.  None accepts everything.  Returns
+	/// the policy that was in place.
+	pub fn set_policy(&self, policy: Option<SourcePolicy>) -> Option<SourcePolicy> {
+		std::mem::replace(&mut *self.policy.lock().unwrap(), policy)
+	}
+
+	/// Counts of the datagrams the policy has accepted and rejected, or None
+	/// if there is no policy.
+	pub fn policy_stats(&self) -> Option<PolicyStats> {
+		self.policy.lock().unwrap().as_ref().map(|p| p.stats())
+	}
+
+	/// Counts of the datagrams the policy has accepted and rejected from each
+	/// source IP, busiest first.
+	pub fn source_stats(&self) -> Vec<(IpAddr, PolicyStats)> {
+		self.policy.lock().unwrap().as_ref().map(|p| p.source_stats()).unwrap_or_default()
+	}
+
+	/// A snapshot of the counts of what has been received.
+	pub fn stats(&self) -> ReceiverStats {
+		self.stats.lock().unwrap().clone()
+	}
+
+	/// Reset every count to zero.
+	pub fn reset_stats(&self) {
+		*self.stats.lock().unwrap() = ReceiverStats::default();
+	}
+
+	// count a datagram, check it against the policy and decode it; None if
+	// the policy rejects it
+	fn process(&self, datagram: &[u8], source: SocketAddr) -> Option<Result<OscPacket>> {
+		let admitted = self.admits(source);
+		let mut stats = self.stats.lock().unwrap();
+		stats.count_datagram(datagram.len().min(UDP_BUFFER_SIZE), source);
+		if let Err(rejected) = admitted {
+			stats.rejected += 1;
+			// on_reject may call back into the receiver
+			drop(stats);
+			rejected.notify();
+			return None;
+		}
+		if datagram.len() > UDP_BUFFER_SIZE {
+			stats.truncated += 1;
+			return Some(Err(Error::new(InvalidInput, format!("Datagram bigger than {} bytes.", UDP_BUFFER_SIZE))));
+		}
+		let result = datagram_to_packet(datagram);
+		match result {
+			Ok(ref packet) => stats.count_packet(packet),
+			Err(ref e) => stats.count_decode_error(DecodeErrorKind::of(e).unwrap_or(UnexpectedEnd))
+		}
+		Some(result)
+	}
+
+	fn admits(&self, source: SocketAddr) -> std::result::Result<(), Rejected> {
+		match *self.policy.lock().unwrap() {
+			Some(ref mut policy) => policy.check_deferred(source),
+			None => Ok(())
+		}
+	}
+
+	fn set_mode(&self, mode: ReadMode) -> Result<()> {
+		let mut current = self.mode.lock().unwrap();
+		if *current == Some(mode) {
+			return Ok(());
+		}
+		match mode {
+			ReadMode::NonBlocking => self.socket.set_nonblocking(true)?,
+			ReadMode::Blocking(timeout) => {
+				if *current == Some(ReadMode::NonBlocking) || current.is_none() {
+					self.socket.set_nonblocking(false)?;
+				}
+				self.socket.set_read_timeout(timeout)?;
+			}
+		}
+		*current = Some(mode);
+		Ok(())
+	}
+}
+
+#[cfg(unix)]
+impl AsRawFd for OscReceiver {
+	fn as_raw_fd(&self) -> RawFd {
+		self.socket.as_raw_fd()
+	}
+}
+
+#[cfg(windows)]
+impl AsRawSocket for OscReceiver {
+	fn as_raw_socket(&self) -> RawSocket {
+		self.socket.as_raw_socket()
+	}
+}
+
+/// Interpret the contents of a datagram as an Osc packet.  This is the
+/// inverse of sender::packet_to_buffer, without the leading size.
+pub fn datagram_to_packet(buf: &[u8]) -> Result<OscPacket> {
+	// if we didn't receive enough data, throw an error
+	if buf.len() < MIN_OSC_PACKET_SIZE {
+		return Err(decode_err(TooShort, PACKET_SIZE_ERR));
+	}
+	read_packet(buf, 0)
+}
+
+fn decode_err<M: ToString>(kind: DecodeErrorKind, message: M) -> Error {
+	Error::new(InvalidInput, DecodeError::new(kind, message))
 }
 
 // interpret a buffer as an Osc packet; useful as bundles are recursive
-fn read_packet(buf: &[u8]) -> Result<OscPacket> {
+fn read_packet(buf: &[u8], depth: usize) -> Result<OscPacket> {
 	if is_bundle(buf) {
-		read_bundle(buf)
+		read_bundle(buf, depth)
 	}
 	else {
 		read_message(buf)
@@ -102,12 +330,18 @@ fn is_bundle(buf: &[u8]) -> bool {
     buf[0] as char == BUNDLE_FIRST_CHAR
 }
 
-// read the buffer as a bundle, assuming proper OSC formatting
-fn read_bundle(buf: &[u8]) -> Result<OscPacket> {
-	let mut reader = &mut BufReader::new(buf);
+// read the buffer as a bundle, assuming proper OSC formatting; depth is the
+// number of bundles it is nested in
+fn read_bundle(buf: &[u8], depth: usize) -> Result<OscPacket> {
+	if depth >= MAX_BUNDLE_DEPTH {
+		return Err(decode_err(BadBundle, format!("Bundle nested more than {} deep.", MAX_BUNDLE_DEPTH)));
+	}
 
-	// ignore 8 byte bundle ID string
-	reader.take(8).read_to_end(&mut vec!());;
+	// check the 8 byte bundle ID string
+	if !buf.starts_with(BUNDLE_ID) {
+		return Err(decode_err(BadBundle, "Invalid bundle ID."));
+	}
+	let mut reader = &buf[BUNDLE_ID.len()..];
 
 	// read the 64 bit time tag
 	let (sec, frac_sec): OscTimeTag;
@@ -115,48 +349,38 @@ fn read_bundle(buf: &[u8]) -> Result<OscPacket> {
 	match reader.read_u32::<BigEndian>() {
 		Ok(v) => sec = v,
 		Err(Io(e)) => return Err(e),
-        Err(UnexpectedEOF) => return Err(Error::new(InvalidInput, UnexpectedEOF))
+        Err(UnexpectedEOF) => return Err(decode_err(UnexpectedEnd, UnexpectedEOF))
 	}
 
 	match reader.read_u32::<BigEndian>() {
 		Ok(v) => frac_sec = v,
 		Err(Io(e)) => return Err(e),
-        Err(UnexpectedEOF) => return Err(Error::new(InvalidInput, UnexpectedEOF))
+        Err(UnexpectedEOF) => return Err(decode_err(UnexpectedEnd, UnexpectedEOF))
 	}
 
 	// now interpret the bundle contents
 	let mut bundle_conts = Vec::new();
 
 	// until we're out of buffer, read elements
-	let mut element_size: u32;
+	let mut element_size: usize;
 	loop {
 		// get the length of the bundle element, should be a mult of 4
 		match reader.read_i32::<BigEndian>() {
-			Ok(n) => element_size = n as u32,
+			Ok(n) if n <= 0 => return Err(decode_err(BadBundle, format!("Invalid bundle element size {}.", n))),
+			Ok(n) => element_size = n as usize,
             Err(Io(e)) => return Err(e),
             Err(UnexpectedEOF) =>
                 //  End of bundle
                 break
 		}
 
-        // try to read the specified length
-        let vec = &mut Vec::new();
-        match reader.take(element_size as u64).read_to_end(vec) {
-            Ok(num_read) => {
-                if num_read == element_size as usize {
-                    // if we got a valid vector, interpret it as a Osc packet
-                    match read_packet(vec.as_ref()) {
-                        Ok(pack) => bundle_conts.push(pack),
-                        Err(e) => return Err(e)
-                    }
-                } else {
-                    break
-                }
-            }
-            _ => break
-        }
-
-
+		// interpret the element in place as a Osc packet
+		if element_size > reader.len() {
+			return Err(decode_err(UnexpectedEnd, "Bundle element extends past the end of the bundle."));
+		}
+		let (element, rest) = reader.split_at(element_size);
+		bundle_conts.push(read_packet(element, depth + 1)?);
+		reader = rest;
 	}
 
 	Ok(OscBundle{time_tag: (sec, frac_sec), conts: bundle_conts})
@@ -167,25 +391,20 @@ fn read_message(buf: &[u8]) -> Result<OscPacket> {
 
 	let mut reader = BufReader::new(buf);
 
-	let addr: String;
-
 	// get the address
-	match read_null_term_string(&mut reader) {
-		Ok(a) => { addr = a; },
-		Err(e) => { return Err(e); }
-	}
+	let addr = read_null_term_string(&mut reader)?;
 
-	let tt_str: String;
+	// some older implementations omit the type tags when there are no args
+	if reader.fill_buf()?.is_empty() {
+		return Ok(OscMessage{addr, args: vec!()});
+	}
 
 	// now read the type tags
-	match read_null_term_string(&mut reader) {
-		Ok(tt) => { tt_str = tt; },
-		Err(e) => { return Err(e); }
-	}
+	let tt_str = read_null_term_string(&mut reader)?;
 
 	// check to make sure the first char is a comma
-	if tt_str.chars().nth(0) != Some(',') {
-		return Err(Error::new(InvalidInput, "Missing type tag comma."));
+	if !tt_str.starts_with(',') {
+		return Err(decode_err(BadTypeTags, "Missing type tag comma."));
 	}
 
 	// now read the arguments
@@ -199,7 +418,7 @@ fn read_message(buf: &[u8]) -> Result<OscPacket> {
 		}
 	}
 
-    Ok(OscMessage{addr: addr, args: args})
+    Ok(OscMessage{addr, args})
 }
 
 // Osc strings are null-terminated, we'll do this a lot
@@ -209,7 +428,10 @@ fn read_null_term_string(reader: &mut BufReader<&[u8]>) -> Result<String> {
 	match reader.read_until(0u8, m) {
         Ok(n) => {
             if n == 0 {
-                return Err(Error::new(InvalidInput, "No string to read."));
+                return Err(decode_err(UnexpectedEnd, "No string to read."));
+            }
+            if m[n - 1] != 0 {
+                return Err(decode_err(BadString, "Unterminated string."));
             }
         }
         Err(e) => return Err(e)
@@ -226,7 +448,7 @@ fn read_null_term_string(reader: &mut BufReader<&[u8]>) -> Result<String> {
         },
         // return an error if we can't parse this as a string
         Err(e) => {
-            Err(Error::new(InvalidInput, e))
+            Err(decode_err(BadString, e))
         }
     }
 }
@@ -238,12 +460,12 @@ fn read_osc_arg(reader: &mut BufReader<&[u8]>, type_tag: char) -> Result<OscArg>
 		'i' => match reader.read_i32::<BigEndian>() {
 			Ok(v) => Ok(OscInt(v)),
 			Err(Io(e)) => Err(e),
-            Err(UnexpectedEOF) => return Err(Error::new(InvalidInput, UnexpectedEOF))
+            Err(UnexpectedEOF) => Err(decode_err(UnexpectedEnd, UnexpectedEOF))
 		},
 		'f' => match reader.read_f32::<BigEndian>() {
 			Ok(v) => Ok(OscFloat(v)),
 			Err(Io(e)) => Err(e),
-            Err(UnexpectedEOF) => return Err(Error::new(InvalidInput, UnexpectedEOF))
+            Err(UnexpectedEOF) => Err(decode_err(UnexpectedEnd, UnexpectedEOF))
 		},
 		's' => match read_null_term_string(reader) {
 			Ok(v) => Ok(OscStr(v)),
@@ -253,23 +475,23 @@ fn read_osc_arg(reader: &mut BufReader<&[u8]>, type_tag: char) -> Result<OscArg>
 			Ok(v) => Ok(OscBlob(v)),
 			Err(e) => Err(e),
 		},
-		_ 	=> Err(Error::new(InvalidInput, format!("Invalid type tag {}", type_tag.to_string()) ))
+		_ 	=> Err(decode_err(UnsupportedType, format!("Invalid type tag {}", type_tag) ))
 	}
 }
 
 // read a blob
 fn read_blob(reader: &mut BufReader<&[u8]>) -> Result<Vec<u8>> {
-	let len : u32;
-	match reader.read_i32::<BigEndian>() {
-		Ok(v) => {len = v as u32;},
+	let len = match reader.read_i32::<BigEndian>() {
+		Ok(v) => v as u32,
 		Err(Io(e)) => {return Err(e);},
-        Err(UnexpectedEOF) => return Err(Error::new(InvalidInput, UnexpectedEOF))
-	}
+        Err(UnexpectedEOF) => return Err(decode_err(UnexpectedEnd, UnexpectedEOF))
+	};
 
     let mut vec = Vec::new();
     match reader.take(len as u64).read_to_end(&mut vec) {
-		Ok(len) => {
-			reader.consume(four_byte_pad(len));
+		Ok(n) if n < len as usize => Err(decode_err(UnexpectedEnd, "Blob extends past the end of the packet.")),
+		Ok(n) => {
+			reader.consume(four_byte_pad(n));
 			Ok(vec)
 		},
 		Err(e) => Err(e),
@@ -318,11 +540,37 @@ fn test_read_bundle(){
 	};
 
 	let buf = packet_to_buffer(packet.clone());
-	let res = read_bundle(&buf[4..]).unwrap();
+	let res = read_bundle(&buf[4..], 0).unwrap();
 
 	assert_eq!(packet,res);
 }
 
+#[test]
+fn test_read_nested_bundle(){
+	let nested = |depth: usize| (0..depth).fold(OscMessage{addr: "/a".to_string(), args: vec!()},
+		|inner, _| OscBundle{time_tag: (0,1), conts: vec!(inner)});
+
+	let buf = packet_to_buffer(nested(MAX_BUNDLE_DEPTH));
+	assert_eq!(datagram_to_packet(&buf[4..]).unwrap(), nested(MAX_BUNDLE_DEPTH));
+
+	let buf = packet_to_buffer(nested(MAX_BUNDLE_DEPTH + 1));
+	let err = datagram_to_packet(&buf[4..]).unwrap_err();
+	assert_eq!(err.kind(), InvalidInput);
+	assert_eq!(DecodeErrorKind::of(&err), Some(BadBundle));
+
+	// a frame of nothing but bundle headers, each claiming the rest of the
+	// frame, is turned away long before the stack runs out
+	let depth = 100_000;
+	let mut frame = Vec::with_capacity(depth * 20);
+	for i in 0..depth {
+		frame.extend_from_slice(BUNDLE_ID);
+		frame.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]);
+		frame.extend_from_slice(&(((depth - i - 1) * 20) as i32).to_be_bytes());
+	}
+	frame.truncate(frame.len() - 4);
+	assert!(datagram_to_packet(&frame).is_err());
+}
+
 
 #[test]
 fn test_read_null_term_string(){
@@ -365,7 +613,7 @@ fn test_read_blob(){
 
 	let mut buf = BufWriter::new(vec!());
 	buf.write_i32::<BigEndian>(9);
-	buf.write( vec!(0u8, 1u8, 2u8, 3u8, 4u8, 5u8, 10u8, 15u8, 20u8, 0u8, 0u8, 0u8).as_ref() );
+	buf.write_all( vec!(0u8, 1u8, 2u8, 3u8, 4u8, 5u8, 10u8, 15u8, 20u8, 0u8, 0u8, 0u8).as_ref() );
      buf.flush();
 
 	let tbuf = buf.get_ref();
@@ -382,3 +630,128 @@ fn test_read_blob(){
 
 
 }
+
+#[test]
+fn test_try_recv_and_drain() {
+	use sender::OscSender;
+
+	let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+	let receiver = OscReceiver::new(localhost).unwrap();
+	let sender = OscSender::new(localhost, receiver.local_addr().unwrap()).unwrap();
+	assert_eq!(receiver.try_recv().unwrap(), None);
+
+	let msg = |i| OscMessage{addr: "/frame".to_string(), args: vec!(OscInt(i))};
+	for i in 0..3 {
+		sender.send(msg(i)).unwrap();
+	}
+	// wait until the first has arrived, then take the rest
+	assert_eq!(receiver.recv(Some(Duration::from_secs(5))).unwrap(), msg(0));
+	let mut rest = Vec::new();
+	while rest.len() < 2 {
+		rest.extend(receiver.drain().unwrap());
+	}
+	assert_eq!(rest, vec!(msg(1), msg(2)));
+	assert!(receiver.drain().unwrap().is_empty());
+
+	// back to blocking with a timeout
+	assert!(receiver.recv(Some(Duration::from_millis(10))).is_err());
+}
+
+#[test]
+fn test_source_policy() {
+	use transport::LoopbackNetwork;
+	use sender::OscSender;
+	use policy::{SourcePolicy, PolicyStats};
+
+	let network = LoopbackNetwork::new();
+	let receiver = OscReceiver::from_socket(network.bind("192.168.1.10:9000").unwrap());
+	let dest = receiver.local_addr().unwrap();
+	let console = OscSender::from_socket(network.bind("192.168.1.20:0").unwrap(), dest);
+	let flooder = OscSender::from_socket(network.bind("192.168.1.99:0").unwrap(), dest);
+	let stranger = OscSender::from_socket(network.bind("10.0.0.1:0").unwrap(), dest);
+
+	let mut policy = SourcePolicy::new();
+	policy.allow("192.168.1.0/24".parse().unwrap());
+	policy.set_max_rate(Some(5));
+	assert!(receiver.set_policy(Some(policy)).is_none());
+
+	let cue = |i| OscMessage{addr: "/cue".to_string(), args: vec!(OscInt(i))};
+	for i in 0..20 {
+		flooder.send(cue(100 + i)).unwrap();
+	}
+	stranger.send(cue(200)).unwrap();
+	console.send(cue(1)).unwrap();
+
+	let received = receiver.drain().unwrap();
+	assert_eq!(received.len(), 6);
+	assert_eq!(received.last(), Some(&cue(1)));
+	assert_eq!(receiver.policy_stats(), Some(PolicyStats{accepted: 6, not_allowed: 1, rate_limited: 15}));
+	assert_eq!(receiver.source_stats()[0].0, "192.168.1.99".parse::<IpAddr>().unwrap());
+
+	// nothing accepted before the timeout
+	stranger.send(cue(201)).unwrap();
+	let err = receiver.recv(Some(Duration::from_millis(20))).unwrap_err();
+	assert!(err.kind() == TimedOut || err.kind() == WouldBlock);
+
+	// on_reject can read the receiver's counts
+	let receiver = std::sync::Arc::new(receiver);
+	let (tx, rx) = std::sync::mpsc::channel();
+	let mut policy = SourcePolicy::new();
+	policy.allow("192.168.1.0/24".parse().unwrap());
+	let weak = std::sync::Arc::downgrade(&receiver);
+	policy.set_on_reject(move |source, _| {
+		let receiver = weak.upgrade().unwrap();
+		tx.send((source, receiver.stats().rejected, receiver.policy_stats())).unwrap();
+	});
+	receiver.reset_stats();
+	receiver.set_policy(Some(policy));
+	stranger.send(cue(201)).unwrap();
+	let _ = receiver.try_recv();
+	let (source, rejected, policy_stats) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+	assert_eq!(source.ip(), "10.0.0.1".parse::<IpAddr>().unwrap());
+	assert_eq!(rejected, 1);
+	assert_eq!(policy_stats, Some(PolicyStats{accepted: 0, not_allowed: 1, rate_limited: 0}));
+	// the stranger isn't tracked
+	assert!(receiver.source_stats().is_empty());
+
+	assert!(receiver.set_policy(None).is_some());
+	stranger.send(cue(202)).unwrap();
+	assert_eq!(receiver.recv(Some(Duration::from_secs(1))).unwrap(), cue(202));
+	assert_eq!(receiver.policy_stats(), None);
+}
+
+#[test]
+fn test_receiver_stats() {
+	use transport::{LoopbackNetwork, Transport};
+	use sender::OscSender;
+	use stats::SenderStats;
+
+	let network = LoopbackNetwork::new();
+	let receiver = OscReceiver::from_socket(network.bind("10.0.0.1:9000").unwrap());
+	let dest = receiver.local_addr().unwrap();
+	let sender = OscSender::from_socket(network.bind("10.0.0.2:5000").unwrap(), dest);
+	let raw = network.bind("10.0.0.3:5000").unwrap();
+
+	let fader = OscMessage{addr: "/fader".to_string(), args: vec!(OscFloat(0.5))};
+	sender.send(fader.clone()).unwrap();
+	sender.send(OscBundle{time_tag: (0, 1), conts: vec!(fader.clone(), fader.clone())}).unwrap();
+	raw.send_to(b"/a\0\0", dest).unwrap();
+	raw.send_to(b"/a\0\0,h\0\0\0\0\0\0\0\0\0\x01", dest).unwrap();
+	raw.send_to(&[b'/'; UDP_BUFFER_SIZE + 100], dest).unwrap();
+
+	assert_eq!(receiver.drain().unwrap().len(), 2);
+	let stats = receiver.stats();
+	assert_eq!(stats.datagrams, 5);
+	assert_eq!(stats.bytes, 16 + 56 + 4 + 16 + UDP_BUFFER_SIZE as u64);
+	assert_eq!(stats.truncated, 1);
+	assert_eq!(stats.packets, 2);
+	assert_eq!(stats.messages, 3);
+	assert_eq!(stats.decode_errors.get(&TooShort), Some(&1));
+	assert_eq!(stats.decode_errors.get(&UnsupportedType), Some(&1));
+	assert_eq!(stats.sources[&"10.0.0.3".parse().unwrap()], 3);
+	assert_eq!(stats.addresses["/fader"], 3);
+	assert_eq!(sender.stats(), SenderStats{packets: 2, bytes: 72, errors: 0});
+
+	receiver.reset_stats();
+	assert_eq!(receiver.stats().datagrams, 0);
+}
@@ -0,0 +1,314 @@
+//! Module for recording received OSC to a file and playing it back.
+//!
+//! A recording is a text file with one packet per line: the time in seconds
+//! since recording started, the address the packet came from, and the packet in
+//! the syntax of the text module.
+//!
+//! ```text
+//! 0.000000 192.168.1.20:9000 /mixer/1/fader ,f 0.5
+//! 0.016667 192.168.1.20:9000 /mixer/1/fader ,f 0.51
+//! ```
+
+use std::fs::File;
+use std::path::Path;
+use std::net::{UdpSocket, SocketAddr, ToSocketAddrs};
+use std::io::{Error, Result, BufRead, BufReader, BufWriter, Write};
+use std::io::ErrorKind::InvalidInput;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use data::*;
+use receiver::OscReceiver;
+use sender::OscSender;
+use transport::Transport;
+use pattern::addr_matches;
+use text::parse_packet;
+
+/// A single packet from a recording.
+#[derive(Debug,Clone,PartialEq)]
+pub struct RecordedPacket {
+	/// Time since the start of the recording.
+	pub elapsed: Duration,
+	/// Address the packet was received from.
+	pub source: SocketAddr,
+	pub packet: OscPacket
+}
+
+impl RecordedPacket {
+	/// Format this packet as a line of a recording, without the newline.
+	pub fn to_line(&self) -> String {
+		format!("{}.{:06} {} {}",
+			self.elapsed.as_secs(), self.elapsed.subsec_micros(), self.source, self.packet)
+	}
+
+	/// Parse a line of a recording.
+	pub fn from_line(line: &str) -> Result<RecordedPacket> {
+		let mut fields = line.trim().splitn(3, ' ');
+		let (elapsed, source, packet) = match (fields.next(), fields.next(), fields.next()) {
+			(Some(e), Some(s), Some(p)) => (e, s, p),
+			_ => return Err(Error::new(InvalidInput, format!("Malformed recording line {}.", line)))
+		};
+
+		let elapsed = elapsed.parse::<f64>().ok()
+			.and_then(|e| Duration::try_from_secs_f64(e).ok())
+			.ok_or_else(|| Error::new(InvalidInput, format!("Invalid time {}.", elapsed)))?;
+		let source = source.parse::<SocketAddr>()
+			.map_err(|_| Error::new(InvalidInput, format!("Invalid source address {}.", source)))?;
+
+		Ok(RecordedPacket{elapsed, source, packet: parse_packet(packet)?})
+	}
+}
+
+/// Read every packet from a recording.  Blank lines are skipped.
+pub fn read_recording<R: BufRead>(reader: R) -> Result<Vec<RecordedPacket>> {
+	let mut packets = Vec::new();
+	for line in reader.lines() {
+		let line = line?;
+		if line.trim().is_empty() { continue; }
+		packets.push(RecordedPacket::from_line(&line)?);
+	}
+	Ok(packets)
+}
+
+/// Read every packet from a recording file.
+pub fn load_recording<P: AsRef<Path>>(path: P) -> Result<Vec<RecordedPacket>> {
+	read_recording(BufReader::new(File::open(path)?))
+}
+
+/// Wraps an OscReceiver and writes every packet it receives to a recording.
+pub struct OscRecorder<W: Write, S: Transport = UdpSocket> {
+	receiver: OscReceiver<S>,
+	out: W,
+	start: Instant
+}
+
+impl<S: Transport> OscRecorder<BufWriter<File>, S> {
+
+	/// Constructs a new OscRecorder writing to a newly created file.  Returns
+	/// Err if the file could not be created.
+	pub fn create<P: AsRef<Path>>(receiver: OscReceiver<S>, path: P) -> Result<Self> {
+		Ok(OscRecorder::new(receiver, BufWriter::new(File::create(path)?)))
+	}
+}
+
+impl<W: Write, S: Transport> OscRecorder<W, S> {
+
+	/// Constructs a new OscRecorder writing to any writer.  Timestamps are
+	/// measured from the moment the recorder is constructed.
+	pub fn new(receiver: OscReceiver<S>, out: W) -> Self {
+		OscRecorder{receiver, out, start: Instant::now()}
+	}
+
+	/// Receive a single packet, append it to the recording and return it.
+	/// Blocks and times out in the same way as OscReceiver::recv.  Packets
+	/// that fail to decode are returned as errors and are not recorded.
+	pub fn record(&mut self, timeout: Option<Duration>) -> Result<RecordedPacket> {
+		let (packet, source) = self.receiver.recv_from(timeout)?;
+		let recorded = RecordedPacket{elapsed: self.start.elapsed(), source, packet};
+
+		// flush every packet so nothing is lost if the show crashes
+		writeln!(self.out, "{}", recorded.to_line())?;
+		self.out.flush()?;
+
+		Ok(recorded)
+	}
+
+	/// Stop recording, returning the receiver and the writer.
+	pub fn into_inner(self) -> (OscReceiver<S>, W) {
+		(self.receiver, self.out)
+	}
+}
+
+/// Plays a recording back through an OscSender, preserving the time between
+/// packets.  When looping, each pass lasts as long as the recording did, from
+/// the start of recording to its last packet.
+pub struct OscPlayer {
+	packets: Vec<RecordedPacket>,
+	speed: f64,
+	loops: Option<u32>,
+	filters: Vec<String>
+}
+
+impl OscPlayer {
+
+	/// Constructs a new OscPlayer that plays the packets once at normal speed.
+	pub fn new(packets: Vec<RecordedPacket>) -> OscPlayer {
+		OscPlayer{packets, speed: 1.0, loops: Some(1), filters: Vec::new()}
+	}
+
+	/// Constructs a new OscPlayer from a recording file.
+	pub fn load<P: AsRef<Path>>(path: P) -> Result<OscPlayer> {
+		load_recording(path).map(OscPlayer::new)
+	}
+
+	/// Scale the playback speed; 2.0 plays twice as fast.  Panics if the speed
+	/// is not positive.
+	pub fn speed(mut self, speed: f64) -> OscPlayer {
+		assert!(speed > 0.0 && speed.is_finite(), "playback speed must be positive");
+		self.speed = speed;
+		self
+	}
+
+	/// Set how many times to play the recording; None loops forever.
+	pub fn loops(mut self, loops: Option<u32>) -> OscPlayer {
+		self.loops = loops;
+		self
+	}
+
+	/// Only play messages whose address matches this OSC address pattern.  If
+	/// several filters are added, messages matching any of them are played.
+	pub fn filter(mut self, pattern: &str) -> OscPlayer {
+		self.filters.push(pattern.to_string());
+		self
+	}
+
+	/// Play the recording, blocking until it has finished.  Returns the number
+	/// of packets sent, or the first error encountered while sending.  Playing
+	/// stops early if a pass sends nothing, and looping forever is refused for
+	/// a recording with no duration, which would flood the destination.
+	pub fn play<T: ToSocketAddrs, S: Transport>(&self, sender: &OscSender<T, S>) -> Result<usize> {
+		if self.loops.is_none() && !self.packets.is_empty() && self.duration() == Duration::from_secs(0) {
+			return Err(Error::new(InvalidInput, "Can't loop a recording with no duration forever."));
+		}
+
+		let mut sent = 0;
+		let mut pass = 0;
+		while self.loops.is_none_or(|n| pass < n) {
+			let start = Instant::now();
+			let pass_sent = self.play_pass(sender, start)?;
+			if pass_sent == 0 {
+				break;
+			}
+			sent += pass_sent;
+			pass += 1;
+
+			// wait out the rest of the recording before starting it again
+			if self.loops.is_none_or(|n| pass < n) {
+				let end = start + self.duration();
+				let now = Instant::now();
+				if end > now {
+					thread::sleep(end - now);
+				}
+			}
+		}
+		Ok(sent)
+	}
+
+	// the length of one pass at the playback speed
+	fn duration(&self) -> Duration {
+		self.packets.last().map_or(Duration::from_secs(0), |p| p.elapsed.div_f64(self.speed))
+	}
+
+	fn play_pass<T: ToSocketAddrs, S: Transport>(&self, sender: &OscSender<T, S>, start: Instant) -> Result<usize> {
+		let offset = self.packets.first().map_or(Duration::from_secs(0), |p| p.elapsed);
+		let mut sent = 0;
+
+		for recorded in &self.packets {
+			let packet = match self.apply_filters(recorded.packet.clone()) {
+				Some(p) => p,
+				None => continue
+			};
+
+			// schedule against the start of the pass so delays don't accumulate
+			let due = start + recorded.elapsed.checked_sub(offset).unwrap_or_default().div_f64(self.speed);
+			let now = Instant::now();
+			if due > now {
+				thread::sleep(due - now);
+			}
+
+			sender.send(packet)?;
+			sent += 1;
+		}
+		Ok(sent)
+	}
+
+	fn apply_filters(&self, packet: OscPacket) -> Option<OscPacket> {
+		if self.filters.is_empty() {
+			return Some(packet);
+		}
+		filter_packet(packet, &|addr: &str| self.filters.iter().any(|f| addr_matches(f, addr)))
+	}
+}
+
+#[test]
+fn test_recorded_packet_line() {
+	let recorded = RecordedPacket{
+		elapsed: Duration::new(12, 345678000),
+		source: "127.0.0.1:9000".parse().unwrap(),
+		packet: OscPacket::OscMessage{addr: "/a".to_string(), args: vec!(OscArg::OscInt(1))}
+	};
+	assert_eq!(recorded.to_line(), "12.345678 127.0.0.1:9000 /a ,i 1");
+	assert_eq!(RecordedPacket::from_line(&recorded.to_line()).unwrap(), recorded);
+
+	assert!(RecordedPacket::from_line("12.5 127.0.0.1:9000").is_err());
+	assert!(RecordedPacket::from_line("-1 127.0.0.1:9000 /a").is_err());
+	assert!(RecordedPacket::from_line("1e30 127.0.0.1:1 /a ,").is_err());
+	assert!(read_recording("1e30 127.0.0.1:1 /a ,\n".as_bytes()).is_err());
+	assert!(RecordedPacket::from_line("1 nowhere /a").is_err());
+}
+
+#[test]
+fn test_read_recording() {
+	let text = "0.000000 127.0.0.1:1 /a ,i 1\n\n0.500000 [::1]:2 #bundle 0:1 [ /b ,s \"x y\" ]\n";
+	let packets = read_recording(text.as_bytes()).unwrap();
+	assert_eq!(packets.len(), 2);
+	assert_eq!(packets[1].elapsed, Duration::from_millis(500));
+	assert_eq!(packets[1].source, "[::1]:2".parse().unwrap());
+}
+
+#[test]
+fn test_record_and_play() {
+	let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+	// record two packets sent from a sender
+	let receiver = OscReceiver::new(localhost).unwrap();
+	let dest = receiver.local_addr().unwrap();
+	let sender = OscSender::new(localhost, dest).unwrap();
+	let mut recorder = OscRecorder::new(receiver, Vec::new());
+
+	let first = OscPacket::OscMessage{addr: "/keep".to_string(), args: vec!(OscArg::OscInt(1))};
+	let second = OscPacket::OscMessage{addr: "/drop".to_string(), args: vec!(OscArg::OscFloat(2.0))};
+	sender.send(first.clone()).unwrap();
+	sender.send(second.clone()).unwrap();
+	recorder.record(Some(Duration::from_secs(5))).unwrap();
+	recorder.record(Some(Duration::from_secs(5))).unwrap();
+
+	let (receiver, out) = recorder.into_inner();
+	let mut packets = read_recording(&out[..]).unwrap();
+	assert_eq!(packets.iter().map(|p| p.packet.clone()).collect::<Vec<_>>(), vec!(first.clone(), second.clone()));
+
+	// spread them out in time and play them back twice, filtered
+	packets[1].elapsed = packets[0].elapsed + Duration::from_millis(100);
+	let filtered = OscPlayer::new(packets.clone()).loops(Some(2)).filter("/k*");
+	assert_eq!(filtered.play(&sender).unwrap(), 2);
+
+	// then once at double speed
+	let start = Instant::now();
+	assert_eq!(OscPlayer::new(packets).speed(2.0).play(&sender).unwrap(), 2);
+	assert!(start.elapsed() >= Duration::from_millis(50));
+
+	assert_eq!(receiver.recv(Some(Duration::from_secs(5))).unwrap(), first);
+	assert_eq!(receiver.recv(Some(Duration::from_secs(5))).unwrap(), first);
+	assert_eq!(receiver.recv(Some(Duration::from_secs(5))).unwrap(), first);
+	assert_eq!(receiver.recv(Some(Duration::from_secs(5))).unwrap(), second);
+}
+
+#[test]
+fn test_play_stops_on_empty_pass() {
+	let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+	let sender = OscSender::new(localhost, localhost).unwrap();
+
+	// nothing to send, so looping forever returns at once
+	assert_eq!(OscPlayer::new(vec!()).loops(None).play(&sender).unwrap(), 0);
+
+	let recorded = RecordedPacket{
+		elapsed: Duration::from_secs(0),
+		source: localhost,
+		packet: OscPacket::OscMessage{addr: "/a".to_string(), args: vec!()}
+	};
+	let filtered = OscPlayer::new(vec!(recorded.clone(), recorded.clone())).loops(Some(1000)).filter("/b");
+	assert_eq!(filtered.play(&sender).unwrap(), 0);
+
+	// a recording with no duration would be sent as fast as possible
+	assert!(OscPlayer::new(vec!(recorded)).loops(None).play(&sender).is_err());
+}
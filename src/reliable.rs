@@ -0,0 +1,429 @@
+//! Module for reliable delivery of OSC between two endpoints over UDP.
+//!
+//! An OscReliable wraps each packet it sends in a message to a reserved
+//! address carrying a session id, a sequence number and the encoded packet as
+//! a blob:
+//!
+//! ```text
+//! /osc-reliable/data ,iib session seq packet
+//! ```
+//!
+//! The other end answers every data message with an acknowledgement, and
+//! delivers each sequence number once, optionally in order:
+//!
+//! ```text
+//! /osc-reliable/ack ,ii session seq
+//! ```
+//!
+//! Unacknowledged packets are sent again, waiting twice as long after each
+//! attempt up to a maximum.  The session id changes whenever an endpoint is
+//! created, so a restarted peer starts again from sequence number zero; late
+//! retransmissions from the session it replaced are acknowledged but not
+//! delivered.  Packets from the peer that aren't wrapped are delivered as they
+//! arrive.
+//!
+//! In ordered mode a missing packet holds back everything after it, so a peer
+//! that gives up on a packet stalls delivery; give up only when unordered.
+//! Unordered, duplicates are recognised within the last 1024 sequence numbers,
+//! and anything older is taken to have been delivered or given up on.
+
+use std::collections::BTreeMap;
+use std::net::{UdpSocket, ToSocketAddrs};
+use std::io::Result;
+use std::io::ErrorKind::{InvalidInput, TimedOut, WouldBlock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use data::*;
+use data::OscPacket::*;
+use data::OscArg::*;
+use receiver::{OscReceiver, datagram_to_packet};
+use sender::{OscSender, packet_to_buffer};
+use transport::Transport;
+
+/// Address of wrapped packets.
+pub const RELIABLE_DATA_ADDR: &str = "/osc-reliable/data";
+/// Address of acknowledgements.
+pub const RELIABLE_ACK_ADDR: &str = "/osc-reliable/ack";
+
+const DEFAULT_INITIAL_WAIT_MS: u64 = 100;
+const DEFAULT_MAX_WAIT_MS: u64 = 2000;
+// sequence numbers remembered for spotting duplicates when unordered
+const RECEIVE_WINDOW: u32 = 1024;
+
+struct Outgoing {
+	packet: OscPacket,
+	attempts: u32,
+	wait: Duration,
+	retry_at: Instant
+}
+
+/// One end of a reliable channel with a single peer.
+pub struct OscReliable<T: ToSocketAddrs, S: Transport = UdpSocket> {
+	receiver: OscReceiver<S>,
+	sender: OscSender<T, S>,
+	session: i32,
+	next_seq: u32,
+	unacked: BTreeMap<u32, Outgoing>,
+	failed: Vec<OscPacket>,
+	initial_wait: Duration,
+	max_wait: Duration,
+	max_attempts: Option<u32>,
+	ordered: bool,
+	peer_session: Option<i32>,
+	next_expected: u32,
+	// packets received ahead of next_expected; None once delivered
+	held: BTreeMap<u32, Option<OscPacket>>
+}
+
+impl<T: ToSocketAddrs, S: Transport> OscReliable<T, S> {
+
+	/// Constructs a new OscReliable receiving from the peer on one socket and
+	/// sending to it on another.  Packets are delivered as they arrive and
+	/// retried forever, first after 100ms and at most every 2s.
+	pub fn new(receiver: OscReceiver<S>, sender: OscSender<T, S>) -> Self {
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+		OscReliable{
+			receiver,
+			sender,
+			session: now.as_millis() as u32 as i32,
+			next_seq: 0,
+			unacked: BTreeMap::new(),
+			failed: Vec::new(),
+			initial_wait: Duration::from_millis(DEFAULT_INITIAL_WAIT_MS),
+			max_wait: Duration::from_millis(DEFAULT_MAX_WAIT_MS),
+			max_attempts: None,
+			ordered: false,
+			peer_session: None,
+			next_expected: 0,
+			held: BTreeMap::new()
+		}
+	}
+
+	/// Deliver packets from the peer in the order they were sent.
+	pub fn set_ordered(&mut self, ordered: bool) {
+		self.ordered = ordered;
+	}
+
+	/// Set how long to wait for the first acknowledgement, the most to wait
+	/// between attempts, and how many attempts to make before giving up.
+	pub fn set_retry(&mut self, initial_wait: Duration, max_wait: Duration, max_attempts: Option<u32>) {
+		self.initial_wait = initial_wait;
+		self.max_wait = max_wait;
+		self.max_attempts = max_attempts;
+	}
+
+	/// Send a packet and keep sending it until the peer acknowledges it.
+	/// Returns its sequence number.
+	pub fn send(&mut self, packet: OscPacket) -> Result<u32> {
+		let seq = self.next_seq;
+		self.next_seq += 1;
+		self.sender.send(self.wrap(seq, &packet))?;
+		self.unacked.insert(seq, Outgoing{
+			packet,
+			attempts: 1,
+			wait: self.initial_wait,
+			retry_at: Instant::now() + self.initial_wait
+		});
+		Ok(seq)
+	}
+
+	/// The number of sent packets not yet acknowledged.
+	pub fn pending(&self) -> usize {
+		self.unacked.len()
+	}
+
+	/// Whether a sent packet has been acknowledged or given up on.
+	pub fn is_done(&self, seq: u32) -> bool {
+		seq < self.next_seq && !self.unacked.contains_key(&seq)
+	}
+
+	/// Take the packets given up on since the last call.
+	pub fn take_failed(&mut self) -> Vec<OscPacket> {
+		std::mem::take(&mut self.failed)
+	}
+
+	/// Receive packets from the peer, acknowledging them and resending
+	/// unacknowledged packets while waiting.  Returns as soon as something is
+	/// delivered, or with nothing once the timeout has passed.  Packets that
+	/// fail to decode are skipped.
+	pub fn poll(&mut self, timeout: Option<Duration>) -> Result<Vec<OscPacket>> {
+		let deadline = timeout.map(|t| Instant::now() + t);
+		loop {
+			self.retransmit()?;
+
+			let now = Instant::now();
+			let mut wait = deadline.map(|d| d.saturating_duration_since(now));
+			if let Some(retry_at) = self.unacked.values().map(|o| o.retry_at).min() {
+				let until_retry = retry_at.saturating_duration_since(now);
+				wait = Some(wait.map_or(until_retry, |w| w.min(until_retry)));
+			}
+			// a zero read timeout is an error
+			let wait = wait.map(|w| w.max(Duration::from_millis(1)));
+
+			match self.receiver.recv(wait) {
+				Ok(packet) => {
+					let delivered = self.handle(packet)?;
+					if !delivered.is_empty() {
+						return Ok(delivered);
+					}
+				},
+				Err(ref e) if [WouldBlock, TimedOut, InvalidInput].contains(&e.kind()) => (),
+				Err(e) => return Err(e)
+			}
+			if deadline.is_some_and(|d| Instant::now() >= d) {
+				return Ok(Vec::new());
+			}
+		}
+	}
+
+	fn handle(&mut self, packet: OscPacket) -> Result<Vec<OscPacket>> {
+		let mut delivered = Vec::new();
+		match packet {
+			OscMessage{ref addr, ref args} if addr == RELIABLE_ACK_ADDR => {
+				if let [OscInt(session), OscInt(seq)] = args[..] {
+					if session == self.session {
+						self.unacked.remove(&(seq as u32));
+					}
+				}
+			},
+			OscMessage{ref addr, ref args} if addr == RELIABLE_DATA_ADDR => {
+				if let [OscInt(session), OscInt(seq), OscBlob(ref buf)] = args[..] {
+					self.sender.send(OscMessage{
+						addr: RELIABLE_ACK_ADDR.to_string(),
+						args: vec!(OscInt(session), OscInt(seq))
+					})?;
+					if let Ok(inner) = datagram_to_packet(buf) {
+						self.receive(session, seq as u32, inner, &mut delivered);
+					}
+				}
+			},
+			other => delivered.push(other)
+		}
+		Ok(delivered)
+	}
+
+	fn receive(&mut self, session: i32, seq: u32, packet: OscPacket, delivered: &mut Vec<OscPacket>) {
+		// session ids are taken from the clock, so a restarted peer's is
+		// greater, allowing for wrapping
+		match self.peer_session {
+			Some(current) if current == session => (),
+			Some(current) if session.wrapping_sub(current) < 0 => return,
+			_ => {
+				self.peer_session = Some(session);
+				self.next_expected = 0;
+				self.held.clear();
+			}
+		}
+		if seq < self.next_expected || self.held.contains_key(&seq) {
+			return;
+		}
+
+		if self.ordered {
+			self.held.insert(seq, Some(packet));
+		}
+		else {
+			delivered.push(packet);
+			self.held.insert(seq, None);
+			// a gap the peer gave up on would otherwise keep every later
+			// number here forever
+			if seq - self.next_expected >= RECEIVE_WINDOW {
+				self.next_expected = seq + 1 - RECEIVE_WINDOW;
+				self.held = self.held.split_off(&self.next_expected);
+			}
+		}
+		while let Some(entry) = self.held.remove(&self.next_expected) {
+			delivered.extend(entry);
+			self.next_expected += 1;
+		}
+	}
+
+	fn retransmit(&mut self) -> Result<()> {
+		let now = Instant::now();
+		let due: Vec<u32> = self.unacked.iter()
+			.filter(|&(_, o)| o.retry_at <= now)
+			.map(|(&seq, _)| seq)
+			.collect();
+
+		for seq in due {
+			let given_up = self.max_attempts.is_some_and(|m| self.unacked[&seq].attempts >= m);
+			if given_up {
+				let outgoing = self.unacked.remove(&seq).unwrap();
+				self.failed.push(outgoing.packet);
+				continue;
+			}
+			self.sender.send(self.wrap(seq, &self.unacked[&seq].packet))?;
+			let max_wait = self.max_wait;
+			let outgoing = self.unacked.get_mut(&seq).unwrap();
+			outgoing.attempts += 1;
+			outgoing.wait = (outgoing.wait * 2).min(max_wait);
+			outgoing.retry_at = now + outgoing.wait;
+		}
+		Ok(())
+	}
+
+	fn wrap(&self, seq: u32, packet: &OscPacket) -> OscPacket {
+		OscMessage{
+			addr: RELIABLE_DATA_ADDR.to_string(),
+			args: vec!(
+				OscInt(self.session),
+				OscInt(seq as i32),
+				OscBlob(packet_to_buffer(packet.clone())[4..].to_vec())
+			)
+		}
+	}
+}
+
+#[cfg(test)]
+fn msg(i: i32) -> OscPacket {
+	OscMessage{addr: "/cue".to_string(), args: vec!(OscInt(i))}
+}
+
+#[test]
+fn test_reliable_delivery() {
+	use std::net::SocketAddr;
+
+	let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+	let a_in = OscReceiver::new(localhost).unwrap();
+	let b_in = OscReceiver::new(localhost).unwrap();
+	let a_out = OscSender::new(localhost, b_in.local_addr().unwrap()).unwrap();
+	let b_out = OscSender::new(localhost, a_in.local_addr().unwrap()).unwrap();
+	let mut a = OscReliable::new(a_in, a_out);
+	let mut b = OscReliable::new(b_in, b_out);
+
+	for i in 0..3 {
+		assert_eq!(a.send(msg(i)).unwrap(), i as u32);
+	}
+	assert_eq!(a.pending(), 3);
+
+	let mut received = Vec::new();
+	while received.len() < 3 {
+		received.extend(b.poll(Some(Duration::from_secs(5))).unwrap());
+	}
+	assert_eq!(received, vec!(msg(0), msg(1), msg(2)));
+
+	while a.pending() > 0 {
+		a.poll(Some(Duration::from_millis(50))).unwrap();
+	}
+	assert!(a.is_done(2));
+	assert!(!a.is_done(3));
+	assert!(a.take_failed().is_empty());
+}
+
+#[test]
+fn test_reliable_dedupe_and_order() {
+	use std::net::SocketAddr;
+
+	let timeout = Some(Duration::from_secs(5));
+	let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+	let b_in = OscReceiver::new(localhost).unwrap();
+	let acks = OscReceiver::new(localhost).unwrap();
+	let raw = OscSender::new(localhost, b_in.local_addr().unwrap()).unwrap();
+	let mut b = OscReliable::new(b_in, OscSender::new(localhost, acks.local_addr().unwrap()).unwrap());
+	b.set_ordered(true);
+
+	let data = |seq: i32| OscMessage{
+		addr: RELIABLE_DATA_ADDR.to_string(),
+		args: vec!(OscInt(7), OscInt(seq), OscBlob(packet_to_buffer(msg(seq))[4..].to_vec()))
+	};
+
+	// 1 arrives before 0 and 1 is duplicated
+	raw.send(data(1)).unwrap();
+	raw.send(data(1)).unwrap();
+	raw.send(data(0)).unwrap();
+	raw.send(data(2)).unwrap();
+	raw.send(msg(99)).unwrap();
+
+	let mut received = Vec::new();
+	while received.len() < 4 {
+		received.extend(b.poll(timeout).unwrap());
+	}
+	assert_eq!(received, vec!(msg(0), msg(1), msg(2), msg(99)));
+
+	let ack = acks.recv(timeout).unwrap();
+	assert_eq!(ack, OscMessage{addr: RELIABLE_ACK_ADDR.to_string(), args: vec!(OscInt(7), OscInt(1))});
+}
+
+#[test]
+fn test_reliable_sessions_and_gaps() {
+	use std::net::SocketAddr;
+
+	let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+	let b_in = OscReceiver::new(localhost).unwrap();
+	let b_out = OscSender::new(localhost, localhost).unwrap();
+	let mut b = OscReliable::new(b_in, b_out);
+	let mut delivered = Vec::new();
+
+	// a gap at 0 that is never filled doesn't keep everything after it
+	for seq in 1..3000 {
+		b.receive(7, seq, msg(seq as i32), &mut delivered);
+	}
+	assert_eq!(delivered.len(), 2999);
+	assert!(b.held.len() <= RECEIVE_WINDOW as usize);
+	b.receive(7, 2999, msg(2999), &mut delivered);
+	assert_eq!(delivered.len(), 2999);
+
+	// a newer session starts again, and a late packet from the old one is
+	// ignored rather than resetting it
+	b.receive(8, 0, msg(0), &mut delivered);
+	b.receive(7, 3000, msg(3000), &mut delivered);
+	b.receive(8, 0, msg(0), &mut delivered);
+	assert_eq!(delivered.len(), 3000);
+	assert_eq!(delivered[2999], msg(0));
+	assert_eq!(b.peer_session, Some(8));
+
+	// session ids wrap around
+	b.receive(i32::MAX, 0, msg(1), &mut delivered);
+	b.receive(i32::MIN, 0, msg(2), &mut delivered);
+	b.receive(i32::MAX, 1, msg(3), &mut delivered);
+	assert_eq!(b.peer_session, Some(i32::MIN));
+	assert_eq!(delivered[3000..], [msg(1), msg(2)]);
+}
+
+#[test]
+fn test_reliable_retry_and_give_up() {
+	use std::net::SocketAddr;
+
+	let timeout = Some(Duration::from_secs(5));
+	let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+	let a_in = OscReceiver::new(localhost).unwrap();
+	let silent = OscReceiver::new(localhost).unwrap();
+	let mut a = OscReliable::new(a_in, OscSender::new(localhost, silent.local_addr().unwrap()).unwrap());
+	a.set_retry(Duration::from_millis(10), Duration::from_millis(20), Some(3));
+
+	a.send(msg(1)).unwrap();
+	a.poll(Some(Duration::from_millis(200))).unwrap();
+	assert_eq!(a.take_failed(), vec!(msg(1)));
+	assert!(a.is_done(0));
+
+	let first = silent.recv(timeout).unwrap();
+	for _ in 0..2 {
+		assert_eq!(silent.recv(timeout).unwrap(), first);
+	}
+	assert!(silent.recv(Some(Duration::from_millis(50))).is_err());
+}
+
+#[test]
+fn test_reliable_over_lossy_link() {
+	use transport::{LoopbackNetwork, LinkConditions};
+
+	let network = LoopbackNetwork::new();
+	network.set_conditions(LinkConditions{loss: 0.3, duplicate: 0.2, reorder: 0.3, ..LinkConditions::default()});
+	let a_in = OscReceiver::from_socket(network.bind("127.0.0.1:0").unwrap());
+	let b_in = OscReceiver::from_socket(network.bind("127.0.0.1:0").unwrap());
+	let a_out = OscSender::from_socket(network.bind("127.0.0.1:0").unwrap(), b_in.local_addr().unwrap());
+	let b_out = OscSender::from_socket(network.bind("127.0.0.1:0").unwrap(), a_in.local_addr().unwrap());
+	let mut a = OscReliable::new(a_in, a_out);
+	let mut b = OscReliable::new(b_in, b_out);
+	a.set_retry(Duration::from_millis(5), Duration::from_millis(20), None);
+	b.set_ordered(true);
+
+	for i in 0..20 {
+		a.send(msg(i)).unwrap();
+	}
+	let mut received = Vec::new();
+	while received.len() < 20 || a.pending() > 0 {
+		received.extend(b.poll(Some(Duration::from_millis(5))).unwrap());
+		a.poll(Some(Duration::from_millis(5))).unwrap();
+	}
+	assert_eq!(received, (0..20).map(msg).collect::<Vec<_>>());
+}
@@ -7,7 +7,9 @@ use std::net::UdpSocket;
 use std::net::SocketAddrV4;
 use std::net::ToSocketAddrs;
 
-use std::io::{Result, BufWriter};
+use std::io::{Error, Result, BufWriter};
+use std::sync::Mutex;
+use std::io::ErrorKind::InvalidInput;
 use self::byteorder::{BigEndian, WriteBytesExt};
 
 use std::io::prelude::*;
@@ -17,14 +19,20 @@ use data::OscPacket::*;
 use data::OscArg::*;
 
 use util::*;
+use bundle::{pack, split, encoded_len};
+#[cfg(all(feature = "batch", target_os = "linux"))]
+use batch::send_batch;
+use transport::Transport;
+use stats::SenderStats;
 
-// we may want to generalize this beyond UDP later
 /// Structure which contains the port used to send Osc packets, and handles
-/// the task of converting Rust Osc objects into valic Osc messages
-pub struct OscSender<T: ToSocketAddrs> {
+/// the task of converting Rust Osc objects into valic Osc messages.  Sends
+/// over UDP unless given another Transport.
+pub struct OscSender<T: ToSocketAddrs, S: Transport = UdpSocket> {
 
-	socket: UdpSocket,
-	dest: T
+	socket: S,
+	dest: T,
+	stats: Mutex<SenderStats>
 
 }
 
@@ -34,24 +42,92 @@ impl<T: ToSocketAddrs> OscSender<T> {
     /// address.  Returns Err if an error occurred when trying to bind to the socket.
     pub fn new(local_addr: T, dest_addr: T) -> Result<Self> {
         match UdpSocket::bind(local_addr) {
-            Ok(s) => Ok(OscSender{socket: s, dest: dest_addr}),
-            Err(e) => return Err(e),
+            Ok(s) => Ok(OscSender::from_socket(s, dest_addr)),
+            Err(e) => Err(e),
         }
     }
 
+	/// Send many packets with as few system calls as the kernel allows.
+	/// Returns the number of packets sent, which is fewer than given if
+	/// sending fails part way.
+	#[cfg(all(feature = "batch", target_os = "linux"))]
+	pub fn send_batch(&self, packets: &[OscPacket]) -> Result<usize> {
+		let dest = self.dest.to_socket_addrs()?.next()
+			.ok_or_else(|| Error::new(InvalidInput, "No destination address."))?;
+		let result = send_batch(&self.socket, dest, packets);
+		let mut stats = self.stats.lock().unwrap();
+		match result {
+			Ok(n) => {
+				stats.packets += n as u64;
+				stats.bytes += packets[..n].iter().map(|p| encoded_len(p) as u64).sum::<u64>();
+			},
+			Err(_) => stats.errors += 1
+		}
+		result
+	}
+}
+
+impl<T: ToSocketAddrs, S: Transport> OscSender<T, S> {
+
+    /// Constructs a new OscSender sending from a socket that is already bound.
+    pub fn from_socket(socket: S, dest_addr: T) -> Self {
+        OscSender{socket, dest: dest_addr, stats: Mutex::new(SenderStats::default())}
+    }
+
 
 	/// Attempt to send a Rust OSC packet as an OSC UDP packet.
 	pub fn send(&self, packet: OscPacket) -> Result<usize> {
 		// note that we trim off the first four bytes, as they are the packet length
 		// and the socket automatically calcs and sends that
-		self.socket.send_to(&packet_to_buffer(packet)[4..], &self.dest)
+		let result = self.socket.send_to(&packet_to_buffer(packet)[4..], &self.dest);
+		let mut stats = self.stats.lock().unwrap();
+		match result {
+			Ok(n) => {
+				stats.packets += 1;
+				stats.bytes += n as u64;
+			},
+			Err(_) => stats.errors += 1
+		}
+		result
 	}
 
+	/// Send a packet as one or more datagrams of at most max_size bytes,
+	/// splitting a bundle that is too large into several with the same time
+	/// tag.  Returns the number of datagrams sent.  Nothing is sent if a
+	/// message is too large to fit.
+	pub fn send_split(&self, packet: OscPacket, max_size: usize) -> Result<usize> {
+		self.send_datagrams(split(packet, max_size)?)
+	}
+
+	/// Send many packets gathered into as few bundles as fit in datagrams of
+	/// at most max_size bytes.  Returns the number of datagrams sent.  Nothing
+	/// is sent if a message is too large to fit.
+	pub fn send_bundled(&self, packets: Vec<OscPacket>, time_tag: OscTimeTag, max_size: usize) -> Result<usize> {
+		self.send_datagrams(pack(packets, time_tag, max_size)?)
+	}
+
+	/// A snapshot of the counts of what has been sent.
+	pub fn stats(&self) -> SenderStats {
+		*self.stats.lock().unwrap()
+	}
+
+	/// Reset every count to zero.
+	pub fn reset_stats(&self) {
+		*self.stats.lock().unwrap() = SenderStats::default();
+	}
+
+	fn send_datagrams(&self, packets: Vec<OscPacket>) -> Result<usize> {
+		let count = packets.len();
+		for packet in packets {
+			self.send(packet)?;
+		}
+		Ok(count)
+	}
 
 }
 
-// convert an OscArg to its correpsonding type tag character
-fn arg_to_type_tag(arg: &OscArg) -> char {
+/// Convert an OscArg to its corresponding type tag character.
+pub fn arg_to_type_tag(arg: &OscArg) -> char {
 	match *arg {
 		OscInt(_) => 'i',
 		OscFloat(_) => 'f',
@@ -98,22 +174,22 @@ pub fn packet_to_buffer(packet: OscPacket) -> Vec<u8> {
 
 			//--- write the address string
 
-			buf.write(to_osc_string(addr).as_bytes()).unwrap();
+			buf.write_all(to_osc_string(addr).as_bytes()).unwrap();
 
 			//--- write the string of type tags
 
 			// starts with a comma
-			buf.write(&[',' as u8]).unwrap();
+			buf.write_all(b",").unwrap();
 
 			// convert all the args to type tags and write them
 			let tt_vec: Vec<u8> = args.iter().map(|a| arg_to_type_tag(a) as u8).collect();
-			buf.write( tt_vec.as_ref() ).unwrap();
+			buf.write_all( tt_vec.as_ref() ).unwrap();
 
 			// null-terminate type tag string
-			buf.write(&[0u8]).unwrap();
+			buf.write_all(&[0u8]).unwrap();
 
 			// pad with nulls to obey osc string spec
-			pad_with_null!(buf write args.len()+2);
+			pad_with_null!(buf write_all args.len()+2);
 
 			//--- write all the arguments
 
@@ -124,19 +200,16 @@ pub fn packet_to_buffer(packet: OscPacket) -> Vec<u8> {
 		OscBundle{time_tag, conts} => {
 
 			//--- write the bundle identifier string
-			buf.write("#bundle\0".as_bytes()).unwrap();
+			buf.write_all("#bundle\0".as_bytes()).unwrap();
 
 			//--- write the two parts of the time tag
-			match time_tag {
-				(sec, frac_sec) => {
-					buf.write_u32::<BigEndian>(sec).unwrap();
-					buf.write_u32::<BigEndian>(frac_sec).unwrap();
-				}
-			}
+			let (sec, frac_sec) = time_tag;
+			buf.write_u32::<BigEndian>(sec).unwrap();
+			buf.write_u32::<BigEndian>(frac_sec).unwrap();
 
 			//--- write each piece of the bundle payload, themselves Osc packets
 			for packet in conts.into_iter() {
-				buf.write(packet_to_buffer(packet).as_ref()).unwrap();
+				buf.write_all(packet_to_buffer(packet).as_ref()).unwrap();
 			}
 		}
 	}
@@ -173,11 +246,11 @@ fn write_arg(buf: &mut Vec<u8>, arg: OscArg) {
 	match arg {
 		OscInt(v) 	=> { buf.write_i32::<BigEndian>(v).unwrap(); },
 		OscFloat(v) => { buf.write_f32::<BigEndian>(v).unwrap(); },
-		OscStr(v) 	=> { buf.write(to_osc_string(v).as_bytes()).unwrap(); },
+		OscStr(v) 	=> { buf.write_all(to_osc_string(v).as_bytes()).unwrap(); },
 		OscBlob(v) 	=> {
 			buf.write_i32::<BigEndian>( v.len() as i32 ).unwrap();
-			buf.write(v.as_ref()).unwrap();
-			pad_with_null!(buf write v.len());
+			buf.write_all(v.as_ref()).unwrap();
+			pad_with_null!(buf write_all v.len());
 		}
 	}
 }
@@ -201,13 +274,13 @@ fn test_packet_to_buffer_message() {
 	let mut tbuf = Vec::new();
 	tbuf.write_i32::<BigEndian>(44);
 
-	tbuf.write("/test/addr\0\0".as_bytes());
-	tbuf.write(",ifsb\0\0\0".as_bytes());
+	tbuf.write_all("/test/addr\0\0".as_bytes());
+	tbuf.write_all(",ifsb\0\0\0".as_bytes());
 	tbuf.write_i32::<BigEndian>(123);
 	tbuf.write_f32::<BigEndian>(0.0);
-	tbuf.write("abc\0".as_bytes());
+	tbuf.write_all("abc\0".as_bytes());
 	tbuf.write_i32::<BigEndian>(5);
-	tbuf.write(vec!(1u8, 2u8, 3u8, 4u8, 5u8, 0u8, 0u8, 0u8).as_ref());
+	tbuf.write_all(vec!(1u8, 2u8, 3u8, 4u8, 5u8, 0u8, 0u8, 0u8).as_ref());
 
 	assert_eq!(buf, tbuf);
 }
@@ -241,32 +314,32 @@ fn test_packet_to_buffer_bundle() {
 	let mut tbuf = Vec::new();
 	tbuf.write_i32::<BigEndian>(96); // size of total packet
 
-	tbuf.write("#bundle\0".as_bytes());
+	tbuf.write_all("#bundle\0".as_bytes());
 	tbuf.write_u32::<BigEndian>(0);
 	tbuf.write_u32::<BigEndian>(1);
 
 	tbuf.write_i32::<BigEndian>(12); // size of first bundle element
-	tbuf.write("/t\0\0".as_bytes());
-	tbuf.write(",i\0\0".as_bytes());
+	tbuf.write_all("/t\0\0".as_bytes());
+	tbuf.write_all(",i\0\0".as_bytes());
 	tbuf.write_i32::<BigEndian>(123);
 
 	tbuf.write_i32::<BigEndian>(60); // size of second bundle element
 
-	tbuf.write("#bundle\0".as_bytes());
+	tbuf.write_all("#bundle\0".as_bytes());
 	tbuf.write_u32::<BigEndian>(123);
 	tbuf.write_u32::<BigEndian>(456);
 
 	tbuf.write_i32::<BigEndian>(16); // size of first bundle message
-	tbuf.write("/a\0\0".as_bytes());
-	tbuf.write(",fs\0".as_bytes());
+	tbuf.write_all("/a\0\0".as_bytes());
+	tbuf.write_all(",fs\0".as_bytes());
 	tbuf.write_f32::<BigEndian>(0.0);
-	tbuf.write("abc\0".as_bytes());
+	tbuf.write_all("abc\0".as_bytes());
 
 	tbuf.write_i32::<BigEndian>(20); // size of second bundle message
-	tbuf.write("/b\0\0".as_bytes());
-	tbuf.write(",b\0\0".as_bytes());
+	tbuf.write_all("/b\0\0".as_bytes());
+	tbuf.write_all(",b\0\0".as_bytes());
 	tbuf.write_i32::<BigEndian>(5);
-	tbuf.write(vec!(1u8, 2u8, 3u8, 4u8, 5u8, 0u8, 0u8, 0u8).as_ref());
+	tbuf.write_all(vec!(1u8, 2u8, 3u8, 4u8, 5u8, 0u8, 0u8, 0u8).as_ref());
 
 	assert_eq!(tbuf, res);
 }
@@ -303,9 +376,9 @@ fn test_write_arg(){
 	let mut tbuf = Vec::new();
 	tbuf.write_i32::<BigEndian>(123);
 	tbuf.write_f32::<BigEndian>(0.0);
-	tbuf.write("abc\0".as_bytes());
+	tbuf.write_all("abc\0".as_bytes());
 	tbuf.write_i32::<BigEndian>(5);
-	tbuf.write(vec!(1u8, 2u8, 3u8, 4u8, 5u8, 0u8, 0u8, 0u8).as_ref());
+	tbuf.write_all(vec!(1u8, 2u8, 3u8, 4u8, 5u8, 0u8, 0u8, 0u8).as_ref());
 
 	assert_eq!(buf, tbuf);
 
@@ -0,0 +1,436 @@
+//! Module for receiving OSC on several ports and transports at once.
+//!
+//! An OscServer owns any number of UDP and TCP listeners, each receiving on
+//! its own threads, and gathers everything they receive into one channel.
+//! Every packet is tagged with the id of the listener it arrived on and the
+//! address it came from.  Read them with recv, or hand them to a closure with
+//! serve.
+//!
+//! OSC over TCP needs framing.  OSC 1.0 prefixes each packet with its size as
+//! a 32 bit big-endian int; OSC 1.1 uses SLIP, ending each packet with a 0xC0
+//! byte.  Packets that fail to decode are skipped, and a TCP connection that
+//! sends a malformed frame is closed.  Each TCP listener serves at most 64
+//! connections at once; more are closed as soon as they are accepted.
+//!
+//! Every listener stops when the server's shutdown handle is shut down, after
+//! which recv gives an error once the packets already received are read.
+//! Dropping the server stops every listener and waits for its threads.
+
+extern crate byteorder;
+
+use std::net::{TcpListener, TcpStream, UdpSocket, SocketAddr, ToSocketAddrs};
+use std::io::{Error, Result, Read};
+use std::io::ErrorKind::{InvalidData, TimedOut, WouldBlock};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use self::byteorder::{BigEndian, ByteOrder};
+
+use data::*;
+use listener::{OscListener, ShutdownHandle};
+use receiver::{OscReceiver, datagram_to_packet};
+use sender::packet_to_buffer;
+use transport::Transport;
+
+// how often threads check whether they should stop
+const POLL_INTERVAL_MS: u64 = 100;
+// largest packet accepted over TCP
+const MAX_TCP_PACKET_SIZE: usize = 1 << 20;
+// connections each TCP listener serves at once
+const MAX_TCP_CONNECTIONS: usize = 64;
+
+const SLIP_END: u8 = 0xC0;
+const SLIP_ESC: u8 = 0xDB;
+const SLIP_ESC_END: u8 = 0xDC;
+const SLIP_ESC_ESC: u8 = 0xDD;
+
+/// Identifies one of a server's listeners.
+pub type ListenerId = usize;
+
+/// How packets are delimited on a TCP stream.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum TcpFraming {
+	/// Each packet is preceded by its size, as in OSC 1.0.
+	LengthPrefixed,
+	/// Packets are SLIP encoded, as in OSC 1.1.
+	Slip
+}
+
+impl TcpFraming {
+
+	/// Encode a packet as a frame for sending on a TCP stream.
+	pub fn encode(&self, packet: OscPacket) -> Vec<u8> {
+		let buf = packet_to_buffer(packet);
+		match *self {
+			TcpFraming::LengthPrefixed => buf,
+			TcpFraming::Slip => {
+				let mut frame = vec!(SLIP_END);
+				for &b in &buf[4..] {
+					match b {
+						SLIP_END => frame.extend_from_slice(&[SLIP_ESC, SLIP_ESC_END]),
+						SLIP_ESC => frame.extend_from_slice(&[SLIP_ESC, SLIP_ESC_ESC]),
+						b => frame.push(b)
+					}
+				}
+				frame.push(SLIP_END);
+				frame
+			}
+		}
+	}
+
+	// take every complete frame from the front of a buffer
+	fn decode(&self, buf: &mut Vec<u8>) -> Result<Vec<Vec<u8>>> {
+		let mut frames = Vec::new();
+		match *self {
+			TcpFraming::LengthPrefixed => {
+				while buf.len() >= 4 {
+					let size = BigEndian::read_i32(&buf[..4]);
+					if size < 0 || size as usize > MAX_TCP_PACKET_SIZE {
+						return Err(Error::new(InvalidData, format!("Invalid packet size {}.", size)));
+					}
+					let size = size as usize;
+					if buf.len() < 4 + size { break; }
+					frames.push(buf[4..4 + size].to_vec());
+					buf.drain(..4 + size);
+				}
+			},
+			TcpFraming::Slip => {
+				while let Some(end) = buf.iter().position(|&b| b == SLIP_END) {
+					let mut frame = Vec::with_capacity(end);
+					let mut escaped = false;
+					for &b in &buf[..end] {
+						match (escaped, b) {
+							(false, SLIP_ESC) => { escaped = true; continue; },
+							(true, SLIP_ESC_END) => frame.push(SLIP_END),
+							(true, SLIP_ESC_ESC) => frame.push(SLIP_ESC),
+							(true, _) => return Err(Error::new(InvalidData, "Invalid SLIP escape.")),
+							(false, b) => frame.push(b)
+						}
+						escaped = false;
+					}
+					buf.drain(..end + 1);
+					// frames may start with END as well as finish with one
+					if !frame.is_empty() {
+						frames.push(frame);
+					}
+				}
+				if buf.len() > MAX_TCP_PACKET_SIZE {
+					return Err(Error::new(InvalidData, "SLIP frame too large."));
+				}
+			}
+		}
+		Ok(frames)
+	}
+}
+
+/// A packet received by a server.
+#[derive(Debug,Clone,PartialEq)]
+pub struct ServerPacket {
+	/// The listener the packet arrived on.
+	pub listener: ListenerId,
+	/// The address the packet came from.
+	pub source: SocketAddr,
+	pub packet: OscPacket
+}
+
+/// Receives on several UDP and TCP listeners into a single channel.  The
+/// datagram listeners are UDP unless another Transport is chosen, in which
+/// case start from OscServer::default and add them with add_receiver.
+pub struct OscServer<S: Transport = UdpSocket> {
+	packets: Receiver<ServerPacket>,
+	sender: Sender<ServerPacket>,
+	local_addrs: Vec<SocketAddr>,
+	udp: Vec<OscListener<S>>,
+	handle: ShutdownHandle,
+	threads: Arc<Mutex<Vec<JoinHandle<()>>>>
+}
+
+impl OscServer {
+
+	/// Constructs a server with no listeners.
+	pub fn new() -> OscServer {
+		OscServer::default()
+	}
+
+	/// Start receiving UDP on an address.  Returns the new listener's id.
+	pub fn add_udp<T: ToSocketAddrs>(&mut self, addr: T) -> Result<ListenerId> {
+		self.add_receiver(OscReceiver::new(addr)?)
+	}
+}
+
+impl<S: Transport + Send + 'static> OscServer<S> {
+
+	/// Start receiving datagrams from a receiver.  Returns the new listener's
+	/// id.
+	pub fn add_receiver(&mut self, receiver: OscReceiver<S>) -> Result<ListenerId> {
+		let id = self.local_addrs.len();
+		let sender = self.sender.clone();
+		let listener = OscListener::spawn_with_handle(receiver, move |packet, source| {
+			let _ = sender.send(ServerPacket{listener: id, source, packet});
+		}, self.handle.clone())?;
+		self.local_addrs.push(listener.local_addr());
+		self.udp.push(listener);
+		Ok(id)
+	}
+
+	/// Start accepting TCP connections on an address.  Returns the new
+	/// listener's id.
+	pub fn add_tcp<T: ToSocketAddrs>(&mut self, addr: T, framing: TcpFraming) -> Result<ListenerId> {
+		let id = self.local_addrs.len();
+		let listener = TcpListener::bind(addr)?;
+		listener.set_nonblocking(true)?;
+		self.local_addrs.push(listener.local_addr()?);
+
+		let sender = self.sender.clone();
+		let handle = self.handle.clone();
+		let threads = self.threads.clone();
+		let connections = Arc::new(AtomicUsize::new(0));
+		let thread = thread::spawn(move || {
+			while !handle.is_shutdown() {
+				match listener.accept() {
+					Ok((stream, source)) => {
+						if connections.fetch_add(1, Ordering::SeqCst) >= MAX_TCP_CONNECTIONS {
+							connections.fetch_sub(1, Ordering::SeqCst);
+							continue;
+						}
+						let sender = sender.clone();
+						let handle = handle.clone();
+						let connections = connections.clone();
+						let connection = thread::spawn(move || {
+							let _ = read_stream(stream, source, id, framing, &sender, &handle);
+							connections.fetch_sub(1, Ordering::SeqCst);
+						});
+						let mut threads = threads.lock().unwrap();
+						threads.retain(|t| !t.is_finished());
+						threads.push(connection);
+					},
+					Err(ref e) if e.kind() == WouldBlock => thread::sleep(Duration::from_millis(POLL_INTERVAL_MS)),
+					Err(_) => break
+				}
+			}
+		});
+		self.threads.lock().unwrap().push(thread);
+		Ok(id)
+	}
+
+	/// The local address of a listener.
+	pub fn local_addr(&self, id: ListenerId) -> Option<SocketAddr> {
+		self.local_addrs.get(id).cloned()
+	}
+
+	/// Receive the next packet from any listener.  Blocks until one arrives
+	/// or the timeout passes, and gives an error if the server has been shut
+	/// down and every packet it received has been read.
+	pub fn recv(&self, timeout: Option<Duration>) -> Result<ServerPacket> {
+		let deadline = timeout.map(|t| Instant::now() + t);
+		let poll = Duration::from_millis(POLL_INTERVAL_MS);
+		loop {
+			if let Ok(packet) = self.packets.try_recv() {
+				return Ok(packet);
+			}
+			if self.handle.is_shutdown() {
+				return Err(Error::other("The server has been shut down."));
+			}
+			let wait = match deadline {
+				Some(deadline) => {
+					let now = Instant::now();
+					if now >= deadline {
+						return Err(Error::new(TimedOut, "Timed out waiting for a packet."));
+					}
+					(deadline - now).min(poll)
+				},
+				None => poll
+			};
+			if let Ok(packet) = self.packets.recv_timeout(wait) {
+				return Ok(packet);
+			}
+		}
+	}
+
+	/// Receive a packet from any listener if one is waiting, without blocking.
+	pub fn try_recv(&self) -> Option<ServerPacket> {
+		self.packets.try_recv().ok()
+	}
+
+	/// Hand every packet to a closure until the server is shut down through
+	/// its shutdown handle.
+	pub fn serve<F: FnMut(ServerPacket)>(&self, mut handler: F) {
+		let timeout = Duration::from_millis(POLL_INTERVAL_MS);
+		while !self.handle.is_shutdown() {
+			if let Ok(packet) = self.packets.recv_timeout(timeout) {
+				handler(packet);
+			}
+		}
+	}
+
+	/// A handle for stopping the server's threads and serve.
+	pub fn shutdown_handle(&self) -> ShutdownHandle {
+		self.handle.clone()
+	}
+}
+
+impl<S: Transport> Default for OscServer<S> {
+	fn default() -> OscServer<S> {
+		let (sender, packets) = channel();
+		OscServer{
+			packets,
+			sender,
+			local_addrs: Vec::new(),
+			udp: Vec::new(),
+			handle: ShutdownHandle::new(),
+			threads: Arc::new(Mutex::new(Vec::new()))
+		}
+	}
+}
+
+impl<S: Transport> Drop for OscServer<S> {
+	fn drop(&mut self) {
+		self.handle.shutdown();
+		self.udp.clear();
+		// accept threads can add connection threads until they finish, so
+		// keep going until there are none left
+		loop {
+			let thread = self.threads.lock().unwrap().pop();
+			match thread {
+				Some(t) => { let _ = t.join(); },
+				None => break
+			}
+		}
+	}
+}
+
+fn read_stream(mut stream: TcpStream, source: SocketAddr, id: ListenerId, framing: TcpFraming,
+	sender: &Sender<ServerPacket>, handle: &ShutdownHandle) -> Result<()> {
+
+	stream.set_nonblocking(false)?;
+	stream.set_read_timeout(Some(Duration::from_millis(POLL_INTERVAL_MS)))?;
+	let mut chunk = [0u8; 4096];
+	let mut buf = Vec::new();
+	while !handle.is_shutdown() {
+		match stream.read(&mut chunk) {
+			Ok(0) => break,
+			Ok(n) => {
+				buf.extend_from_slice(&chunk[..n]);
+				for frame in framing.decode(&mut buf)? {
+					if let Ok(packet) = datagram_to_packet(&frame) {
+						let _ = sender.send(ServerPacket{listener: id, source, packet});
+					}
+				}
+			},
+			Err(ref e) if e.kind() == WouldBlock || e.kind() == TimedOut => (),
+			Err(e) => return Err(e)
+		}
+	}
+	Ok(())
+}
+
+#[test]
+fn test_slip_round_trip() {
+	use data::OscPacket::*;
+	use data::OscArg::*;
+
+	let packet = OscMessage{addr: "/blob".to_string(), args: vec!(OscBlob(vec!(SLIP_END, SLIP_ESC, 1)))};
+	let mut frame = TcpFraming::Slip.encode(packet.clone());
+	assert!(frame[1..frame.len() - 1].iter().all(|&b| b != SLIP_END));
+
+	// split across reads
+	let mut buf = frame[..5].to_vec();
+	assert!(TcpFraming::Slip.decode(&mut buf).unwrap().is_empty());
+	buf.extend(frame.drain(5..));
+	let frames = TcpFraming::Slip.decode(&mut buf).unwrap();
+	assert_eq!(frames.len(), 1);
+	assert_eq!(datagram_to_packet(&frames[0]).unwrap(), packet);
+	assert!(buf.is_empty());
+
+	assert!(TcpFraming::Slip.decode(&mut vec!(SLIP_ESC, 1, SLIP_END)).is_err());
+	assert!(TcpFraming::LengthPrefixed.decode(&mut vec!(0xFF, 0, 0, 0)).is_err());
+}
+
+#[test]
+fn test_server() {
+	use std::io::{ErrorKind, Write};
+	use data::OscPacket::*;
+	use data::OscArg::*;
+	use sender::OscSender;
+
+	let timeout = Some(Duration::from_secs(5));
+	let mut server = OscServer::new();
+	let tablets = server.add_udp("127.0.0.1:0").unwrap();
+	let console = server.add_udp("127.0.0.1:0").unwrap();
+	let media = server.add_tcp("127.0.0.1:0", TcpFraming::LengthPrefixed).unwrap();
+	let media_slip = server.add_tcp("127.0.0.1:0", TcpFraming::Slip).unwrap();
+
+	let msg = |i| OscMessage{addr: "/x".to_string(), args: vec!(OscInt(i))};
+	let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+	let tablet = OscSender::new(localhost, server.local_addr(tablets).unwrap()).unwrap();
+	tablet.send(msg(1)).unwrap();
+	let received = server.recv(timeout).unwrap();
+	assert_eq!((received.listener, received.packet), (tablets, msg(1)));
+
+	OscSender::new(localhost, server.local_addr(console).unwrap()).unwrap().send(msg(2)).unwrap();
+	assert_eq!(server.recv(timeout).unwrap().listener, console);
+
+	let mut stream = TcpStream::connect(server.local_addr(media).unwrap()).unwrap();
+	let mut frames = TcpFraming::LengthPrefixed.encode(msg(3));
+	frames.extend(TcpFraming::LengthPrefixed.encode(msg(4)));
+	stream.write_all(&frames).unwrap();
+	for i in 3..5 {
+		let received = server.recv(timeout).unwrap();
+		assert_eq!(received.listener, media);
+		assert_eq!(received.source, stream.local_addr().unwrap());
+		assert_eq!(received.packet, msg(i));
+	}
+
+	let mut stream = TcpStream::connect(server.local_addr(media_slip).unwrap()).unwrap();
+	stream.write_all(&TcpFraming::Slip.encode(msg(5))).unwrap();
+	let received = server.recv(timeout).unwrap();
+	assert_eq!((received.listener, received.packet), (media_slip, msg(5)));
+
+	// serve until shut down from the handler
+	tablet.send(msg(6)).unwrap();
+	let handle = server.shutdown_handle();
+	let mut served = Vec::new();
+	server.serve(|p| { served.push(p.packet); handle.shutdown(); });
+	assert_eq!(served, vec!(msg(6)));
+
+	// the handle stops the UDP listeners as well as the TCP ones
+	assert_eq!(server.recv(timeout).unwrap_err().kind(), ErrorKind::Other);
+	let start = Instant::now();
+	while server.udp.iter().any(|l| l.is_running()) {
+		assert!(start.elapsed() < Duration::from_secs(5));
+		thread::sleep(Duration::from_millis(10));
+	}
+}
+
+#[test]
+fn test_tcp_connection_limit() {
+	let mut server = OscServer::new();
+	let id = server.add_tcp("127.0.0.1:0", TcpFraming::LengthPrefixed).unwrap();
+	let addr = server.local_addr(id).unwrap();
+
+	let _open: Vec<TcpStream> = (0..MAX_TCP_CONNECTIONS).map(|_| TcpStream::connect(addr).unwrap()).collect();
+	let mut extra = TcpStream::connect(addr).unwrap();
+	extra.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+	let mut buf = [0u8; 1];
+	assert_eq!(extra.read(&mut buf).unwrap(), 0);
+}
+
+#[test]
+fn test_server_over_loopback() {
+	use data::OscPacket::*;
+	use sender::OscSender;
+	use transport::{LoopbackNetwork, LoopbackSocket};
+
+	let network = LoopbackNetwork::new();
+	let mut server = OscServer::<LoopbackSocket>::default();
+	let id = server.add_receiver(OscReceiver::from_socket(network.bind("127.0.0.1:0").unwrap())).unwrap();
+	let sender = OscSender::from_socket(network.bind("127.0.0.1:0").unwrap(), server.local_addr(id).unwrap());
+
+	let msg = OscMessage{addr: "/x".to_string(), args: vec!()};
+	sender.send(msg.clone()).unwrap();
+	let received = server.recv(Some(Duration::from_secs(5))).unwrap();
+	assert_eq!((received.listener, received.packet), (id, msg));
+}
@@ -0,0 +1,213 @@
+//! Module for sending and receiving OSC on a single UDP socket.
+//!
+//! OscSender and OscReceiver each bind their own socket, so a device that
+//! replies to the port a message came from never reaches the receiver.  An
+//! OscSocket sends and receives on the same port.  It can send to a default
+//! destination with send, or anywhere with send_to, and receives with the same
+//! blocking and timeout behaviour as OscReceiver.
+//!
+//! Receiving goes through an OscReceiver, which is where the source policy
+//! and stats are set and read.  Works over any Transport, a UDP socket unless
+//! another is chosen.
+//!
+//! To use the socket from several threads, clone it with try_clone or split it
+//! into an OscSender and an OscReceiver sharing the port.  The read timeout
+//! belongs to the port, so only one of the handles should receive.
+
+use std::net::{UdpSocket, SocketAddr, ToSocketAddrs};
+use std::io::{Error, Result};
+use std::io::ErrorKind::{InvalidInput, NotConnected};
+use std::time::Duration;
+
+use data::*;
+use receiver::OscReceiver;
+use sender::{OscSender, packet_to_buffer};
+use transport::Transport;
+
+/// A socket that both sends and receives OSC.
+pub struct OscSocket<S: Transport = UdpSocket> {
+	receiver: OscReceiver<S>,
+	dest: Option<SocketAddr>
+}
+
+impl OscSocket {
+
+	/// Bind a socket with no default destination.
+	pub fn bind<T: ToSocketAddrs>(addr: T) -> Result<OscSocket> {
+		Ok(OscSocket::from_socket(UdpSocket::bind(addr)?))
+	}
+
+	/// Bind a socket sending to a default destination, like OscSender::new.
+	pub fn new<T: ToSocketAddrs>(local_addr: T, dest_addr: T) -> Result<OscSocket> {
+		let mut socket = OscSocket::bind(local_addr)?;
+		socket.set_dest(dest_addr)?;
+		Ok(socket)
+	}
+
+	/// Another handle to the same socket, with the same default destination.
+	/// The handle has its own receiver, so starts with no policy and empty
+	/// stats.
+	pub fn try_clone(&self) -> Result<OscSocket> {
+		Ok(OscSocket{receiver: OscReceiver::from_socket(self.receiver.get_ref().try_clone()?), dest: self.dest})
+	}
+
+	/// Split the socket into a sender to the default destination and a
+	/// receiver, both using the same port.  The receiver keeps the socket's
+	/// policy and stats.
+	pub fn split(self) -> Result<(OscSender<SocketAddr>, OscReceiver)> {
+		let dest = self.dest.ok_or_else(|| Error::new(NotConnected, "No destination set for the socket."))?;
+		let sender = OscSender::from_socket(self.receiver.get_ref().try_clone()?, dest);
+		Ok((sender, self.receiver))
+	}
+}
+
+impl<S: Transport> OscSocket<S> {
+
+	/// Constructs a new OscSocket from a socket that is already bound.
+	pub fn from_socket(socket: S) -> OscSocket<S> {
+		OscSocket{receiver: OscReceiver::from_socket(socket), dest: None}
+	}
+
+	/// Set the destination used by send.
+	pub fn set_dest<T: ToSocketAddrs>(&mut self, dest: T) -> Result<()> {
+		self.dest = Some(self.resolve(dest)?);
+		Ok(())
+	}
+
+	/// The destination used by send, if one has been set.
+	pub fn dest(&self) -> Option<SocketAddr> {
+		self.dest
+	}
+
+	/// The local address the socket is bound to.
+	pub fn local_addr(&self) -> Result<SocketAddr> {
+		self.receiver.local_addr()
+	}
+
+	/// Send a packet to the default destination.
+	pub fn send(&self, packet: OscPacket) -> Result<usize> {
+		match self.dest {
+			Some(dest) => self.receiver.get_ref().send_to(&packet_to_buffer(packet)[4..], dest),
+			None => Err(Error::new(NotConnected, "No destination set for the socket."))
+		}
+	}
+
+	/// Send a packet to a destination.
+	pub fn send_to<T: ToSocketAddrs>(&self, packet: OscPacket, dest: T) -> Result<usize> {
+		let dest = self.resolve(dest)?;
+		self.receiver.get_ref().send_to(&packet_to_buffer(packet)[4..], dest)
+	}
+
+	/// Receive a packet.  Blocks and times out in the same way as
+	/// OscReceiver::recv.
+	pub fn recv(&self, timeout: Option<Duration>) -> Result<OscPacket> {
+		self.receiver.recv(timeout)
+	}
+
+	/// Receive a packet along with the address it was sent from, which is
+	/// where a reply should go.
+	pub fn recv_from(&self, timeout: Option<Duration>) -> Result<(OscPacket, SocketAddr)> {
+		self.receiver.recv_from(timeout)
+	}
+
+	/// The receiver behind recv, for setting a source policy and reading
+	/// stats.
+	pub fn receiver(&self) -> &OscReceiver<S> {
+		&self.receiver
+	}
+
+	/// The underlying socket.
+	pub fn into_inner(self) -> S {
+		self.receiver.into_inner()
+	}
+
+	// pick an address of the same family as the socket
+	fn resolve<T: ToSocketAddrs>(&self, dest: T) -> Result<SocketAddr> {
+		let ipv4 = self.local_addr()?.is_ipv4();
+		dest.to_socket_addrs()?
+			.find(|a| a.is_ipv4() == ipv4)
+			.ok_or_else(|| Error::new(InvalidInput, "No destination address usable from this socket."))
+	}
+}
+
+#[test]
+fn test_reply_to_source_port() {
+	use data::OscPacket::*;
+	use data::OscArg::*;
+
+	let timeout = Some(Duration::from_secs(5));
+	let device = OscSocket::bind("127.0.0.1:0").unwrap();
+	let client = OscSocket::new("127.0.0.1:0", &device.local_addr().unwrap().to_string()).unwrap();
+
+	let request = OscMessage{addr: "/ch/01/mix/fader".to_string(), args: vec!()};
+	client.send(request.clone()).unwrap();
+	let (received, source) = device.recv_from(timeout).unwrap();
+	assert_eq!(received, request);
+	assert_eq!(source, client.local_addr().unwrap());
+
+	let reply = OscMessage{addr: "/ch/01/mix/fader".to_string(), args: vec!(OscFloat(0.75))};
+	device.send_to(reply.clone(), source).unwrap();
+	assert_eq!(client.recv(timeout).unwrap(), reply);
+
+	assert_eq!(device.send(reply).unwrap_err().kind(), NotConnected);
+}
+
+#[test]
+fn test_clone_and_split() {
+	use std::thread;
+	use data::OscPacket::*;
+	use data::OscArg::*;
+
+	let timeout = Some(Duration::from_secs(5));
+	let echo = OscSocket::bind("127.0.0.1:0").unwrap();
+	let mut socket = OscSocket::bind("127.0.0.1:0").unwrap();
+	socket.set_dest(echo.local_addr().unwrap()).unwrap();
+	let clone = socket.try_clone().unwrap();
+	assert_eq!(clone.dest(), socket.dest());
+
+	let echo_thread = thread::spawn(move || {
+		for _ in 0..2 {
+			let (packet, source) = echo.recv_from(timeout).unwrap();
+			echo.send_to(packet, source).unwrap();
+		}
+	});
+
+	let (sender, receiver) = socket.split().unwrap();
+	let msg = |i| OscMessage{addr: "/echo".to_string(), args: vec!(OscInt(i))};
+	let send_thread = thread::spawn(move || {
+		sender.send(msg(1)).unwrap();
+		clone.send(msg(2)).unwrap();
+	});
+	assert_eq!(receiver.recv(timeout).unwrap(), msg(1));
+	assert_eq!(receiver.recv(timeout).unwrap(), msg(2));
+	send_thread.join().unwrap();
+	echo_thread.join().unwrap();
+}
+
+#[test]
+fn test_recv_applies_policy_and_stats() {
+	use std::net::IpAddr;
+	use data::OscPacket::*;
+	use policy::{SourcePolicy, Subnet};
+	use transport::LoopbackNetwork;
+
+	let timeout = Some(Duration::from_secs(5));
+	let network = LoopbackNetwork::new();
+	let device = OscSocket::from_socket(network.bind("127.0.0.1:0").unwrap());
+	let allowed = OscSocket::from_socket(network.bind("127.0.0.1:0").unwrap());
+	let denied = OscSocket::from_socket(network.bind("127.0.0.2:0").unwrap());
+	let mut policy = SourcePolicy::new();
+	policy.allow(Subnet::new("127.0.0.1".parse::<IpAddr>().unwrap(), 32).unwrap());
+	device.receiver().set_policy(Some(policy));
+
+	let msg = OscMessage{addr: "/go".to_string(), args: vec!()};
+	let dest = device.local_addr().unwrap();
+	denied.send_to(msg.clone(), dest).unwrap();
+	allowed.receiver().get_ref().send_to(&[0; ::receiver::UDP_BUFFER_SIZE + 1], dest).unwrap();
+	allowed.send_to(msg.clone(), dest).unwrap();
+
+	assert_eq!(device.recv(timeout).unwrap_err().kind(), InvalidInput);
+	assert_eq!(device.recv_from(timeout).unwrap(), (msg, allowed.local_addr().unwrap()));
+	let stats = device.receiver().stats();
+	assert_eq!((stats.datagrams, stats.rejected, stats.truncated), (3, 1, 1));
+}
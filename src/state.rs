@@ -0,0 +1,298 @@
+//! Module for keeping the last-known value of every OSC address.
+//!
+//! An OscStateStore absorbs incoming packets and remembers, for each address,
+//! the last argument list it received and when.  It can answer address pattern
+//! queries, notify subscribers when a value changes, and save itself to a file
+//! so a restarted application can resend the full state to a console.
+//!
+//! Snapshots are text files with one address per line: the time it was last
+//! updated in seconds since the Unix epoch, then the message in the syntax of
+//! the text module.
+//!
+//! ```text
+//! 1700000000.250000 /mixer/1/fader ,f 0.5
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+use std::net::ToSocketAddrs;
+use std::io::{Error, Result, BufRead, BufReader, BufWriter, Write};
+use std::io::ErrorKind::InvalidInput;
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use data::*;
+use data::OscPacket::*;
+use data::OscArg::*;
+use pattern::addr_matches;
+use receiver::OscReceiver;
+use sender::OscSender;
+use transport::Transport;
+use text::parse_packet;
+
+/// The last-known value of an address.
+#[derive(Debug,Clone,PartialEq)]
+pub struct ParamState {
+	pub args: Vec<OscArg>,
+	pub updated: SystemTime
+}
+
+/// Sent to subscribers when the value of an address changes.
+#[derive(Debug,Clone,PartialEq)]
+pub struct StateChange {
+	pub addr: String,
+	/// The previous arguments, or None if the address is new.
+	pub old: Option<Vec<OscArg>>,
+	pub new: Vec<OscArg>
+}
+
+struct Subscriber {
+	pattern: String,
+	chan: Sender<StateChange>
+}
+
+/// A table of the last-known arguments for every address seen.
+#[derive(Default)]
+pub struct OscStateStore {
+	params: BTreeMap<String, ParamState>,
+	subscribers: Vec<Subscriber>
+}
+
+impl OscStateStore {
+
+	/// Constructs an empty store.
+	pub fn new() -> OscStateStore {
+		OscStateStore{params: BTreeMap::new(), subscribers: Vec::new()}
+	}
+
+	/// Record every message in a packet, including those inside bundles.
+	/// Returns the number of addresses whose value changed.
+	pub fn absorb(&mut self, packet: &OscPacket) -> usize {
+		self.absorb_at(packet, SystemTime::now())
+	}
+
+	/// Receive a packet from a receiver and absorb it, returning the packet.
+	/// Blocks and times out in the same way as OscReceiver::recv.
+	pub fn absorb_from<S: Transport>(&mut self, receiver: &OscReceiver<S>, timeout: Option<Duration>) -> Result<OscPacket> {
+		let packet = receiver.recv(timeout)?;
+		self.absorb(&packet);
+		Ok(packet)
+	}
+
+	/// Set the arguments for a single address.  Returns true if the value
+	/// changed.
+	pub fn set(&mut self, addr: &str, args: Vec<OscArg>) -> bool {
+		self.set_at(addr, args, SystemTime::now())
+	}
+
+	/// The last-known state of an address.
+	pub fn get(&self, addr: &str) -> Option<&ParamState> {
+		self.params.get(addr)
+	}
+
+	/// Every stored address matching an OSC address pattern, in address order.
+	pub fn query(&self, pattern: &str) -> Vec<(&str, &ParamState)> {
+		self.params.iter()
+			.filter(|&(addr, _)| addr_matches(pattern, addr))
+			.map(|(addr, state)| (addr.as_str(), state))
+			.collect()
+	}
+
+	/// The number of addresses stored.
+	pub fn len(&self) -> usize {
+		self.params.len()
+	}
+
+	/// Whether the store is empty.
+	pub fn is_empty(&self) -> bool {
+		self.params.is_empty()
+	}
+
+	/// Forget every stored value.  Subscribers are not notified.
+	pub fn clear(&mut self) {
+		self.params.clear();
+	}
+
+	/// Get a channel that receives every change to an address matching an OSC
+	/// address pattern.  Dropping the receiver unsubscribes.
+	pub fn subscribe(&mut self, pattern: &str) -> Receiver<StateChange> {
+		let (tx, rx) = channel();
+		self.subscribers.push(Subscriber{pattern: pattern.to_string(), chan: tx});
+		rx
+	}
+
+	/// One message per stored address, in address order, for resending the
+	/// full state.
+	pub fn messages(&self) -> Vec<OscPacket> {
+		self.params.iter()
+			.map(|(addr, state)| OscMessage{addr: addr.clone(), args: state.args.clone()})
+			.collect()
+	}
+
+	/// Send every stored value through a sender, one message per address.
+	/// Returns the number of messages sent.
+	pub fn send_all<T: ToSocketAddrs, S: Transport>(&self, sender: &OscSender<T, S>) -> Result<usize> {
+		let messages = self.messages();
+		for message in &messages {
+			sender.send(message.clone())?;
+		}
+		Ok(messages.len())
+	}
+
+	/// Write every stored value to a snapshot.
+	pub fn write_snapshot<W: Write>(&self, mut out: W) -> Result<()> {
+		for (addr, state) in &self.params {
+			let t = state.updated.duration_since(UNIX_EPOCH).unwrap_or_default();
+			let message = OscMessage{addr: addr.clone(), args: state.args.clone()};
+			writeln!(out, "{}.{:06} {}", t.as_secs(), t.subsec_micros(), message)?;
+		}
+		out.flush()
+	}
+
+	/// Save every stored value to a snapshot file.
+	pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+		self.write_snapshot(BufWriter::new(File::create(path)?))
+	}
+
+	/// Read a snapshot into the store, keeping the update times it records.
+	/// Subscribers are notified of any values that change.  Returns the number
+	/// of addresses read.
+	pub fn read_snapshot<R: BufRead>(&mut self, reader: R) -> Result<usize> {
+		let mut count = 0;
+		for line in reader.lines() {
+			let line = line?;
+			let line = line.trim();
+			if line.is_empty() { continue; }
+
+			let (time, message) = match line.find(' ') {
+				Some(i) => (&line[..i], &line[i + 1..]),
+				None => return Err(Error::new(InvalidInput, format!("Malformed snapshot line {}.", line)))
+			};
+			let updated = time.parse::<f64>().ok()
+				.and_then(|t| Duration::try_from_secs_f64(t).ok())
+				.and_then(|d| UNIX_EPOCH.checked_add(d))
+				.ok_or_else(|| Error::new(InvalidInput, format!("Invalid time {}.", time)))?;
+
+			match parse_packet(message)? {
+				OscMessage{addr, args} => { self.set_at(&addr, args, updated); },
+				OscBundle{..} => return Err(Error::new(InvalidInput, "Snapshots hold messages, not bundles."))
+			}
+			count += 1;
+		}
+		Ok(count)
+	}
+
+	/// Load a snapshot file into the store.
+	pub fn restore<P: AsRef<Path>>(&mut self, path: P) -> Result<usize> {
+		self.read_snapshot(BufReader::new(File::open(path)?))
+	}
+
+	fn absorb_at(&mut self, packet: &OscPacket, updated: SystemTime) -> usize {
+		match *packet {
+			OscMessage{ref addr, ref args} => self.set_at(addr, args.clone(), updated) as usize,
+			OscBundle{ref conts, ..} => conts.iter().map(|p| self.absorb_at(p, updated)).sum()
+		}
+	}
+
+	fn set_at(&mut self, addr: &str, args: Vec<OscArg>, updated: SystemTime) -> bool {
+		let old = self.params.insert(addr.to_string(), ParamState{args: args.clone(), updated});
+		let old = old.map(|s| s.args);
+		if old.as_ref() == Some(&args) {
+			return false;
+		}
+
+		let change = StateChange{addr: addr.to_string(), old, new: args};
+		// notify, and forget subscribers that have hung up
+		self.subscribers.retain(|sub| {
+			!addr_matches(&sub.pattern, addr) || sub.chan.send(change.clone()).is_ok()
+		});
+		true
+	}
+}
+
+#[test]
+fn test_absorb_and_query() {
+	let mut store = OscStateStore::new();
+	let packet = OscBundle{
+		time_tag: (0,1),
+		conts: vec!(
+			OscMessage{addr: "/mixer/1/fader".to_string(), args: vec!(OscFloat(0.5))},
+			OscMessage{addr: "/mixer/2/fader".to_string(), args: vec!(OscFloat(0.25))},
+			OscMessage{addr: "/mixer/1/mute".to_string(), args: vec!(OscInt(1))}
+		)
+	};
+	assert_eq!(store.absorb(&packet), 3);
+	assert_eq!(store.absorb(&packet), 0);
+	assert_eq!(store.len(), 3);
+
+	assert_eq!(store.get("/mixer/1/fader").unwrap().args, vec!(OscFloat(0.5)));
+	assert!(store.get("/mixer/3/fader").is_none());
+
+	let faders: Vec<&str> = store.query("/mixer/*/fader").into_iter().map(|(a, _)| a).collect();
+	assert_eq!(faders, vec!("/mixer/1/fader", "/mixer/2/fader"));
+}
+
+#[test]
+fn test_subscribe() {
+	let mut store = OscStateStore::new();
+	let faders = store.subscribe("/mixer/*/fader");
+	let dropped = store.subscribe("/mixer/*/*");
+	drop(dropped);
+
+	assert!(store.set("/mixer/1/fader", vec!(OscFloat(0.5))));
+	assert!(!store.set("/mixer/1/fader", vec!(OscFloat(0.5))));
+	assert!(store.set("/mixer/1/fader", vec!(OscFloat(0.75))));
+	assert!(store.set("/mixer/1/mute", vec!(OscInt(1))));
+
+	assert_eq!(faders.try_recv().unwrap(), StateChange{
+		addr: "/mixer/1/fader".to_string(), old: None, new: vec!(OscFloat(0.5))
+	});
+	assert_eq!(faders.try_recv().unwrap(), StateChange{
+		addr: "/mixer/1/fader".to_string(), old: Some(vec!(OscFloat(0.5))), new: vec!(OscFloat(0.75))
+	});
+	assert!(faders.try_recv().is_err());
+	assert_eq!(store.subscribers.len(), 1);
+}
+
+#[test]
+fn test_snapshot_round_trip() {
+	let mut store = OscStateStore::new();
+	store.set_at("/b", vec!(OscStr("two words".to_string()), OscBlob(vec!(1u8))), UNIX_EPOCH + Duration::from_millis(1500));
+	store.set_at("/a", vec!(OscInt(1)), UNIX_EPOCH + Duration::from_secs(1700000000));
+
+	let mut snapshot = Vec::new();
+	store.write_snapshot(&mut snapshot).unwrap();
+	assert_eq!(String::from_utf8(snapshot.clone()).unwrap(),
+		"1700000000.000000 /a ,i 1\n1.500000 /b ,sb \"two words\" 0x01\n");
+
+	let mut restored = OscStateStore::new();
+	assert_eq!(restored.read_snapshot(&snapshot[..]).unwrap(), 2);
+	assert_eq!(restored.messages(), store.messages());
+	assert_eq!(restored.get("/b").unwrap().updated, UNIX_EPOCH + Duration::from_millis(1500));
+
+	assert!(restored.read_snapshot("1.0 #bundle 0:1 [ ]".as_bytes()).is_err());
+	assert!(restored.read_snapshot("/a ,i 1".as_bytes()).is_err());
+	assert!(restored.read_snapshot("1e30 /a ,i 1".as_bytes()).is_err());
+	assert!(restored.read_snapshot("1e19 /a ,i 1".as_bytes()).is_err());
+	assert!(restored.read_snapshot("-1 /a ,i 1".as_bytes()).is_err());
+}
+
+#[test]
+fn test_send_all() {
+	use std::net::SocketAddr;
+
+	let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+	let receiver = OscReceiver::new(localhost).unwrap();
+	let sender = OscSender::new(localhost, receiver.local_addr().unwrap()).unwrap();
+
+	let mut store = OscStateStore::new();
+	store.set("/a", vec!(OscInt(1)));
+	store.set("/b", vec!(OscInt(2)));
+	assert_eq!(store.send_all(&sender).unwrap(), 2);
+
+	let mut mirror = OscStateStore::new();
+	mirror.absorb_from(&receiver, Some(Duration::from_secs(5))).unwrap();
+	mirror.absorb_from(&receiver, Some(Duration::from_secs(5))).unwrap();
+	assert_eq!(mirror.messages(), store.messages());
+}
@@ -0,0 +1,302 @@
+//! Module of the counters kept by receivers and senders.
+//!
+//! Every OscReceiver counts the datagrams and bytes it receives, datagrams
+//! too big for its buffer, datagrams its policy rejects and those that fail
+//! to decode, by the kind of error, along with datagrams per source IP and
+//! messages per address.  At most 1024 sources and 1024 addresses are counted
+//! one by one; beyond that, the one seen least recently is forgotten to make
+//! room.  Every OscSender counts the packets and bytes it sends and the sends
+//! that fail.  The stats methods of each return a snapshot of the counts.
+//!
+//! With the `prometheus` feature, prometheus_text formats snapshots from any
+//! number of receivers and senders in the Prometheus text exposition format.
+
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use data::*;
+use data::OscPacket::*;
+use util::Recency;
+
+// at most this many sources and addresses are counted one by one; beyond it
+// the least recently seen is forgotten
+const MAX_TRACKED_KEYS: usize = 1024;
+
+/// What was wrong with a datagram that failed to decode.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub enum DecodeErrorKind {
+	/// Shorter than the smallest possible packet.
+	TooShort,
+	/// The data ended in the middle of a value.
+	UnexpectedEnd,
+	/// A string without a terminating null, or not valid UTF-8.
+	BadString,
+	/// Type tags not starting with a comma.
+	BadTypeTags,
+	/// A type tag this library doesn't support.
+	UnsupportedType,
+	/// A bad bundle ID or bundle element size, or bundles nested too deeply.
+	BadBundle
+}
+
+impl DecodeErrorKind {
+	/// The kind of a decode error returned by the receiver, or None for
+	/// other errors.
+	pub fn of(error: &io::Error) -> Option<DecodeErrorKind> {
+		error.get_ref()?.downcast_ref::<DecodeError>().map(|e| e.kind)
+	}
+
+	/// A short name for the kind, as used in metric labels.
+	pub fn as_str(&self) -> &'static str {
+		match *self {
+			DecodeErrorKind::TooShort => "too_short",
+			DecodeErrorKind::UnexpectedEnd => "unexpected_end",
+			DecodeErrorKind::BadString => "bad_string",
+			DecodeErrorKind::BadTypeTags => "bad_type_tags",
+			DecodeErrorKind::UnsupportedType => "unsupported_type",
+			DecodeErrorKind::BadBundle => "bad_bundle"
+		}
+	}
+}
+
+/// The error inside the InvalidInput errors returned for datagrams that fail
+/// to decode.
+#[derive(Debug,Clone,PartialEq)]
+pub struct DecodeError {
+	kind: DecodeErrorKind,
+	message: String
+}
+
+impl DecodeError {
+	/// Constructs a decode error of a kind with a message.
+	pub fn new<M: ToString>(kind: DecodeErrorKind, message: M) -> DecodeError {
+		DecodeError{kind, message: message.to_string()}
+	}
+
+	/// What was wrong with the datagram.
+	pub fn kind(&self) -> DecodeErrorKind {
+		self.kind
+	}
+}
+
+impl fmt::Display for DecodeError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(&self.message)
+	}
+}
+
+impl error::Error for DecodeError {}
+
+/// A snapshot of the counts kept by an OscReceiver.
+#[derive(Debug,Clone,PartialEq,Default)]
+pub struct ReceiverStats {
+	/// Datagrams received, whether or not they were accepted and decoded.
+	pub datagrams: u64,
+	/// Bytes received, not counting what was cut off the end of
+	/// truncated datagrams.
+	pub bytes: u64,
+	/// Datagrams too big for the receive buffer, which are dropped.
+	pub truncated: u64,
+	/// Datagrams dropped by the receiver's policy.
+	pub rejected: u64,
+	/// Datagrams that failed to decode, by the kind of error.
+	pub decode_errors: BTreeMap<DecodeErrorKind, u64>,
+	/// Packets decoded.
+	pub packets: u64,
+	/// Messages decoded, counting each message inside bundles.
+	pub messages: u64,
+	/// Datagrams received from each source IP, for the 1024 seen most
+	/// recently.
+	pub sources: BTreeMap<IpAddr, u64>,
+	/// Messages decoded for each address, for the 1024 seen most recently.
+	pub addresses: BTreeMap<String, u64>,
+	// when each source and address was last counted, in counts of datagrams
+	// and messages
+	source_seen: Recency<IpAddr>,
+	address_seen: Recency<String>
+}
+
+impl ReceiverStats {
+
+	/// The number of datagrams that failed to decode.
+	pub fn decode_error_count(&self) -> u64 {
+		self.decode_errors.values().sum()
+	}
+
+	/// Count a datagram arriving.
+	pub(crate) fn count_datagram(&mut self, len: usize, source: SocketAddr) {
+		self.datagrams += 1;
+		self.bytes += len as u64;
+		count_key(&mut self.sources, &mut self.source_seen, &source.ip(), self.datagrams);
+	}
+
+	/// Count a decoded packet and the messages inside it.
+	pub(crate) fn count_packet(&mut self, packet: &OscPacket) {
+		self.packets += 1;
+		self.count_messages(packet);
+	}
+
+	/// Count a datagram that failed to decode.
+	pub(crate) fn count_decode_error(&mut self, kind: DecodeErrorKind) {
+		*self.decode_errors.entry(kind).or_insert(0) += 1;
+	}
+
+	fn count_messages(&mut self, packet: &OscPacket) {
+		match *packet {
+			OscMessage{ref addr, ..} => {
+				self.messages += 1;
+				count_key(&mut self.addresses, &mut self.address_seen, addr, self.messages);
+			},
+			OscBundle{ref conts, ..} => {
+				for p in conts {
+					self.count_messages(p);
+				}
+			}
+		}
+	}
+}
+
+// count a key, forgetting the least recently seen one to make room if there
+// are too many
+fn count_key<K: Ord + Clone>(counts: &mut BTreeMap<K, u64>, seen: &mut Recency<K>, key: &K, now: u64) {
+	match counts.get_mut(key) {
+		Some(n) => *n += 1,
+		None => {
+			if counts.len() >= MAX_TRACKED_KEYS {
+				if let Some(oldest) = seen.pop_oldest() {
+					counts.remove(&oldest);
+				}
+			}
+			counts.insert(key.clone(), 1);
+		}
+	}
+	seen.touch(key, now);
+}
+
+/// A snapshot of the counts kept by an OscSender.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Default)]
+pub struct SenderStats {
+	/// Datagrams sent.
+	pub packets: u64,
+	/// Bytes sent.
+	pub bytes: u64,
+	/// Sends that failed.
+	pub errors: u64
+}
+
+/// Format stats from named receivers and senders as Prometheus metrics, each
+/// labelled with its name.  The per-source and per-address metrics have a
+/// series for each source IP and address a receiver tracks, so up to 1024 of
+/// each per receiver; a series whose source or address is forgotten stops
+/// being reported.
+#[cfg(feature = "prometheus")]
+pub fn prometheus_text(receivers: &[(&str, &ReceiverStats)], senders: &[(&str, &SenderStats)]) -> String {
+	use std::fmt::Write;
+
+	let mut out = String::new();
+	{
+		let mut family = |name: &str, help: &str, samples: Vec<(String, u64)>| {
+			writeln!(out, "# HELP {} {}", name, help).unwrap();
+			writeln!(out, "# TYPE {} counter", name).unwrap();
+			for (labels, value) in samples {
+				writeln!(out, "{}{{{}}} {}", name, labels, value).unwrap();
+			}
+		};
+		let receiver_totals = |f: &dyn Fn(&ReceiverStats) -> u64| -> Vec<(String, u64)> {
+			receivers.iter().map(|&(name, s)| (label("receiver", name), f(s))).collect()
+		};
+
+		family("osc_receiver_datagrams_total", "Datagrams received.", receiver_totals(&|s| s.datagrams));
+		family("osc_receiver_bytes_total", "Bytes received.", receiver_totals(&|s| s.bytes));
+		family("osc_receiver_truncated_total", "Datagrams dropped for being too big for the receive buffer.",
+			receiver_totals(&|s| s.truncated));
+		family("osc_receiver_rejected_total", "Datagrams dropped by the source policy.", receiver_totals(&|s| s.rejected));
+		family("osc_receiver_decode_errors_total", "Datagrams that failed to decode.",
+			receivers.iter().flat_map(|&(name, s)| s.decode_errors.iter().map(move |(kind, &n)|
+				(format!("{},{}", label("receiver", name), label("kind", kind.as_str())), n))).collect());
+		family("osc_receiver_packets_total", "Packets decoded.", receiver_totals(&|s| s.packets));
+		family("osc_receiver_messages_total", "Messages decoded, including those inside bundles.",
+			receiver_totals(&|s| s.messages));
+		family("osc_receiver_source_datagrams_total", "Datagrams received from each source IP.",
+			receivers.iter().flat_map(|&(name, s)| s.sources.iter().map(move |(source, &n)|
+				(format!("{},{}", label("receiver", name), label("source", &source.to_string())), n))).collect());
+		family("osc_receiver_address_messages_total", "Messages decoded for each address.",
+			receivers.iter().flat_map(|&(name, s)| s.addresses.iter().map(move |(addr, &n)|
+				(format!("{},{}", label("receiver", name), label("address", addr)), n))).collect());
+
+		let sender_totals = |f: &dyn Fn(&SenderStats) -> u64| -> Vec<(String, u64)> {
+			senders.iter().map(|&(name, s)| (label("sender", name), f(s))).collect()
+		};
+		family("osc_sender_packets_total", "Datagrams sent.", sender_totals(&|s| s.packets));
+		family("osc_sender_bytes_total", "Bytes sent.", sender_totals(&|s| s.bytes));
+		family("osc_sender_errors_total", "Sends that failed.", sender_totals(&|s| s.errors));
+	}
+	out
+}
+
+// a label with its value escaped as the exposition format requires
+#[cfg(feature = "prometheus")]
+fn label(name: &str, value: &str) -> String {
+	let escaped = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+	format!("{}=\"{}\"", name, escaped)
+}
+
+#[test]
+fn test_count_messages() {
+	use data::OscArg::*;
+
+	let source: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+	let mut stats = ReceiverStats::default();
+	let fader = OscMessage{addr: "/fader".to_string(), args: vec!(OscFloat(0.5))};
+	stats.count_datagram(24, source);
+	stats.count_packet(&OscBundle{time_tag: (0, 1), conts: vec!(fader.clone(), fader.clone())});
+	stats.count_datagram(12, source);
+	stats.count_decode_error(DecodeErrorKind::UnexpectedEnd);
+
+	assert_eq!(stats.datagrams, 2);
+	assert_eq!(stats.bytes, 36);
+	assert_eq!(stats.packets, 1);
+	assert_eq!(stats.messages, 2);
+	assert_eq!(stats.decode_error_count(), 1);
+	assert_eq!(stats.sources[&source.ip()], 2);
+	assert_eq!(stats.addresses["/fader"], 2);
+}
+
+#[test]
+fn test_forget_least_recent_keys() {
+	let mut stats = ReceiverStats::default();
+	let source = |i: usize| SocketAddr::new(IpAddr::V4((0x0a00_0000 + i as u32).into()), 5000);
+	for i in 0..MAX_TRACKED_KEYS + 10 {
+		stats.count_datagram(8, source(i));
+		// the first source stays recent
+		stats.count_datagram(8, source(0));
+		// ports don't make a source new
+		stats.count_datagram(8, SocketAddr::new(source(0).ip(), 6000 + i as u16));
+	}
+	assert_eq!(stats.sources.len(), MAX_TRACKED_KEYS);
+	assert_eq!(stats.sources[&source(0).ip()], 2 * (MAX_TRACKED_KEYS as u64 + 10) + 1);
+	assert!(!stats.sources.contains_key(&source(1).ip()));
+	assert_eq!(stats.sources[&source(MAX_TRACKED_KEYS + 9).ip()], 1);
+	assert_eq!(stats.datagrams, 3 * (MAX_TRACKED_KEYS as u64 + 10));
+}
+
+#[cfg(feature = "prometheus")]
+#[test]
+fn test_prometheus_text() {
+	let mut stats = ReceiverStats::default();
+	stats.count_datagram(16, "10.0.0.2:5000".parse().unwrap());
+	stats.count_decode_error(DecodeErrorKind::TooShort);
+	stats.addresses.insert("/say \"hi\"".to_string(), 3);
+	let sent = SenderStats{packets: 4, bytes: 64, errors: 1};
+
+	let text = prometheus_text(&[("lighting", &stats)], &[("console", &sent)]);
+	assert!(text.contains("# TYPE osc_receiver_datagrams_total counter\nosc_receiver_datagrams_total{receiver=\"lighting\"} 1\n"));
+	assert!(text.contains("osc_receiver_decode_errors_total{receiver=\"lighting\",kind=\"too_short\"} 1\n"));
+	assert!(text.contains("osc_receiver_source_datagrams_total{receiver=\"lighting\",source=\"10.0.0.2\"} 1\n"));
+	assert!(text.contains("osc_receiver_address_messages_total{receiver=\"lighting\",address=\"/say \\\"hi\\\"\"} 3\n"));
+	assert!(text.contains("osc_sender_errors_total{sender=\"console\"} 1\n"));
+	assert_eq!(text.matches("# HELP").count(), 12);
+}
@@ -0,0 +1,244 @@
+//! Module for mirroring a set of addresses with a peer without feedback loops.
+//!
+//! An OscSync keeps the last-known value of each mirrored address.  Local
+//! changes are sent to the peer only if they differ from that value, and not if
+//! they repeat a value the peer sent within the echo window, so a motorized
+//! fader that reports back the position it was just driven to doesn't bounce
+//! it back and forth.  Values received from the peer are returned from poll
+//! for the application to apply, and are never echoed back.
+//!
+//! On connect, an OscSync sends a hello message to its peer.  A peer that
+//! receives a hello resends its full state, so a restarted peer catches up.
+
+use std::collections::HashMap;
+use std::net::{UdpSocket, ToSocketAddrs};
+use std::io::Result;
+use std::time::{Duration, Instant};
+
+use data::*;
+use data::OscPacket::*;
+use data::OscArg::*;
+use pattern::addr_matches;
+use receiver::OscReceiver;
+use sender::OscSender;
+use state::{OscStateStore, StateChange};
+use transport::Transport;
+
+/// Address of the message asking a peer to resend its full state.
+pub const SYNC_HELLO_ADDR: &str = "/osc-sync/hello";
+
+const DEFAULT_ECHO_WINDOW_MS: u64 = 500;
+
+/// Mirrors addresses matching a set of patterns with a single peer, over UDP
+/// unless the receiver and sender use another Transport.
+pub struct OscSync<T: ToSocketAddrs, S: Transport = UdpSocket> {
+	receiver: OscReceiver<S>,
+	sender: OscSender<T, S>,
+	patterns: Vec<String>,
+	state: OscStateStore,
+	// values recently received from the peer, by address
+	received: HashMap<String, Vec<(Vec<OscArg>, Instant)>>,
+	echo_window: Duration
+}
+
+impl<T: ToSocketAddrs, S: Transport> OscSync<T, S> {
+
+	/// Constructs a new OscSync receiving from the peer on one socket and
+	/// sending to it on another.  Nothing is mirrored until patterns are added.
+	pub fn new(receiver: OscReceiver<S>, sender: OscSender<T, S>) -> Self {
+		OscSync{
+			receiver,
+			sender,
+			patterns: Vec::new(),
+			state: OscStateStore::new(),
+			received: HashMap::new(),
+			echo_window: Duration::from_millis(DEFAULT_ECHO_WINDOW_MS)
+		}
+	}
+
+	/// Mirror every address matching an OSC address pattern.
+	pub fn mirror(&mut self, pattern: &str) {
+		self.patterns.push(pattern.to_string());
+	}
+
+	/// Set how long after receiving a value a local change back to that value
+	/// is treated as an echo.  Defaults to half a second.
+	pub fn set_echo_window(&mut self, window: Duration) {
+		self.echo_window = window;
+	}
+
+	/// Whether an address is mirrored.
+	pub fn is_mirrored(&self, addr: &str) -> bool {
+		self.patterns.iter().any(|p| addr_matches(p, addr))
+	}
+
+	/// The last-known values of the mirrored addresses.
+	pub fn state(&self) -> &OscStateStore {
+		&self.state
+	}
+
+	/// Ask the peer to send its full state.
+	pub fn connect(&self) -> Result<()> {
+		self.sender.send(OscMessage{addr: SYNC_HELLO_ADDR.to_string(), args: vec!()}).map(|_| ())
+	}
+
+	/// Report a local change.  Returns true if it was sent to the peer, false
+	/// if the address isn't mirrored, the value hasn't changed, or it echoes a
+	/// value recently received from the peer.
+	pub fn set(&mut self, addr: &str, args: Vec<OscArg>) -> Result<bool> {
+		if !self.is_mirrored(addr) || self.is_echo(addr, &args) {
+			return Ok(false);
+		}
+		if !self.state.set(addr, args.clone()) {
+			return Ok(false);
+		}
+		self.sender.send(OscMessage{addr: addr.to_string(), args})?;
+		Ok(true)
+	}
+
+	/// Send the value of every mirrored address to the peer.  Returns the
+	/// number of messages sent.
+	pub fn resync(&self) -> Result<usize> {
+		self.state.send_all(&self.sender)
+	}
+
+	/// Receive one packet from the peer and return the changes it makes to
+	/// mirrored addresses.  Answers hello messages by resending the full
+	/// state.  Blocks and times out in the same way as OscReceiver::recv.
+	pub fn poll(&mut self, timeout: Option<Duration>) -> Result<Vec<StateChange>> {
+		self.forget_old_echoes();
+		let packet = self.receiver.recv(timeout)?;
+		let mut changes = Vec::new();
+		self.absorb(packet, &mut changes)?;
+		Ok(changes)
+	}
+
+	fn absorb(&mut self, packet: OscPacket, changes: &mut Vec<StateChange>) -> Result<()> {
+		match packet {
+			OscMessage{ref addr, ..} if addr == SYNC_HELLO_ADDR => { self.resync()?; },
+			OscMessage{addr, args} => {
+				if !self.is_mirrored(&addr) { return Ok(()); }
+
+				let now = Instant::now();
+				let window = self.echo_window;
+				let recent = self.received.entry(addr.clone()).or_default();
+				recent.retain(|&(_, t)| now.duration_since(t) < window);
+				recent.push((args.clone(), now));
+
+				let old = self.state.get(&addr).map(|s| s.args.clone());
+				if self.state.set(&addr, args.clone()) {
+					changes.push(StateChange{addr, old, new: args});
+				}
+			},
+			OscBundle{conts, ..} => {
+				for p in conts { self.absorb(p, changes)?; }
+			}
+		}
+		Ok(())
+	}
+
+	// drop received values that have left the echo window, so addresses that
+	// are never received again don't keep theirs forever
+	fn forget_old_echoes(&mut self) {
+		let now = Instant::now();
+		let window = self.echo_window;
+		self.received.retain(|_, recent| {
+			recent.retain(|&(_, t)| now.duration_since(t) < window);
+			!recent.is_empty()
+		});
+	}
+
+	fn is_echo(&self, addr: &str, args: &[OscArg]) -> bool {
+		let now = Instant::now();
+		self.received.get(addr).is_some_and(|recent| {
+			recent.iter().any(|(v, t)| now.duration_since(*t) < self.echo_window && v[..] == *args)
+		})
+	}
+}
+
+#[cfg(test)]
+fn sync_pair() -> (OscSync<std::net::SocketAddr>, OscSync<std::net::SocketAddr>) {
+	use std::net::SocketAddr;
+
+	let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+	let a_in = OscReceiver::new(localhost).unwrap();
+	let b_in = OscReceiver::new(localhost).unwrap();
+	let a_out = OscSender::new(localhost, b_in.local_addr().unwrap()).unwrap();
+	let b_out = OscSender::new(localhost, a_in.local_addr().unwrap()).unwrap();
+
+	let mut a = OscSync::new(a_in, a_out);
+	let mut b = OscSync::new(b_in, b_out);
+	a.mirror("/fader/*");
+	b.mirror("/fader/*");
+	(a, b)
+}
+
+#[test]
+fn test_sync_suppresses_echoes() {
+	let timeout = Some(Duration::from_secs(5));
+	let (mut a, mut b) = sync_pair();
+
+	// unmirrored and unchanged values aren't sent
+	assert!(!a.set("/other", vec!(OscInt(1))).unwrap());
+	assert!(a.set("/fader/1", vec!(OscFloat(0.5))).unwrap());
+	assert!(!a.set("/fader/1", vec!(OscFloat(0.5))).unwrap());
+
+	assert_eq!(b.poll(timeout).unwrap(), vec!(StateChange{
+		addr: "/fader/1".to_string(), old: None, new: vec!(OscFloat(0.5))
+	}));
+
+	// b's fader moves to 0.5 and then reports it; that's not sent back
+	assert!(!b.set("/fader/1", vec!(OscFloat(0.5))).unwrap());
+
+	// a lagging report of an older value is still an echo
+	assert!(a.set("/fader/1", vec!(OscFloat(0.6))).unwrap());
+	assert_eq!(b.poll(timeout).unwrap().len(), 1);
+	assert!(!b.set("/fader/1", vec!(OscFloat(0.5))).unwrap());
+
+	// a real move on b's side goes through
+	assert!(b.set("/fader/1", vec!(OscFloat(0.9))).unwrap());
+	assert_eq!(a.poll(timeout).unwrap()[0].new, vec!(OscFloat(0.9)));
+
+	// once the window has passed, returning to an old value is a real move
+	b.set_echo_window(Duration::from_millis(0));
+	assert!(b.set("/fader/1", vec!(OscFloat(0.5))).unwrap());
+}
+
+#[test]
+fn test_sync_hello_resends_state() {
+	let timeout = Some(Duration::from_secs(5));
+	let (mut a, mut b) = sync_pair();
+
+	b.set("/fader/1", vec!(OscFloat(0.25))).unwrap();
+	b.set("/fader/2", vec!(OscFloat(0.75))).unwrap();
+	a.poll(timeout).unwrap();
+	a.poll(timeout).unwrap();
+
+	// a restarts with no state and says hello
+	let (a_in, a_out) = (a.receiver, a.sender);
+	let mut a = OscSync::new(a_in, a_out);
+	a.mirror("/fader/*");
+	a.connect().unwrap();
+
+	assert!(b.poll(timeout).unwrap().is_empty());
+	a.poll(timeout).unwrap();
+	a.poll(timeout).unwrap();
+	assert_eq!(a.state().messages(), b.state().messages());
+}
+
+#[test]
+fn test_sync_forgets_old_echoes() {
+	let timeout = Some(Duration::from_secs(5));
+	let (mut a, mut b) = sync_pair();
+
+	a.set("/fader/1", vec!(OscFloat(0.5))).unwrap();
+	a.set("/fader/2", vec!(OscFloat(0.5))).unwrap();
+	b.poll(timeout).unwrap();
+	b.poll(timeout).unwrap();
+	assert_eq!(b.received.len(), 2);
+
+	// polling drops values outside the window, even if nothing arrives
+	b.set_echo_window(Duration::from_millis(0));
+	assert!(b.poll(Some(Duration::from_millis(10))).is_err());
+	assert!(b.received.is_empty());
+}
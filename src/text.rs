@@ -0,0 +1,395 @@
+//! Module for printing and parsing OSC packets in a human-readable text form.
+//!
+//! A message is written as its address, its type tag string and then one token
+//! per argument, in the style of `oscsend` and `oscdump`:
+//!
+//! ```text
+//! /mixer/1/fader ,fis 0.5 3 "hello"
+//! ```
+//!
+//! Ints are written in decimal, floats in the shortest form that reads back to
+//! the same value, strings are double-quoted with backslash escapes, and blobs
+//! are written as `0x` followed by two hex digits per byte.  A bundle is written
+//! as `#bundle`, its time tag as `seconds:fraction` (both raw 32-bit words, so
+//! the time tag `(123, 456)` is written `123:456`) and its contents in brackets:
+//!
+//! ```text
+//! #bundle 123:456 [ /a ,i 1 /b ,sb "x" 0x0102 ]
+//! ```
+//!
+//! The type tag string of a message without arguments may be left out when
+//! parsing.  Addresses that are empty or contain whitespace are quoted like
+//! strings.
+
+use std::fmt;
+use std::str::FromStr;
+use std::iter::Peekable;
+use std::vec::IntoIter;
+
+use std::io::{Error, Result};
+use std::io::ErrorKind::InvalidInput;
+
+use data::*;
+use data::OscPacket::*;
+use data::OscArg::*;
+
+use sender::arg_to_type_tag;
+
+const BUNDLE_TOKEN: &str = "#bundle";
+const OPEN_TOKEN: &str = "[";
+const CLOSE_TOKEN: &str = "]";
+
+impl fmt::Display for OscArg {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			OscInt(v) => write!(f, "{}", v),
+			// Debug formatting gives the shortest string that parses back exactly
+			OscFloat(v) => write!(f, "{:?}", v),
+			OscStr(ref v) => write_quoted(f, v),
+			OscBlob(ref v) => {
+				f.write_str("0x")?;
+				for byte in v {
+					write!(f, "{:02x}", byte)?;
+				}
+				Ok(())
+			}
+		}
+	}
+}
+
+impl fmt::Display for OscPacket {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			OscMessage{ref addr, ref args} => {
+				if addr_needs_quotes(addr) {
+					write_quoted(f, addr)?;
+				}
+				else {
+					f.write_str(addr)?;
+				}
+				f.write_str(" ,")?;
+				for arg in args {
+					write!(f, "{}", arg_to_type_tag(arg))?;
+				}
+				for arg in args {
+					write!(f, " {}", arg)?;
+				}
+				Ok(())
+			},
+			OscBundle{time_tag: (sec, frac_sec), ref conts} => {
+				write!(f, "{} {}:{} {}", BUNDLE_TOKEN, sec, frac_sec, OPEN_TOKEN)?;
+				for packet in conts {
+					write!(f, " {}", packet)?;
+				}
+				write!(f, " {}", CLOSE_TOKEN)
+			}
+		}
+	}
+}
+
+impl FromStr for OscPacket {
+	type Err = Error;
+
+	fn from_str(s: &str) -> Result<OscPacket> {
+		parse_packet(s)
+	}
+}
+
+/// Parse a single packet from its text form.  Returns Err if the text is not a
+/// well-formed packet or if anything follows the packet.
+pub fn parse_packet(s: &str) -> Result<OscPacket> {
+	let mut tokens = tokenize(s)?.into_iter().peekable();
+	let packet = read_packet(&mut tokens)?;
+	match tokens.next() {
+		None => Ok(packet),
+		Some(t) => Err(parse_err(format!("Unexpected trailing input {}.", t.text)))
+	}
+}
+
+// a whitespace-delimited word, remembering whether it was written in quotes
+struct Token {
+	text: String,
+	quoted: bool
+}
+
+type Tokens = Peekable<IntoIter<Token>>;
+
+fn parse_err<S: Into<String>>(msg: S) -> Error {
+	Error::new(InvalidInput, msg.into())
+}
+
+// split the input on whitespace, treating a double-quoted run as one token
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+	let mut tokens = Vec::new();
+	let mut chars = s.chars().peekable();
+
+	while let Some(&c) = chars.peek() {
+		if c.is_whitespace() {
+			chars.next();
+		}
+		else if c == '"' {
+			chars.next();
+			tokens.push(Token{text: read_quoted(&mut chars)?, quoted: true});
+		}
+		else {
+			let mut text = String::new();
+			while let Some(&c) = chars.peek() {
+				if c.is_whitespace() { break; }
+				text.push(c);
+				chars.next();
+			}
+			tokens.push(Token{text, quoted: false});
+		}
+	}
+	Ok(tokens)
+}
+
+// read the body of a quoted string, the opening quote having been consumed
+fn read_quoted<I: Iterator<Item=char>>(chars: &mut I) -> Result<String> {
+	let mut text = String::new();
+	loop {
+		match chars.next() {
+			None => return Err(parse_err("Unterminated string.")),
+			Some('"') => return Ok(text),
+			Some('\\') => {
+				let escaped = match chars.next() {
+					Some('n') => '\n',
+					Some('r') => '\r',
+					Some('t') => '\t',
+					Some('\\') => '\\',
+					Some('"') => '"',
+					Some('u') => read_unicode_escape(chars)?,
+					Some(c) => return Err(parse_err(format!("Unknown escape \\{}.", c))),
+					None => return Err(parse_err("Unterminated string."))
+				};
+				text.push(escaped);
+			},
+			Some(c) => text.push(c)
+		}
+	}
+}
+
+// read the {hex} part of a \u{hex} escape
+fn read_unicode_escape<I: Iterator<Item=char>>(chars: &mut I) -> Result<char> {
+	if chars.next() != Some('{') {
+		return Err(parse_err("Malformed unicode escape."));
+	}
+	let mut hex = String::new();
+	loop {
+		match chars.next() {
+			Some('}') => break,
+			Some(c) => hex.push(c),
+			None => return Err(parse_err("Malformed unicode escape."))
+		}
+	}
+	u32::from_str_radix(&hex, 16).ok()
+		.and_then(std::char::from_u32)
+		.ok_or_else(|| parse_err(format!("Invalid unicode escape {}.", hex)))
+}
+
+fn write_quoted(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+	f.write_str("\"")?;
+	for c in s.chars() {
+		match c {
+			'\n' => f.write_str("\\n")?,
+			'\r' => f.write_str("\\r")?,
+			'\t' => f.write_str("\\t")?,
+			'\\' => f.write_str("\\\\")?,
+			'"' => f.write_str("\\\"")?,
+			c if c.is_control() => write!(f, "\\u{{{:x}}}", c as u32)?,
+			c => write!(f, "{}", c)?
+		}
+	}
+	f.write_str("\"")
+}
+
+// addresses are written bare unless that would make them unreadable
+fn addr_needs_quotes(addr: &str) -> bool {
+	addr.is_empty()
+		|| addr == BUNDLE_TOKEN
+		|| addr == OPEN_TOKEN
+		|| addr == CLOSE_TOKEN
+		|| addr.starts_with('"')
+		|| addr.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+fn next_token(tokens: &mut Tokens, what: &str) -> Result<Token> {
+	tokens.next().ok_or_else(|| parse_err(format!("Expected {}, found end of input.", what)))
+}
+
+fn read_packet(tokens: &mut Tokens) -> Result<OscPacket> {
+	let first = next_token(tokens, "an address or #bundle")?;
+	if !first.quoted && first.text == BUNDLE_TOKEN {
+		read_bundle(tokens)
+	}
+	else if !first.quoted && (first.text == OPEN_TOKEN || first.text == CLOSE_TOKEN) {
+		Err(parse_err(format!("Unexpected {}.", first.text)))
+	}
+	else {
+		read_message(first.text, tokens)
+	}
+}
+
+fn read_bundle(tokens: &mut Tokens) -> Result<OscPacket> {
+	let time_tag = parse_time_tag(&next_token(tokens, "a time tag")?.text)?;
+
+	let open = next_token(tokens, OPEN_TOKEN)?;
+	if open.quoted || open.text != OPEN_TOKEN {
+		return Err(parse_err(format!("Expected {}, found {}.", OPEN_TOKEN, open.text)));
+	}
+
+	let mut conts = Vec::new();
+	loop {
+		match tokens.peek() {
+			None => return Err(parse_err("Unterminated bundle.")),
+			Some(t) if !t.quoted && t.text == CLOSE_TOKEN => break,
+			Some(_) => conts.push(read_packet(tokens)?)
+		}
+	}
+	tokens.next();
+
+	Ok(OscBundle{time_tag, conts})
+}
+
+fn parse_time_tag(s: &str) -> Result<OscTimeTag> {
+	let mut parts = s.splitn(2, ':');
+	let sec = parts.next().and_then(|p| p.parse::<u32>().ok());
+	let frac_sec = parts.next().and_then(|p| p.parse::<u32>().ok());
+	match (sec, frac_sec) {
+		(Some(sec), Some(frac_sec)) => Ok((sec, frac_sec)),
+		_ => Err(parse_err(format!("Invalid time tag {}.", s)))
+	}
+}
+
+fn read_message(addr: String, tokens: &mut Tokens) -> Result<OscPacket> {
+	// a message with no arguments may leave off its type tags
+	let type_tags = match tokens.peek() {
+		Some(t) if !t.quoted && t.text.starts_with(',') => t.text[1..].to_string(),
+		_ => return Ok(OscMessage{addr, args: Vec::new()})
+	};
+	tokens.next();
+
+	let mut args = Vec::with_capacity(type_tags.len());
+	for tt in type_tags.chars() {
+		let token = next_token(tokens, "an argument")?;
+		args.push(parse_arg(tt, token)?);
+	}
+
+	Ok(OscMessage{addr, args})
+}
+
+fn parse_arg(type_tag: char, token: Token) -> Result<OscArg> {
+	let invalid = |token: &Token| {
+		parse_err(format!("Invalid argument {} for type tag {}.", token.text, type_tag))
+	};
+
+	match type_tag {
+		's' if token.quoted => Ok(OscStr(token.text)),
+		_ if token.quoted => Err(invalid(&token)),
+		'i' => token.text.parse().map(OscInt).map_err(|_| invalid(&token)),
+		'f' => token.text.parse().map(OscFloat).map_err(|_| invalid(&token)),
+		'b' => parse_blob(&token.text).map(OscBlob).ok_or_else(|| invalid(&token)),
+		's' => Err(invalid(&token)),
+		_ => Err(parse_err(format!("Invalid type tag {}", type_tag)))
+	}
+}
+
+fn parse_blob(s: &str) -> Option<Vec<u8>> {
+	if !s.starts_with("0x") || !s.len().is_multiple_of(2) || !s.is_ascii() {
+		return None;
+	}
+	let hex = &s[2..];
+	(0..hex.len()).step_by(2)
+		.map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+		.collect()
+}
+
+#[test]
+fn test_display_message() {
+	let mess = OscMessage{
+		addr: "/mixer/1/fader".to_string(),
+		args: vec!(OscFloat(0.5), OscInt(3), OscStr("hello".to_string()), OscBlob(vec!(0u8, 10u8, 255u8)))
+	};
+	assert_eq!(mess.to_string(), "/mixer/1/fader ,fisb 0.5 3 \"hello\" 0x000aff");
+
+	let empty = OscMessage{addr: "/ping".to_string(), args: vec!()};
+	assert_eq!(empty.to_string(), "/ping ,");
+}
+
+#[test]
+fn test_display_bundle() {
+	let packet = OscBundle{
+		time_tag: (123,456),
+		conts: vec!(
+			OscMessage{addr: "/a".to_string(), args: vec!(OscInt(1))},
+			OscBundle{time_tag: (0,1), conts: vec!()}
+		)
+	};
+	assert_eq!(packet.to_string(), "#bundle 123:456 [ /a ,i 1 #bundle 0:1 [ ] ]");
+}
+
+#[test]
+fn test_parse_packet() {
+	let parsed = parse_packet("/mixer/1/fader ,fis 0.5 3 \"hello\"").unwrap();
+	assert_eq!(parsed, OscMessage{
+		addr: "/mixer/1/fader".to_string(),
+		args: vec!(OscFloat(0.5), OscInt(3), OscStr("hello".to_string()))
+	});
+
+	// ints are accepted where floats are expected
+	assert_eq!(parse_packet("/f ,f 1").unwrap(), OscMessage{addr: "/f".to_string(), args: vec!(OscFloat(1.0))});
+
+	// the type tag string may be left off a message without arguments
+	assert_eq!(parse_packet("  /ping  ").unwrap(), OscMessage{addr: "/ping".to_string(), args: vec!()});
+
+	// address patterns may contain brackets
+	assert_eq!(parse_packet("/ch/[1-4]/mute ,i 1").unwrap(),
+		OscMessage{addr: "/ch/[1-4]/mute".to_string(), args: vec!(OscInt(1))});
+}
+
+#[test]
+fn test_parse_errors() {
+	assert!(parse_packet("").is_err());
+	assert!(parse_packet("/a ,i").is_err());
+	assert!(parse_packet("/a ,i 1.5").is_err());
+	assert!(parse_packet("/a ,s hello").is_err());
+	assert!(parse_packet("/a ,s \"unterminated").is_err());
+	assert!(parse_packet("/a ,b 0x123").is_err());
+	assert!(parse_packet("/a ,x 1").is_err());
+	assert!(parse_packet("/a ,i 1 2").is_err());
+	assert!(parse_packet("#bundle 1 [ ]").is_err());
+	assert!(parse_packet("#bundle 1.2 [ ]").is_err());
+	assert!(parse_packet("#bundle 1:2 [ /a").is_err());
+	assert!(parse_packet("]").is_err());
+}
+
+#[test]
+fn test_text_round_trip() {
+	let packet = OscBundle{
+		time_tag: (4294967295,0),
+		conts: vec!(
+			OscMessage{
+				addr: "/t".to_string(),
+				args: vec!(
+					OscInt(-2147483648),
+					OscFloat(0.1),
+					OscFloat(-1.0e-30),
+					OscFloat(f32::INFINITY),
+					OscStr("quote \" slash \\ tab \t newline \n bell \u{7} unicode \u{e9}".to_string()),
+					OscStr("".to_string()),
+					OscBlob(vec!()),
+					OscBlob(vec!(1u8, 2u8, 254u8)))
+			},
+			OscMessage{addr: "".to_string(), args: vec!()},
+			OscMessage{addr: "has space".to_string(), args: vec!(OscInt(0))},
+			OscMessage{addr: "#bundle".to_string(), args: vec!()},
+			OscBundle{
+				time_tag: (123,456),
+				conts: vec!(OscMessage{addr: "hello/test/address".to_string(), args: vec!(OscStr("[".to_string()))})
+			}
+		)
+	};
+
+	let text = packet.to_string();
+	assert_eq!(text.parse::<OscPacket>().unwrap(), packet);
+}
@@ -0,0 +1,424 @@
+//! Module for the datagram transports OSC is sent and received over.
+//!
+//! OscSender, OscReceiver and the types built on them are generic over a
+//! Transport, which is a UDP socket unless another is chosen.  A
+//! LoopbackNetwork connects any number of LoopbackSockets in memory, so code
+//! built on OscSender and OscReceiver can be tested without binding real
+//! ports, and under loss, duplication, reordering and delay set with
+//! LinkConditions.
+//!
+//! The network draws from a seeded generator, so the same sequence of sends
+//! sees the same losses, duplicates and reorderings on every run.  Delivery
+//! order doesn't depend on timing: a datagram is only received once every
+//! datagram queued before it has been.
+//!
+//! ```
+//! use osc::transport::{LoopbackNetwork, LinkConditions};
+//! use osc::receiver::OscReceiver;
+//! use osc::sender::OscSender;
+//! use osc::data::OscPacket::OscMessage;
+//!
+//! let network = LoopbackNetwork::new();
+//! network.set_conditions(LinkConditions{loss: 0.1, ..LinkConditions::default()});
+//!
+//! let receiver = OscReceiver::from_socket(network.bind("127.0.0.1:0").unwrap());
+//! let dest = receiver.local_addr().unwrap();
+//! let sender = OscSender::from_socket(network.bind("127.0.0.1:0").unwrap(), dest);
+//! sender.send(OscMessage{addr: "/go".to_string(), args: vec!()}).unwrap();
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::net::{UdpSocket, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::io::{Error, Result};
+use std::io::ErrorKind::{AddrInUse, AddrNotAvailable, InvalidInput, TimedOut, WouldBlock};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// A datagram socket OSC can be sent and received over, with the same
+/// meaning as the UdpSocket methods of the same names.
+pub trait Transport {
+	fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> Result<usize>;
+	fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)>;
+	fn local_addr(&self) -> Result<SocketAddr>;
+	fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()>;
+	fn set_nonblocking(&self, nonblocking: bool) -> Result<()>;
+}
+
+impl Transport for UdpSocket {
+	fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> Result<usize> {
+		UdpSocket::send_to(self, buf, addr)
+	}
+
+	fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+		UdpSocket::recv_from(self, buf)
+	}
+
+	fn local_addr(&self) -> Result<SocketAddr> {
+		UdpSocket::local_addr(self)
+	}
+
+	fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+		UdpSocket::set_read_timeout(self, timeout)
+	}
+
+	fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+		UdpSocket::set_nonblocking(self, nonblocking)
+	}
+}
+
+/// How a LoopbackNetwork treats each datagram.  Probabilities are between 0
+/// and 1; the default is a perfect link.
+#[derive(Debug,Clone,Copy,PartialEq,Default)]
+pub struct LinkConditions {
+	/// Chance a datagram is dropped.
+	pub loss: f64,
+	/// Chance a datagram is delivered twice.
+	pub duplicate: f64,
+	/// Chance a datagram overtakes the one queued before it.
+	pub reorder: f64,
+	/// How long after sending a datagram can be received.
+	pub delay: Duration
+}
+
+// ports handed out when binding port 0, as in the IANA dynamic range
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+const DEFAULT_SEED: u64 = 0x2545_f491_4f6c_dd1d;
+
+struct Datagram {
+	data: Vec<u8>,
+	source: SocketAddr,
+	deliver_at: Instant
+}
+
+#[derive(Default)]
+struct Inbox {
+	queue: Mutex<VecDeque<Datagram>>,
+	arrived: Condvar
+}
+
+struct Network {
+	conditions: LinkConditions,
+	rng: u64,
+	next_port: u16,
+	sockets: HashMap<SocketAddr, Arc<Inbox>>
+}
+
+impl Network {
+	// xorshift64*, a uniform float in [0, 1)
+	fn random(&mut self) -> f64 {
+		self.rng ^= self.rng >> 12;
+		self.rng ^= self.rng << 25;
+		self.rng ^= self.rng >> 27;
+		(self.rng.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+	}
+
+	fn chance(&mut self, p: f64) -> bool {
+		p > 0.0 && self.random() < p
+	}
+
+	// whether binding an address would clash with a socket already bound,
+	// which a socket on the unspecified address does on every address
+	fn is_bound(&self, addr: SocketAddr) -> bool {
+		if addr.ip().is_unspecified() {
+			self.sockets.keys().any(|b| b.port() == addr.port() && b.is_ipv4() == addr.is_ipv4())
+		} else {
+			self.sockets.contains_key(&addr) || self.sockets.contains_key(&unspecified(addr))
+		}
+	}
+
+	// the inbox of the socket a datagram to an address is delivered to
+	fn inbox(&self, addr: SocketAddr) -> Option<Arc<Inbox>> {
+		self.sockets.get(&addr).or_else(|| self.sockets.get(&unspecified(addr))).cloned()
+	}
+}
+
+/// An in-memory network of LoopbackSockets.  Clones share the same network.
+#[derive(Clone)]
+pub struct LoopbackNetwork {
+	network: Arc<Mutex<Network>>
+}
+
+impl LoopbackNetwork {
+
+	/// Constructs an empty network with a perfect link and a fixed seed.
+	pub fn new() -> LoopbackNetwork {
+		LoopbackNetwork::with_seed(DEFAULT_SEED)
+	}
+
+	/// Constructs an empty network whose losses, duplicates and reorderings
+	/// are drawn from the given seed.
+	pub fn with_seed(seed: u64) -> LoopbackNetwork {
+		LoopbackNetwork{network: Arc::new(Mutex::new(Network{
+			conditions: LinkConditions::default(),
+			// xorshift gets stuck at zero
+			rng: seed.max(1),
+			next_port: FIRST_EPHEMERAL_PORT,
+			sockets: HashMap::new()
+		}))}
+	}
+
+	/// Change how datagrams sent from now on are treated.
+	pub fn set_conditions(&self, conditions: LinkConditions) {
+		self.network.lock().unwrap().conditions = conditions;
+	}
+
+	/// The conditions datagrams are currently sent under.
+	pub fn conditions(&self) -> LinkConditions {
+		self.network.lock().unwrap().conditions
+	}
+
+	/// Bind a socket to an address on the network.  Port 0 picks a free
+	/// port.  A socket bound to the unspecified address, such as 0.0.0.0,
+	/// receives datagrams sent to any address with its port.  Returns an
+	/// AddrInUse error if the address is already bound.
+	pub fn bind<A: ToSocketAddrs>(&self, addr: A) -> Result<LoopbackSocket> {
+		let mut addr = resolve(addr)?;
+		let mut network = self.network.lock().unwrap();
+		if addr.port() == 0 {
+			let ip = addr.ip();
+			let port = (network.next_port..=u16::MAX).chain(FIRST_EPHEMERAL_PORT..network.next_port)
+				.find(|&p| !network.is_bound(SocketAddr::new(ip, p)))
+				.ok_or_else(|| Error::new(AddrNotAvailable, "No free ports."))?;
+			network.next_port = port.checked_add(1).unwrap_or(FIRST_EPHEMERAL_PORT);
+			addr.set_port(port);
+		}
+		if network.is_bound(addr) {
+			return Err(Error::new(AddrInUse, format!("{} is already bound.", addr)));
+		}
+		let inbox = Arc::new(Inbox::default());
+		network.sockets.insert(addr, inbox.clone());
+		Ok(LoopbackSocket{
+			network: self.network.clone(),
+			addr,
+			inbox,
+			timeout: Mutex::new(None),
+			nonblocking: Mutex::new(false)
+		})
+	}
+}
+
+impl Default for LoopbackNetwork {
+	fn default() -> Self {
+		LoopbackNetwork::new()
+	}
+}
+
+/// A socket bound to an address on a LoopbackNetwork.  Datagrams sent to an
+/// address nobody is bound to are dropped, as with UDP.  Dropping the socket
+/// frees its address.
+pub struct LoopbackSocket {
+	network: Arc<Mutex<Network>>,
+	addr: SocketAddr,
+	inbox: Arc<Inbox>,
+	timeout: Mutex<Option<Duration>>,
+	nonblocking: Mutex<bool>
+}
+
+impl Transport for LoopbackSocket {
+	fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> Result<usize> {
+		let dest = resolve(addr)?;
+		let mut network = self.network.lock().unwrap();
+		let conditions = network.conditions;
+		if network.chance(conditions.loss) {
+			return Ok(buf.len());
+		}
+		let copies = if network.chance(conditions.duplicate) { 2 } else { 1 };
+		let reorder = network.chance(conditions.reorder);
+		let inbox = match network.inbox(dest) {
+			Some(inbox) => inbox,
+			None => return Ok(buf.len())
+		};
+		drop(network);
+
+		// a socket bound to the unspecified address sends from the address
+		// the datagram is routed to, as loopback UDP does
+		let mut source = self.addr;
+		if source.ip().is_unspecified() {
+			source.set_ip(dest.ip());
+		}
+
+		let mut queue = inbox.queue.lock().unwrap();
+		let deliver_at = Instant::now() + conditions.delay;
+		for _ in 0..copies {
+			queue.push_back(Datagram{data: buf.to_vec(), source, deliver_at});
+		}
+		// overtake whatever was waiting before the new copies
+		let len = queue.len();
+		if reorder && len > copies {
+			let overtaken = queue.remove(len - copies - 1).unwrap();
+			queue.push_back(overtaken);
+		}
+		inbox.arrived.notify_all();
+		Ok(buf.len())
+	}
+
+	fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+		let nonblocking = *self.nonblocking.lock().unwrap();
+		let deadline = self.timeout.lock().unwrap().map(|t| Instant::now() + t);
+		let mut queue = self.inbox.queue.lock().unwrap();
+		loop {
+			let now = Instant::now();
+			let ready_at = queue.front().map(|d| d.deliver_at);
+			if ready_at.is_some_and(|t| t <= now) {
+				let datagram = queue.pop_front().unwrap();
+				// like UDP, the rest of a datagram too big for the buffer is lost
+				let n = datagram.data.len().min(buf.len());
+				buf[..n].copy_from_slice(&datagram.data[..n]);
+				return Ok((n, datagram.source));
+			}
+			if nonblocking {
+				return Err(Error::new(WouldBlock, "No datagram waiting."));
+			}
+			if deadline.is_some_and(|d| d <= now) {
+				return Err(Error::new(TimedOut, "Timed out waiting for a datagram."));
+			}
+			let wake_at = match (ready_at, deadline) {
+				(Some(r), Some(d)) => Some(r.min(d)),
+				(r, d) => r.or(d)
+			};
+			queue = match wake_at {
+				Some(t) => self.inbox.arrived.wait_timeout(queue, t - now).unwrap().0,
+				None => self.inbox.arrived.wait(queue).unwrap()
+			};
+		}
+	}
+
+	fn local_addr(&self) -> Result<SocketAddr> {
+		Ok(self.addr)
+	}
+
+	fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+		if timeout == Some(Duration::from_secs(0)) {
+			return Err(Error::new(InvalidInput, "Cannot set a 0 duration timeout."));
+		}
+		*self.timeout.lock().unwrap() = timeout;
+		Ok(())
+	}
+
+	fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+		*self.nonblocking.lock().unwrap() = nonblocking;
+		Ok(())
+	}
+}
+
+impl Drop for LoopbackSocket {
+	fn drop(&mut self) {
+		if let Ok(mut network) = self.network.lock() {
+			network.sockets.remove(&self.addr);
+		}
+	}
+}
+
+fn unspecified(addr: SocketAddr) -> SocketAddr {
+	let ip = if addr.is_ipv4() { IpAddr::V4(Ipv4Addr::UNSPECIFIED) } else { IpAddr::V6(Ipv6Addr::UNSPECIFIED) };
+	SocketAddr::new(ip, addr.port())
+}
+
+fn resolve<A: ToSocketAddrs>(addr: A) -> Result<SocketAddr> {
+	addr.to_socket_addrs()?.next().ok_or_else(|| Error::new(InvalidInput, "No address to bind to."))
+}
+
+#[cfg(test)]
+fn cue(i: i32) -> ::data::OscPacket {
+	::data::OscPacket::OscMessage{addr: "/cue".to_string(), args: vec!(::data::OscArg::OscInt(i))}
+}
+
+#[cfg(test)]
+fn send_and_collect(network: &LoopbackNetwork, count: i32) -> Vec<::data::OscPacket> {
+	use receiver::OscReceiver;
+	use sender::OscSender;
+
+	let receiver = OscReceiver::from_socket(network.bind("127.0.0.1:0").unwrap());
+	let sender = OscSender::from_socket(network.bind("127.0.0.1:0").unwrap(), receiver.local_addr().unwrap());
+	for i in 0..count {
+		sender.send(cue(i)).unwrap();
+	}
+	receiver.drain().unwrap()
+}
+
+#[test]
+fn test_loopback_bind() {
+	let network = LoopbackNetwork::new();
+	let a = network.bind("127.0.0.1:0").unwrap();
+	let b = network.bind("127.0.0.1:0").unwrap();
+	assert_ne!(a.local_addr().unwrap(), b.local_addr().unwrap());
+
+	let fixed = network.bind("127.0.0.1:7009").unwrap();
+	assert_eq!(network.bind("127.0.0.1:7009").err().unwrap().kind(), AddrInUse);
+	drop(fixed);
+	assert!(network.bind("127.0.0.1:7009").is_ok());
+
+	// separate networks don't collide
+	assert!(LoopbackNetwork::new().bind("127.0.0.1:7009").is_ok());
+
+	// the unspecified address clashes with every address on its port
+	let any = network.bind("0.0.0.0:7010").unwrap();
+	assert_eq!(network.bind("127.0.0.1:7010").err().unwrap().kind(), AddrInUse);
+	let a_port = a.local_addr().unwrap().port();
+	assert_eq!(network.bind(("0.0.0.0", a_port)).err().unwrap().kind(), AddrInUse);
+
+	// and receives datagrams sent to any of them
+	let mut buf = [0; 4];
+	a.send_to(&[1], "127.0.0.1:7010").unwrap();
+	any.send_to(&[2], a.local_addr().unwrap()).unwrap();
+	assert_eq!(any.recv_from(&mut buf).unwrap(), (1, a.local_addr().unwrap()));
+	assert_eq!(a.recv_from(&mut buf).unwrap(), (1, "127.0.0.1:7010".parse().unwrap()));
+}
+
+#[test]
+fn test_loopback_perfect_link() {
+	use receiver::OscReceiver;
+
+	let network = LoopbackNetwork::new();
+	let received = send_and_collect(&network, 10);
+	assert_eq!(received, (0..10).map(cue).collect::<Vec<_>>());
+
+	let receiver = OscReceiver::from_socket(network.bind("127.0.0.1:0").unwrap());
+	let err = receiver.recv(Some(Duration::from_millis(10))).unwrap_err();
+	assert_eq!(err.kind(), TimedOut);
+	assert!(receiver.try_recv().unwrap().is_none());
+}
+
+#[test]
+fn test_loopback_conditions() {
+	let lossy = LoopbackNetwork::new();
+	lossy.set_conditions(LinkConditions{loss: 0.5, ..LinkConditions::default()});
+	let lost = send_and_collect(&lossy, 200);
+	assert!(lost.len() > 50 && lost.len() < 150);
+	assert!(lost.windows(2).all(|w| w[0] < w[1]));
+
+	let duplicating = LoopbackNetwork::new();
+	duplicating.set_conditions(LinkConditions{duplicate: 0.5, ..LinkConditions::default()});
+	let received = send_and_collect(&duplicating, 200);
+	assert!(received.len() > 250 && received.len() < 350);
+
+	let reordering = LoopbackNetwork::new();
+	reordering.set_conditions(LinkConditions{reorder: 0.5, ..LinkConditions::default()});
+	let mut received = send_and_collect(&reordering, 200);
+	assert_eq!(received.len(), 200);
+	assert!(received.windows(2).any(|w| w[0] > w[1]));
+	received.sort_by(|a, b| a.partial_cmp(b).unwrap());
+	assert_eq!(received, (0..200).map(cue).collect::<Vec<_>>());
+
+	// the same seed gives the same run
+	let again = LoopbackNetwork::new();
+	again.set_conditions(LinkConditions{loss: 0.5, ..LinkConditions::default()});
+	assert_eq!(send_and_collect(&again, 200), lost);
+}
+
+#[test]
+fn test_loopback_delay() {
+	use receiver::OscReceiver;
+	use sender::OscSender;
+
+	let network = LoopbackNetwork::new();
+	network.set_conditions(LinkConditions{delay: Duration::from_millis(50), ..LinkConditions::default()});
+	let receiver = OscReceiver::from_socket(network.bind("127.0.0.1:0").unwrap());
+	let sender = OscSender::from_socket(network.bind("127.0.0.1:0").unwrap(), receiver.local_addr().unwrap());
+
+	let sent_at = Instant::now();
+	sender.send(cue(1)).unwrap();
+	assert!(receiver.try_recv().unwrap().is_none());
+	assert_eq!(receiver.recv(Some(Duration::from_secs(5))).unwrap(), cue(1));
+	assert!(sent_at.elapsed() >= Duration::from_millis(50));
+}
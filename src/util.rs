@@ -15,4 +15,36 @@ macro_rules! pad_with_null {
 			$operator.$operation(&[0u8]).unwrap();
 		}
 	)
-}
\ No newline at end of file
+}
+
+/// When each of a set of keys was last seen, by a counter that goes up with
+/// every sighting, so the least recently seen key can be found without a scan.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Recency<K: Ord + Clone> {
+	seen: std::collections::BTreeMap<K, u64>,
+	// the reverse of seen; counters are never reused, so each is one key
+	order: std::collections::BTreeMap<u64, K>
+}
+
+impl<K: Ord + Clone> Default for Recency<K> {
+	fn default() -> Self {
+		Recency{seen: Default::default(), order: Default::default()}
+	}
+}
+
+impl<K: Ord + Clone> Recency<K> {
+	/// Record that a key was seen at a counter value higher than any before.
+	pub fn touch(&mut self, key: &K, now: u64) {
+		if let Some(old) = self.seen.insert(key.clone(), now) {
+			self.order.remove(&old);
+		}
+		self.order.insert(now, key.clone());
+	}
+
+	/// Forget the least recently seen key and return it.
+	pub fn pop_oldest(&mut self) -> Option<K> {
+		let (_, key) = self.order.pop_first()?;
+		self.seen.remove(&key);
+		Some(key)
+	}
+}